@@ -0,0 +1,128 @@
+use crate::db::DB;
+use crate::errors::{Error, Result};
+
+#[derive(Debug, Clone)]
+enum Op {
+    Put { bucket: Vec<u8>, key: Vec<u8>, value: Vec<u8> },
+    Delete { bucket: Vec<u8>, key: Vec<u8> },
+}
+
+/// Puts and deletes gathered up to be applied together by [`DB::apply_batch`].
+///
+/// The operations are applied in the order they were added, in one transaction
+/// with one commit, which is much cheaper than a transaction per operation.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<Op>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds a put of `value` under `key` in the top level bucket named `bucket`.
+    pub fn put<B: AsRef<[u8]>, K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, bucket: B, key: K, value: V) -> &mut Self {
+        self.ops.push(Op::Put {
+            bucket: bucket.as_ref().to_vec(),
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+        });
+        self
+    }
+
+    /// Adds a delete of `key` from the top level bucket named `bucket`.
+    ///
+    /// Deleting a key that doesn't exist when the batch is applied does nothing.
+    pub fn delete<B: AsRef<[u8]>, K: AsRef<[u8]>>(&mut self, bucket: B, key: K) -> &mut Self {
+        self.ops.push(Op::Delete {
+            bucket: bucket.as_ref().to_vec(),
+            key: key.as_ref().to_vec(),
+        });
+        self
+    }
+
+    /// Returns the number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl DB {
+    /// Applies every operation in `batch`, in order, in a single writable
+    /// transaction that is committed once.
+    ///
+    /// If any operation fails, for instance because its bucket doesn't exist,
+    /// none of them are applied and the error is returned.
+    pub fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let tx = self.tx(true)?;
+        for op in batch.ops {
+            match op {
+                Op::Put { bucket, key, value } => tx.bucket(&bucket)?.put(key, value)?,
+                Op::Delete { bucket, key } => match tx.bucket(&bucket)?.delete(&key) {
+                    Err(Error::KeyValueMissing) => {}
+                    result => result?,
+                },
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+
+    use super::*;
+
+    #[test]
+    fn test_apply_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap();
+        tx.commit().unwrap();
+        let before = db.stats().unwrap().tx_id;
+
+        let mut batch = WriteBatch::new();
+        for i in 0..6250 {
+            batch.put("data", format!("key{:04}", i), format!("value{}", i));
+        }
+        // a delete after a put of the same key wins, and a put after a delete
+        for i in (0..6250).filter(|i| i % 2 == 0) {
+            batch.delete("data", format!("key{:04}", i));
+        }
+        for i in (0..6250).filter(|i| i % 10 == 0) {
+            batch.put("data", format!("key{:04}", i), "again");
+        }
+        batch.delete("data", "missing");
+        assert_eq!(batch.len(), 10001);
+        db.apply_batch(batch).unwrap();
+        assert_eq!(db.stats().unwrap().tx_id, before + 1);
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        for i in 0..6250 {
+            let value = bucket.get(format!("key{:04}", i).as_bytes()).unwrap();
+            match (i % 10, i % 2) {
+                (0, _) => assert_eq!(value.unwrap().as_slice(), b"again"),
+                (_, 0) => assert!(value.is_none()),
+                _ => assert_eq!(value.unwrap().as_slice(), format!("value{}", i).as_bytes()),
+            }
+        }
+        drop(tx);
+
+        // nothing is applied when an operation fails
+        let mut batch = WriteBatch::new();
+        batch.put("data", "key0001", "changed").put("nope", "key", "value");
+        assert_eq!(db.apply_batch(batch), Err(Error::BucketMissing));
+        let tx = db.tx(false).unwrap();
+        let value = tx.bucket(b"data").unwrap().get(b"key0001").unwrap().unwrap();
+        assert_eq!(value.as_slice(), b"value1");
+    }
+}
@@ -1,26 +1,1122 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::{Bound, RangeBounds};
 use std::rc::Rc;
+use std::slice::from_raw_parts;
+use std::sync::Arc;
+
 use crate::bytes::Bytes;
-use crate::page::{PageID, Pages};
+use crate::config::Mode;
+use crate::cursor::{Cursor, KeyIter, PrefixIter, RangeIter};
+use crate::errors::{Error, Result};
+use crate::freelist::TxFreelist;
+use crate::node::{branch_index, Branch, Comparator, Leaf, Node, NodeData, NodeID, PageNodeID};
+use crate::page::{Page, PageID, Pages};
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub(crate) struct BucketMeta {
     pub(crate) root_page: PageID,
     pub(crate) next_int: u64,
+    // Key / value pairs in the bucket and all of its nested buckets
+    pub(crate) num_keys: u64,
+}
+
+impl BucketMeta {
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        let ptr = self as *const BucketMeta as *const u8;
+        unsafe { from_raw_parts(ptr, size_of::<BucketMeta>()) }
+    }
 }
 
 pub(crate) struct InnerBucket<'b> {
     pub(crate) meta: BucketMeta,
-    // root: PageNodeID,
+    root: PageNodeID,
     pub(crate) deleted: bool,
     dirty: bool,
     buckets: HashMap<Bytes<'b>, Rc<RefCell<InnerBucket<'b>>>>,
-    // pub(crate) nodes: Vec<Rc<RefCell<Node<'b>>>>,
+    pub(crate) nodes: Vec<Rc<RefCell<Node<'b>>>>,
     // Maps a PageID to it's NodeID, so we don't create multiple nodes for a single page
-    // page_node_ids: HashMap<PageID, NodeID>,
+    page_node_ids: HashMap<PageID, NodeID>,
     // Maps PageIDs to their parent's PageID
     page_parents: HashMap<PageID, PageID>,
-    pages: Pages,
-}
\ No newline at end of file
+    pub(crate) pages: Pages,
+    freelist: Rc<RefCell<TxFreelist>>,
+    pub(crate) cmp: Comparator,
+    // Comparators for nested buckets by name, only set on a transaction's root bucket
+    comparators: Option<Arc<HashMap<Vec<u8>, Comparator>>>,
+}
+
+impl<'b> InnerBucket<'b> {
+    pub(crate) fn from_meta(
+        meta: BucketMeta,
+        pages: Pages,
+        freelist: Rc<RefCell<TxFreelist>>,
+    ) -> InnerBucket<'b> {
+        InnerBucket {
+            meta,
+            root: PageNodeID::Page(meta.root_page),
+            deleted: false,
+            dirty: false,
+            buckets: HashMap::new(),
+            nodes: Vec::new(),
+            page_node_ids: HashMap::new(),
+            page_parents: HashMap::new(),
+            pages,
+            freelist,
+            cmp: Comparator::default(),
+            comparators: None,
+        }
+    }
+
+    /// Gives the top level buckets read or created through this root bucket
+    /// the comparators configured for their names.
+    pub(crate) fn with_comparators(mut self, comparators: Arc<HashMap<Vec<u8>, Comparator>>) -> InnerBucket<'b> {
+        self.comparators = Some(comparators);
+        self
+    }
+
+    // Nested buckets share their parent's key order, apart from top level ones
+    fn child_comparator(&self, name: &[u8]) -> Comparator {
+        match &self.comparators {
+            Some(comparators) => comparators.get(name).cloned().unwrap_or_default(),
+            None => self.cmp.clone(),
+        }
+    }
+
+    /// Creates an empty bucket that has never been written to a page.
+    fn new_bucket(pages: Pages, freelist: Rc<RefCell<TxFreelist>>) -> InnerBucket<'b> {
+        let mut bucket = InnerBucket::from_meta(BucketMeta::default(), pages, freelist);
+        bucket.nodes.push(Rc::new(RefCell::new(Node::new(NodeData::Leaves(Vec::new())))));
+        bucket.root = PageNodeID::Node(0);
+        bucket.dirty = true;
+        bucket
+    }
+
+    /// Returns the leaf stored under `key`, reading through any nodes
+    /// this transaction has already modified.
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Leaf<'b>>> {
+        let mut id = self.root;
+        loop {
+            let node = self.read_node(id)?;
+            let node = node.borrow();
+            match &node.data {
+                NodeData::Branches(branches) => {
+                    id = PageNodeID::Page(branches[branch_index(branches, key, &self.cmp)].page);
+                }
+                NodeData::Leaves(leaves) => {
+                    return Ok(leaves
+                        .binary_search_by(|l| self.cmp.compare(l.key(), key))
+                        .ok()
+                        .map(|index| leaves[index].clone()));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn root(&self) -> PageNodeID {
+        self.root
+    }
+
+    /// Returns the node for `id` without keeping it: the node this transaction
+    /// has modified if there is one, otherwise one read from the page.
+    pub(crate) fn read_node(&self, id: PageNodeID) -> Result<Rc<RefCell<Node<'b>>>> {
+        match id {
+            PageNodeID::Node(id) => Ok(self.nodes[id as usize].clone()),
+            PageNodeID::Page(page_id) => match self.page_node_ids.get(&page_id) {
+                Some(id) => Ok(self.nodes[*id as usize].clone()),
+                None => Ok(Rc::new(RefCell::new(self.read_page(page_id)?))),
+            },
+        }
+    }
+
+    // Reads the node on `page_id`, checking in strict mode that a custom
+    // comparator agrees with the order its keys were written in
+    fn read_page(&self, page_id: PageID) -> Result<Node<'b>> {
+        let node = Node::read(&self.pages, page_id)?;
+        if self.cmp.is_custom() && self.pages.is_strict() {
+            let len = node.data.len();
+            let sorted = (1..len).all(|i| self.cmp.compare(&node.data.key(i - 1), &node.data.key(i)).is_lt());
+            if !sorted {
+                return Err(Error::InvalidDB(format!(
+                    "the keys on page {} aren't in the order of the bucket's comparator",
+                    page_id
+                )));
+            }
+        }
+        Ok(node)
+    }
+
+    /// Stores `value` under `key`, replacing any existing value.
+    pub(crate) fn put(&mut self, key: Bytes<'b>, value: Bytes<'b>) -> Result<()> {
+        let (max_value_size, dirty_bytes, max_dirty_bytes) = {
+            let freelist = self.freelist.borrow();
+            (freelist.max_value_size, freelist.dirty_bytes, freelist.max_dirty_bytes)
+        };
+        if value.as_ref().len() > max_value_size {
+            return Err(Error::ValueTooLarge(value.as_ref().len(), max_value_size));
+        }
+        let value = self.pages.encode(value);
+        // Replaced values stay counted, so this errs on the side of using less memory
+        let dirty_bytes = dirty_bytes.saturating_add(key.as_ref().len() + value.as_ref().len());
+        if dirty_bytes > max_dirty_bytes {
+            return Err(Error::TxTooLarge(dirty_bytes, max_dirty_bytes));
+        }
+        match self.get(key.as_ref())? {
+            Some(Leaf::Bucket(_, _)) => return Err(Error::IncompatibleValue),
+            Some(Leaf::Kv(_, _)) => {}
+            None => self.meta.num_keys += 1,
+        }
+        let node = self.writable_leaf(key.as_ref())?;
+        self.dirty = true;
+        let mut freelist = self.freelist.borrow_mut();
+        freelist.stats.bytes_written += value.as_ref().len() as u64;
+        freelist.dirty_bytes = dirty_bytes;
+        drop(freelist);
+        node.borrow_mut().insert(Leaf::Kv(key, value), &self.cmp);
+        Ok(())
+    }
+
+    /// Increments the bucket's sequence, returning the new value.
+    pub(crate) fn next_sequence(&mut self) -> u64 {
+        self.meta.next_int += 1;
+        self.dirty = true;
+        self.meta.next_int
+    }
+
+    /// Removes the key / value pair stored under `key`.
+    pub(crate) fn delete(&mut self, key: &[u8]) -> Result<Leaf<'b>> {
+        match self.get(key)? {
+            None => return Err(Error::KeyValueMissing),
+            Some(Leaf::Bucket(_, _)) => return Err(Error::IncompatibleValue),
+            Some(Leaf::Kv(_, _)) => (),
+        }
+        let node = self.writable_leaf(key)?;
+        self.dirty = true;
+        self.meta.num_keys -= 1;
+        let leaf = node.borrow_mut().remove(key, &self.cmp);
+        Ok(leaf.unwrap())
+    }
+
+    /// Returns the nested bucket stored under `name`, reading it the first time.
+    pub(crate) fn get_bucket(&mut self, name: &[u8]) -> Result<Rc<RefCell<InnerBucket<'b>>>> {
+        let (name, meta) = match self.get(name)? {
+            Some(Leaf::Bucket(name, meta)) => (name, meta),
+            Some(Leaf::Kv(_, _)) => return Err(Error::IncompatibleValue),
+            None => return Err(Error::BucketMissing),
+        };
+        let pages = self.pages.clone();
+        let freelist = self.freelist.clone();
+        let cmp = self.child_comparator(name.as_ref());
+        let bucket = self.buckets.entry(name).or_insert_with(|| {
+            let mut bucket = InnerBucket::from_meta(meta, pages, freelist);
+            bucket.cmp = cmp;
+            Rc::new(RefCell::new(bucket))
+        });
+        Ok(bucket.clone())
+    }
+
+    /// Creates a nested bucket named `name`.
+    pub(crate) fn create_bucket(&mut self, name: Bytes<'b>) -> Result<Rc<RefCell<InnerBucket<'b>>>> {
+        if self.get(name.as_ref())?.is_some() {
+            return Err(Error::BucketExists);
+        }
+        let node = self.writable_leaf(name.as_ref())?;
+        self.dirty = true;
+        node.borrow_mut().insert(Leaf::Bucket(name.clone(), BucketMeta::default()), &self.cmp);
+
+        let mut bucket = InnerBucket::new_bucket(self.pages.clone(), self.freelist.clone());
+        bucket.cmp = self.child_comparator(name.as_ref());
+        let bucket = Rc::new(RefCell::new(bucket));
+        self.buckets.insert(name, bucket.clone());
+        Ok(bucket)
+    }
+
+    /// Deletes the nested bucket named `name`, freeing its pages
+    /// and those of every bucket nested in it.
+    pub(crate) fn delete_bucket(&mut self, name: &[u8]) -> Result<()> {
+        let meta = match self.get(name)? {
+            Some(Leaf::Bucket(_, meta)) => meta,
+            Some(Leaf::Kv(_, _)) => return Err(Error::IncompatibleValue),
+            None => return Err(Error::BucketMissing),
+        };
+        let node = self.writable_leaf(name)?;
+        self.dirty = true;
+        // Changes to the bucket in this transaction were never counted here
+        self.meta.num_keys -= meta.num_keys;
+        node.borrow_mut().remove(name, &self.cmp);
+        if let Some(bucket) = self.buckets.remove(name) {
+            bucket.borrow_mut().deleted = true;
+        }
+        // A bucket created in this transaction hasn't been written to any pages yet
+        if meta.root_page != 0 {
+            self.free_tree(meta.root_page)?;
+        }
+        Ok(())
+    }
+
+    fn free_tree(&self, page_id: PageID) -> Result<()> {
+        let (data, num_pages) = self.pages.node_data(page_id)?;
+        match data {
+            NodeData::Branches(branches) => {
+                for branch in branches {
+                    self.free_tree(branch.page)?;
+                }
+            }
+            NodeData::Leaves(leaves) => {
+                for leaf in leaves {
+                    if let Leaf::Bucket(_, meta) = leaf {
+                        if meta.root_page != 0 {
+                            self.free_tree(meta.root_page)?;
+                        }
+                    }
+                }
+            }
+        }
+        self.freelist.borrow_mut().free(page_id, num_pages);
+        Ok(())
+    }
+
+    /// Reads every node on the path to the leaf that `key` belongs in into
+    /// memory so it can be modified, returning that leaf.
+    fn writable_leaf(&mut self, key: &[u8]) -> Result<Rc<RefCell<Node<'b>>>> {
+        let mut id = self.root;
+        loop {
+            let node = self.node(id)?;
+            let child = match &node.borrow().data {
+                NodeData::Branches(branches) => branches[branch_index(branches, key, &self.cmp)].page,
+                NodeData::Leaves(_) => return Ok(node.clone()),
+            };
+            self.page_parents.insert(child, node.borrow().page_id);
+            id = PageNodeID::Page(child);
+        }
+    }
+
+    /// Returns the node for `id`, reading it from its page the first time.
+    fn node(&mut self, id: PageNodeID) -> Result<Rc<RefCell<Node<'b>>>> {
+        let page_id = match id {
+            PageNodeID::Node(id) => return Ok(self.nodes[id as usize].clone()),
+            PageNodeID::Page(page_id) => page_id,
+        };
+        if let Some(id) = self.page_node_ids.get(&page_id) {
+            return Ok(self.nodes[*id as usize].clone());
+        }
+
+        let id = self.nodes.len() as NodeID;
+        let node = Rc::new(RefCell::new(self.read_page(page_id)?));
+        self.nodes.push(node.clone());
+        self.page_node_ids.insert(page_id, id);
+        if let Some(parent) = self.page_parents.get(&page_id) {
+            let parent = self.page_node_ids[parent];
+            self.nodes[parent as usize].borrow_mut().children.push(id);
+        }
+        self.freelist.borrow_mut().stats.pages_dirtied += 1;
+        Ok(node)
+    }
+
+    // Whether this bucket, or any bucket nested in it, has changes to write
+    fn has_changes(&self) -> bool {
+        self.dirty || self.buckets.values().any(|bucket| bucket.borrow().has_changes())
+    }
+
+    /// Writes every modified node to newly allocated pages,
+    /// returning the bucket's meta pointing at its new root page.
+    pub(crate) fn spill(&mut self) -> Result<BucketMeta> {
+        // Nested buckets are written first, so their new metas can be stored in this bucket
+        let buckets: Vec<(Bytes<'b>, Rc<RefCell<InnerBucket<'b>>>)> = self
+            .buckets
+            .iter()
+            .filter(|(_, bucket)| bucket.borrow().has_changes())
+            .map(|(name, bucket)| (name.clone(), bucket.clone()))
+            .collect();
+        for (name, bucket) in buckets {
+            let old_keys = match self.get(name.as_ref())? {
+                Some(Leaf::Bucket(_, meta)) => meta.num_keys,
+                _ => 0,
+            };
+            let meta = bucket.borrow_mut().spill()?;
+            self.meta.num_keys = self.meta.num_keys + meta.num_keys - old_keys;
+            let node = self.writable_leaf(name.as_ref())?;
+            node.borrow_mut().insert(Leaf::Bucket(name, meta), &self.cmp);
+            self.dirty = true;
+        }
+
+        if !self.dirty {
+            return Ok(self.meta);
+        }
+        let root = match self.root {
+            PageNodeID::Node(id) => id,
+            PageNodeID::Page(page_id) => match self.page_node_ids.get(&page_id) {
+                Some(id) => *id,
+                // Only the meta changed, such as the sequence, so the nodes stay where they are
+                None => {
+                    self.dirty = false;
+                    return Ok(self.meta);
+                }
+            },
+        };
+        let mut branches = self.spill_node(root)?;
+        // Keep adding levels until the root fits in a single page
+        while branches.len() > 1 {
+            branches = self.write_data(&NodeData::Branches(branches))?;
+        }
+        let root_page = match branches.pop() {
+            Some(branch) => branch.page,
+            None => {
+                let mut freelist = self.freelist.borrow_mut();
+                let page = freelist.allocate(Page::HEADER_SIZE)?;
+                NodeData::Leaves(Vec::new()).write(0..0, page);
+                page.id
+            }
+        };
+
+        self.meta.root_page = root_page;
+        self.root = PageNodeID::Page(root_page);
+        self.dirty = false;
+        Ok(self.meta)
+    }
+
+    // Spills the node's children before the node itself, so its branches
+    // point at the children's new pages by the time it is written.
+    fn spill_node(&mut self, id: NodeID) -> Result<Vec<Branch<'b>>> {
+        let node = self.nodes[id as usize].clone();
+        if matches!(self.freelist.borrow().mode, Mode::LowSpace) {
+            self.merge_small_children(&node)?;
+        }
+        let children = node.borrow().children.clone();
+        for child in children {
+            let child_page = self.nodes[child as usize].borrow().page_id;
+            let new_branches = self.spill_node(child)?;
+            if let NodeData::Branches(branches) = &mut node.borrow_mut().data {
+                let index = branches.iter().position(|b| b.page == child_page).unwrap();
+                branches.splice(index..=index, new_branches);
+            }
+        }
+        let node = node.borrow();
+        // The node replaces the page it was read from
+        if node.page_id != 0 {
+            self.freelist.borrow_mut().free(node.page_id, node.num_pages);
+        }
+        self.write_data(&node.data)
+    }
+
+    // Merges each modified leaf that fills less than a quarter of a page with
+    // a neighbouring leaf that hasn't been modified, freeing the neighbour's page.
+    fn merge_small_children(&mut self, node: &Rc<RefCell<Node<'b>>>) -> Result<()> {
+        let threshold = self.pages.pagesize / 4;
+        let children = node.borrow().children.clone();
+        for child in children {
+            let child = self.nodes[child as usize].clone();
+            let mut child = child.borrow_mut();
+            let small = match &child.data {
+                NodeData::Leaves(leaves) => child.data.size(0..leaves.len()) < threshold,
+                NodeData::Branches(_) => false,
+            };
+            if !small {
+                continue;
+            }
+
+            let mut parent = node.borrow_mut();
+            let branches = match &mut parent.data {
+                NodeData::Branches(branches) => branches,
+                NodeData::Leaves(_) => continue,
+            };
+            let index = branches.iter().position(|b| b.page == child.page_id).unwrap();
+            let neighbour = [index + 1, index.wrapping_sub(1)]
+                .into_iter()
+                .find(|i| *i < branches.len() && !self.page_node_ids.contains_key(&branches[*i].page));
+            let neighbour = match neighbour {
+                Some(neighbour) => neighbour,
+                None => continue,
+            };
+            let page_id = branches[neighbour].page;
+            let (data, num_pages) = self.pages.node_data(page_id)?;
+            let mut leaves = match data {
+                NodeData::Leaves(leaves) => leaves,
+                NodeData::Branches(_) => continue,
+            };
+
+            if let NodeData::Leaves(child_leaves) = &mut child.data {
+                match neighbour > index {
+                    true => child_leaves.append(&mut leaves),
+                    false => {
+                        leaves.append(child_leaves);
+                        *child_leaves = leaves;
+                    }
+                }
+            }
+            branches.remove(neighbour);
+            self.freelist.borrow_mut().free(page_id, num_pages);
+        }
+        Ok(())
+    }
+
+    fn write_data(&mut self, data: &NodeData<'b>) -> Result<Vec<Branch<'b>>> {
+        let mut branches = Vec::new();
+        let pagesize = self.pages.pagesize;
+        // Only leaves leave room to spare, branches are always packed
+        let limit = match data {
+            NodeData::Leaves(_) => (pagesize as f64 * self.freelist.borrow().fill_percent as f64) as u64,
+            NodeData::Branches(_) => pagesize,
+        };
+        let ranges = data.split(limit);
+        self.freelist.borrow_mut().stats.splits += ranges.len().saturating_sub(1) as u64;
+        for range in ranges {
+            let mut freelist = self.freelist.borrow_mut();
+            let page = freelist.allocate(data.size(range.clone()))?;
+            data.write(range.clone(), page);
+            branches.push(Branch {
+                key: data.key(range.start),
+                page: page.id,
+            });
+        }
+        Ok(branches)
+    }
+}
+
+/// A collection of key / value pairs, and possibly other buckets, in a transaction.
+///
+/// Values read from a bucket borrow from the transaction, so they can't outlive it.
+pub struct Bucket<'b, 'tx: 'b> {
+    inner: Rc<RefCell<InnerBucket<'tx>>>,
+    writable: bool,
+    _phantom: PhantomData<&'b ()>,
+}
+
+impl<'b, 'tx> Bucket<'b, 'tx> {
+    pub(crate) fn new(inner: Rc<RefCell<InnerBucket<'tx>>>, writable: bool) -> Bucket<'b, 'tx> {
+        Bucket {
+            inner,
+            writable,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns the value stored under `key`, or `None` if there is no value
+    /// or `key` is the name of a nested bucket.
+    ///
+    /// A value read from the file is returned as a [`Bytes::Mmap`], which keeps
+    /// the memory map it points into alive without copying the value.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes<'b>>> {
+        let inner = self.inner.borrow();
+        let value = match inner.get(key)? {
+            Some(Leaf::Kv(_, Bytes::Slice(value))) => inner.pages.pin(value),
+            Some(Leaf::Kv(_, value)) => value,
+            _ => return Ok(None),
+        };
+        inner.pages.decode(value).map(Some)
+    }
+
+    /// Returns whether a value is stored under `key`.
+    ///
+    /// Only the leaf holding `key` is read, not the value, so this is cheaper than
+    /// [`Bucket::get`] for values large enough to spill onto overflow pages.
+    /// A nested bucket named `key` isn't a value, so it gives false.
+    pub fn contains(&self, key: &[u8]) -> Result<bool> {
+        Ok(matches!(self.inner.borrow().get(key)?, Some(Leaf::Kv(..))))
+    }
+
+    /// Stores `value` under `key`, replacing any existing value.
+    ///
+    /// Returns [`Error::ReadOnlyTx`] in a read-only transaction,
+    /// [`Error::IncompatibleValue`] if `key` is the name of a nested bucket, and
+    /// [`Error::ValueTooLarge`] if `value` is larger than the configured `max_value_size`.
+    /// Returns [`Error::TxTooLarge`] if the keys and values put in this transaction would
+    /// add up to more than the configured `max_tx_dirty_bytes`; the transaction can still
+    /// be committed with what it has, and the rest put in the next one.
+    pub fn put<K: Into<Bytes<'tx>>, V: Into<Bytes<'tx>>>(&self, key: K, value: V) -> Result<()> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+        self.inner.borrow_mut().put(key.into(), value.into())
+    }
+
+    /// Returns the bucket named `name` nested in this one.
+    ///
+    /// Returns [`Error::BucketMissing`] if there is no such bucket, and
+    /// [`Error::IncompatibleValue`] if `name` holds a key / value pair instead.
+    pub fn bucket(&self, name: &[u8]) -> Result<Bucket<'b, 'tx>> {
+        let bucket = self.inner.borrow_mut().get_bucket(name)?;
+        Ok(Bucket::new(bucket, self.writable))
+    }
+
+    /// Creates a new bucket named `name` nested in this one.
+    ///
+    /// Returns [`Error::BucketExists`] if a bucket or key / value pair named `name` already exists.
+    pub fn create_bucket(&self, name: &[u8]) -> Result<Bucket<'b, 'tx>> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+        let bucket = self.inner.borrow_mut().create_bucket(name.to_vec().into())?;
+        Ok(Bucket::new(bucket, true))
+    }
+
+    /// Returns a cursor over the key / value pairs in this bucket, in key order.
+    pub fn cursor(&self) -> Cursor<'b, 'tx> {
+        Cursor::new(self.inner.clone())
+    }
+
+    /// Returns the key / value pair with the smallest key, or `None` if the bucket is empty.
+    ///
+    /// Only the leftmost path down the tree is read. Nested buckets are skipped.
+    pub fn first(&self) -> Result<Option<(Bytes<'b>, Bytes<'b>)>> {
+        self.cursor().first()
+    }
+
+    /// Returns the key / value pair with the largest key, or `None` if the bucket is empty.
+    ///
+    /// Only the rightmost path down the tree is read. Nested buckets are skipped.
+    pub fn last(&self) -> Result<Option<(Bytes<'b>, Bytes<'b>)>> {
+        self.cursor().last()
+    }
+
+    /// Returns an iterator over the key / value pairs whose keys lie in `range`,
+    /// in key order. Nested buckets are skipped.
+    ///
+    /// Any range of byte strings will do, such as `start..end` or `..=end`
+    /// with `&[u8]` or `Vec<u8>` bounds.
+    pub fn range<K: AsRef<[u8]> + ?Sized, R: RangeBounds<K>>(&self, range: R) -> RangeIter<'b, 'tx> {
+        RangeIter::new(self.cursor(), range)
+    }
+
+    /// Returns an iterator over the key / value pairs whose keys start with
+    /// `prefix`, in key order. Nested buckets are skipped.
+    ///
+    /// Keys sharing a prefix are only next to each other in byte order, so
+    /// this may stop early in a bucket with a comparator.
+    pub fn prefix(&self, prefix: &[u8]) -> PrefixIter<'b, 'tx> {
+        PrefixIter::new(self.cursor(), prefix)
+    }
+
+    /// Returns an iterator over the keys in this bucket, in key order, without
+    /// reading their values. Nested buckets are skipped.
+    ///
+    /// Large values are left on their overflow pages, which makes this much
+    /// cheaper than a cursor for counting or checking keys. The page cache
+    /// copies whole pages, so set `cache_capacity` to 0 to get the full benefit.
+    pub fn keys(&self) -> KeyIter<'b, 'tx> {
+        KeyIter::new(self.cursor())
+    }
+
+    /// Calls `f` with each key / value pair in this bucket, in key order,
+    /// stopping at the first error `f` returns. Nested buckets are skipped.
+    pub fn for_each<F: FnMut(Bytes<'b>, Bytes<'b>) -> Result<()>>(&self, mut f: F) -> Result<()> {
+        let mut cursor = self.cursor();
+        while let Some((key, value)) = cursor.next()? {
+            f(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Copies every key / value pair in this bucket into a map, skipping nested buckets.
+    ///
+    /// Everything is copied at once, so this is only suited to small buckets, such as
+    /// in tests and migrations; [`Bucket::for_each`] or a cursor visits pairs without
+    /// holding them all. The map reflects the transaction's view of the bucket.
+    pub fn to_map(&self) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+        let mut map = BTreeMap::new();
+        self.for_each(|key, value| {
+            map.insert(key.to_vec(), value.to_vec());
+            Ok(())
+        })?;
+        Ok(map)
+    }
+
+    /// Copies every key in this bucket into a vector, in key order, skipping nested buckets.
+    ///
+    /// Like [`Bucket::to_map`], this copies everything, so it's only suited to small buckets,
+    /// and it reflects the transaction's view. [`Bucket::keys`] iterates without copying.
+    pub fn collect_keys(&self) -> Result<Vec<Vec<u8>>> {
+        self.keys().map(|key| key.map(|key| key.to_vec())).collect()
+    }
+
+    /// Increments the bucket's sequence and returns the new value, starting
+    /// from 1, for generating monotonic keys.
+    ///
+    /// The sequence is stored with the bucket, so it is kept on commit and
+    /// undone on rollback. Returns [`Error::ReadOnlyTx`] in a read-only transaction.
+    pub fn next_sequence(&self) -> Result<u64> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+        Ok(self.inner.borrow_mut().next_sequence())
+    }
+
+    /// Deletes the value stored under `key`.
+    ///
+    /// Returns [`Error::KeyValueMissing`] if there is no value, and
+    /// [`Error::IncompatibleValue`] if `key` is the name of a nested bucket.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+        self.inner.borrow_mut().delete(key).map(|_| ())
+    }
+
+    /// Deletes every key / value pair whose key lies in `range`, returning how many were deleted.
+    ///
+    /// Returns [`Error::IncompatibleValue`], deleting nothing, if a nested bucket's
+    /// name lies in `range`. Pages left unused, overflow pages included, are freed
+    /// when the transaction commits.
+    pub fn delete_range<K: AsRef<[u8]> + ?Sized, R: RangeBounds<K>>(&self, range: R) -> Result<usize> {
+        if !self.writable {
+            return Err(Error::ReadOnlyTx);
+        }
+        let start = range.start_bound().map(|k| k.as_ref());
+        let end = range.end_bound().map(|k| k.as_ref());
+        let cmp = self.inner.borrow().cmp.clone();
+
+        // The keys are collected first, as the cursor can't walk nodes that are being changed
+        let mut keys = Vec::new();
+        let mut cursor = self.cursor();
+        let mut leaf = match start {
+            Bound::Unbounded => cursor.next_leaf()?,
+            Bound::Included(start) | Bound::Excluded(start) => cursor.seek_leaf(start)?,
+        };
+        while let Some(current) = leaf {
+            let key = current.key();
+            let within = match end {
+                Bound::Unbounded => true,
+                Bound::Included(end) => cmp.compare(key, end).is_le(),
+                Bound::Excluded(end) => cmp.compare(key, end).is_lt(),
+            };
+            if !within {
+                break;
+            }
+            let excluded = matches!(start, Bound::Excluded(start) if cmp.compare(key, start).is_eq());
+            match &current {
+                _ if excluded => {}
+                Leaf::Bucket(..) => return Err(Error::IncompatibleValue),
+                Leaf::Kv(key, _) => keys.push(key.to_vec()),
+            }
+            leaf = cursor.next_leaf()?;
+        }
+        drop(cursor);
+
+        let mut inner = self.inner.borrow_mut();
+        for key in &keys {
+            inner.delete(key)?;
+        }
+        Ok(keys.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::config::Config;
+    use crate::freelist::Freelist;
+    use crate::meta::Meta;
+
+    use super::*;
+
+    fn bucket(pagesize: u64) -> InnerBucket<'static> {
+        let mut data = memmap2::MmapMut::map_anon(pagesize as usize * 4).unwrap();
+        #[allow(clippy::cast_ptr_alignment)]
+        let page = unsafe { &mut *(data.as_mut_ptr().add(pagesize as usize * 3) as *mut Page) };
+        page.id = 3;
+        page.page_type = Page::TYPE_LEAF;
+
+        let meta = Meta {
+            meta_page: 0,
+            integrity_code: 0,
+            version: 0,
+            pagesize,
+            root: BucketMeta { root_page: 3, next_int: 0, num_keys: 0 },
+            num_pages: 4,
+            freelist_page: 2,
+            tx_id: 1,
+            user_version: 0,
+            hash: [0; 32],
+        };
+        let pages = Pages::new(Arc::new(data.make_read_only().unwrap()), pagesize);
+        let freelist = Rc::new(RefCell::new(TxFreelist::new(meta.clone(), Freelist::new(), 8, Mode::LowSpace, 1.0, usize::MAX, usize::MAX)));
+        InnerBucket::from_meta(meta.root, pages, freelist)
+    }
+
+    #[test]
+    fn test_nested_buckets() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Config::new().path(dir.path()).open().unwrap();
+            let tx = db.tx(true).unwrap();
+            let c = tx
+                .create_bucket(b"a").unwrap()
+                .create_bucket(b"b").unwrap()
+                .create_bucket(b"c").unwrap();
+            c.put("key", "value").unwrap();
+            tx.commit().unwrap();
+
+            // a nested bucket that is never committed doesn't take up any pages
+            let num_pages = db.context.meta().unwrap().num_pages;
+            let tx = db.tx(true).unwrap();
+            let b = tx.bucket(b"a").unwrap().bucket(b"b").unwrap();
+            b.create_bucket(b"d").unwrap().put("key", "value").unwrap();
+            assert_eq!(b.create_bucket(b"c").err(), Some(Error::BucketExists));
+            tx.rollback().unwrap();
+            assert_eq!(db.context.meta().unwrap().num_pages, num_pages);
+
+            // a change two buckets down is written even though the buckets above it aren't changed
+            let tx = db.tx(true).unwrap();
+            tx.bucket(b"a").unwrap().bucket(b"b").unwrap().bucket(b"c").unwrap().put("other", "value").unwrap();
+            tx.commit().unwrap();
+            assert_eq!(db.len().unwrap(), 2);
+        }
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(false).unwrap();
+        let b = tx.bucket(b"a").unwrap().bucket(b"b").unwrap();
+        assert_eq!(b.bucket(b"c").unwrap().get(b"key").unwrap().unwrap().as_ref(), b"value");
+        assert_eq!(b.bucket(b"c").unwrap().get(b"other").unwrap().unwrap().as_ref(), b"value");
+        assert_eq!(b.bucket(b"d").err(), Some(Error::BucketMissing));
+        assert_eq!(b.create_bucket(b"e").err(), Some(Error::ReadOnlyTx));
+    }
+
+    #[test]
+    fn test_for_each() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..100 {
+            bucket.put(format!("key{:03}", i), "x".repeat(i)).unwrap();
+        }
+        bucket.create_bucket(b"nested").unwrap();
+
+        let mut total = 0;
+        bucket.for_each(|_, value| {
+            total += value.len();
+            Ok(())
+        }).unwrap();
+        assert_eq!(total, (0..100).sum::<usize>());
+
+        let mut seen = 0;
+        let err = bucket.for_each(|_, _| {
+            seen += 1;
+            match seen {
+                3 => Err(Error::KeyValueMissing),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(err, Err(Error::KeyValueMissing));
+        assert_eq!(seen, 3);
+    }
+
+    #[test]
+    fn test_next_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        assert_eq!(bucket.next_sequence().unwrap(), 1);
+        assert_eq!(bucket.next_sequence().unwrap(), 2);
+        assert_eq!(bucket.next_sequence().unwrap(), 3);
+        tx.commit().unwrap();
+
+        // a rolled back increment is undone, even when nothing else in the bucket changed
+        let tx = db.tx(true).unwrap();
+        assert_eq!(tx.bucket(b"data").unwrap().next_sequence().unwrap(), 4);
+        tx.rollback().unwrap();
+        drop(db);
+
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        assert_eq!(tx.bucket(b"data").unwrap().next_sequence().unwrap(), 4);
+        tx.commit().unwrap();
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert_eq!(bucket.next_sequence(), Err(Error::ReadOnlyTx));
+        assert_eq!(bucket.inner.borrow().meta.next_int, 4);
+    }
+
+    #[test]
+    fn test_comparator() {
+        use crate::config::flags::DBFlags;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = || Config::new().path(dir.path()).comparator(b"rev", Box::new(|a: &[u8], b: &[u8]| b.cmp(a)));
+        let db = config().open().unwrap();
+        let tx = db.tx(true).unwrap();
+        for name in [&b"rev"[..], b"fwd"] {
+            let bucket = tx.create_bucket(name).unwrap();
+            for i in 0..1000 {
+                bucket.put(format!("key{:04}", i), "value").unwrap();
+            }
+            bucket.create_bucket(b"nested").unwrap().put("a", "1").unwrap();
+            bucket.bucket(b"nested").unwrap().put("b", "2").unwrap();
+        }
+        tx.commit().unwrap();
+        drop(db);
+
+        let db = config().open().unwrap();
+        let tx = db.tx(false).unwrap();
+        let keys = |name: &[u8]| -> Vec<Vec<u8>> {
+            tx.bucket(name).unwrap().keys().map(|key| key.unwrap().to_vec()).collect()
+        };
+        let forward: Vec<Vec<u8>> = (0..1000).map(|i| format!("key{:04}", i).into_bytes()).collect();
+        let reverse: Vec<Vec<u8>> = forward.iter().rev().cloned().collect();
+        assert_eq!(keys(b"fwd"), forward);
+        assert_eq!(keys(b"rev"), reverse);
+        // nested buckets share the order of their top level bucket
+        let nested = tx.bucket(b"rev").unwrap().bucket(b"nested").unwrap();
+        assert_eq!(nested.keys().map(|key| key.unwrap().to_vec()).collect::<Vec<_>>(), vec![b"b".to_vec(), b"a".to_vec()]);
+
+        let rev = tx.bucket(b"rev").unwrap();
+        assert_eq!(rev.get(b"key0500").unwrap().unwrap().as_slice(), b"value");
+        let range: Vec<_> = rev.range(&b"key0010"[..]..&b"key0007"[..]).map(|pair| pair.unwrap().0.to_vec()).collect();
+        assert_eq!(range, vec![b"key0010".to_vec(), b"key0009".to_vec(), b"key0008".to_vec()]);
+        drop(tx);
+        drop(db);
+
+        // strict mode notices a bucket opened with the wrong order
+        let flags = DBFlags {
+            strict_mode: true,
+            mmap_populate: false,
+            direct_writes: false,
+        };
+        let db = Config::new()
+            .path(dir.path())
+            .flags(flags)
+            .comparator(b"fwd", Box::new(|a: &[u8], b: &[u8]| b.cmp(a)))
+            .open()
+            .unwrap();
+        let tx = db.tx(false).unwrap();
+        assert!(matches!(tx.bucket(b"fwd").unwrap().get(b"key0001"), Err(Error::InvalidDB(_))));
+    }
+
+    #[test]
+    fn test_large_values() {
+        use crate::node::NodeData;
+        use crate::page::LeafElement;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let pagesize = db.pagesize() as usize;
+        let large: Vec<u8> = (0..1 << 20).map(|i: u32| (i % 251) as u8).collect();
+        // A leaf holding just this value fills its page exactly
+        let exact = vec![1u8; pagesize - Page::HEADER_SIZE as usize - size_of::<LeafElement>() - 5];
+        let one_more = vec![2u8; exact.len() + 1];
+
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        bucket.put("large", large.clone()).unwrap();
+        bucket.put("exact", exact.clone()).unwrap();
+        bucket.put("extra", one_more.clone()).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert_eq!(bucket.get(b"large").unwrap().unwrap().as_slice(), &large[..]);
+        assert_eq!(bucket.get(b"exact").unwrap().unwrap().as_slice(), &exact[..]);
+        assert_eq!(bucket.get(b"extra").unwrap().unwrap().as_slice(), &one_more[..]);
+
+        let inner = bucket.inner.borrow();
+        let num_pages = |key: &[u8]| {
+            let root = inner.read_node(inner.root()).unwrap();
+            let root = root.borrow();
+            let page = match &root.data {
+                NodeData::Branches(branches) => branches[branch_index(branches, key, &inner.cmp)].page,
+                NodeData::Leaves(_) => panic!("expected the values to be split into leaves"),
+            };
+            inner.read_node(PageNodeID::Page(page)).unwrap().borrow().num_pages
+        };
+        assert_eq!(num_pages(b"exact"), 1);
+        assert_eq!(num_pages(b"extra"), 2);
+        assert_eq!(num_pages(b"large"), (large.len() as u64).div_ceil(pagesize as u64) + 1);
+    }
+
+    #[test]
+    fn test_contains() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).cache_capacity(0).open().unwrap();
+        let large = vec![7u8; 64 * db.pagesize() as usize];
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        bucket.put("small", "value").unwrap();
+        bucket.put("large", large).unwrap();
+        bucket.create_bucket(b"nested").unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert!(bucket.contains(b"small").unwrap());
+        assert!(!bucket.contains(b"missing").unwrap());
+        assert!(!bucket.contains(b"nested").unwrap());
+        // only the path to the leaf is read, and the value is never copied out of it
+        let before = tx.stats().pages_read;
+        assert!(tx.contains(b"data", b"large").unwrap());
+        assert!(tx.stats().pages_read - before < 4);
+        assert_eq!(tx.contains(b"nope", b"large"), Err(Error::BucketMissing));
+    }
+
+    #[test]
+    fn test_max_value_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).max_value_size(1000).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        bucket.put("under", vec![0u8; 999]).unwrap();
+        bucket.put("exact", vec![0u8; 1000]).unwrap();
+        assert_eq!(bucket.put("over", vec![0u8; 1001]), Err(Error::ValueTooLarge(1001, 1000)));
+        // a rejected put leaves the existing value alone
+        assert_eq!(bucket.put("under", vec![1u8; 5000]), Err(Error::ValueTooLarge(5000, 1000)));
+        tx.commit().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert_eq!(bucket.get(b"under").unwrap().unwrap().as_slice(), &[0u8; 999][..]);
+        assert!(bucket.get(b"over").unwrap().is_none());
+        drop(tx);
+        assert_eq!(db.len().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let large = vec![7u8; 3 * db.pagesize() as usize];
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..1000 {
+            let value = if i % 100 == 0 { large.clone() } else { b"value".to_vec() };
+            bucket.put(format!("key{:04}", i), value).unwrap();
+        }
+        bucket.create_bucket(b"key2000").unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert_eq!(bucket.delete_range("key0200".."key0800").unwrap(), 600);
+        assert_eq!(bucket.delete_range("key0900"..="key0909").unwrap(), 10);
+        // nothing left to delete
+        assert_eq!(bucket.delete_range("key0300".."key0400").unwrap(), 0);
+        // a nested bucket in the range is refused, leaving everything in place
+        assert_eq!(bucket.delete_range("key0950"..), Err(Error::IncompatibleValue));
+        assert!(bucket.get(b"key0999").unwrap().is_some());
+        // the six large values deleted free their overflow pages too
+        let stats = tx.commit().unwrap();
+        assert!(stats.pages_freed >= 6 * 3, "{} pages freed", stats.pages_freed);
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        let keys: Vec<_> = bucket.keys().map(|k| k.unwrap().to_vec()).collect();
+        assert_eq!(keys.len(), 1000 - 610);
+        assert_eq!(keys[199], b"key0199");
+        assert_eq!(keys[200], b"key0800");
+        assert_eq!(keys[299], b"key0899");
+        assert_eq!(keys[300], b"key0910");
+        assert_eq!(bucket.get(b"key0100").unwrap().unwrap().as_slice(), &large[..]);
+        assert!(bucket.get(b"key0500").unwrap().is_none());
+        assert!(bucket.bucket(b"key2000").is_ok());
+    }
+
+    #[test]
+    fn test_max_tx_dirty_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).max_tx_dirty_bytes(100_000).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        let mut err = None;
+        for i in 0..1000 {
+            if let Err(e) = bucket.put(format!("key{:03}", i), vec![0u8; 994]) {
+                err = Some((i, e));
+                break;
+            }
+        }
+        // each pair is 1000 bytes, so the 101st is refused
+        assert_eq!(err, Some((100, Error::TxTooLarge(101_000, 100_000))));
+        tx.commit().unwrap();
+
+        // the limit is per transaction
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert!(bucket.get(b"key099").unwrap().is_some());
+        assert!(bucket.get(b"key100").unwrap().is_none());
+        bucket.put("key100", vec![0u8; 994]).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(db.len().unwrap(), 101);
+    }
+
+    #[test]
+    fn test_first_last() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        assert_eq!(bucket.first().unwrap(), None);
+        assert_eq!(bucket.last().unwrap(), None);
+        // put out of order, across enough leaves to need a few branches
+        for i in (0..5000).map(|i| i * 7919 % 5000) {
+            bucket.put(format!("key{:04}", i), format!("value{}", i)).unwrap();
+        }
+        bucket.create_bucket(b"zzz").unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        let (key, value) = bucket.first().unwrap().unwrap();
+        assert_eq!((key.as_slice(), value.as_slice()), (&b"key0000"[..], &b"value0"[..]));
+        let (key, value) = bucket.last().unwrap().unwrap();
+        assert_eq!((key.as_slice(), value.as_slice()), (&b"key4999"[..], &b"value4999"[..]));
+        let keys: Vec<_> = bucket.keys().map(|k| k.unwrap().to_vec()).collect();
+        assert_eq!(keys.first().unwrap(), b"key0000");
+        assert_eq!(keys.last().unwrap(), b"key4999");
+    }
+
+    #[test]
+    fn test_to_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        assert!(bucket.to_map().unwrap().is_empty());
+        for i in (0..100).rev() {
+            bucket.put(format!("key{:02}", i), format!("value{}", i)).unwrap();
+        }
+        bucket.create_bucket(b"nested").unwrap().put("inner", "value").unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        bucket.put("key00", "changed").unwrap();
+        bucket.delete(b"key99").unwrap();
+        // the transaction's own changes are included
+        let map = bucket.to_map().unwrap();
+        assert_eq!(map.len(), 99);
+        assert_eq!(map[&b"key00"[..]], b"changed");
+        assert_eq!(map[&b"key42"[..]], b"value42");
+        assert!(!map.contains_key(&b"nested"[..]));
+        let keys = bucket.collect_keys().unwrap();
+        assert_eq!(keys, map.into_keys().collect::<Vec<_>>());
+    }
+
+    fn bytes(s: String) -> Bytes<'static> {
+        Bytes::Vec(Rc::new(s.into_bytes()))
+    }
+
+    #[test]
+    fn test_put_get_spill() {
+        let mut bucket = bucket(1024);
+        for i in 0..500 {
+            bucket.put(bytes(format!("key{:04}", i)), bytes(format!("value{}", i))).unwrap();
+        }
+        assert!(matches!(
+            bucket.get(b"key0042").unwrap(),
+            Some(Leaf::Kv(_, v)) if v.as_ref() == b"value42"
+        ));
+        assert!(bucket.get(b"missing").unwrap().is_none());
+        bucket.delete(b"key0001").unwrap();
+        assert!(bucket.get(b"key0001").unwrap().is_none());
+        assert_eq!(bucket.delete(b"key0001").unwrap_err(), Error::KeyValueMissing);
+
+        let meta = bucket.spill().unwrap();
+        assert!(meta.root_page >= 4);
+        // the leaves no longer fit on one page, so the root had to become a branch
+        let freelist = bucket.freelist.borrow();
+        let buffers: HashMap<PageID, &[u8]> = freelist.page_buffers().collect();
+        let root = Page::from_buf(buffers[&meta.root_page], 0, 1024);
+        assert_eq!(root.page_type, Page::TYPE_BRANCH);
+    }
+}
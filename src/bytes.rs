@@ -1,4 +1,12 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, Range};
 use std::rc::Rc;
+use std::string::FromUtf8Error;
+use std::sync::Arc;
+
+use memmap2::Mmap;
 
 #[derive(Debug, Clone)]
 pub enum Bytes<'a> {
@@ -6,6 +14,239 @@ pub enum Bytes<'a> {
     Bytes(bytes::Bytes),
     Vec(Rc<Vec<u8>>),
     String(Rc<String>),
+    /// A range of the memory map, which stays mapped for as long as this lives,
+    /// even if the database remaps its file. The pages it covers may be reused
+    /// once the transaction it was read in ends, hence the lifetime.
+    Mmap(Arc<Mmap>, Range<usize>),
+}
+
+impl<'a> Bytes<'a> {
+    /// Returns the bytes, whichever variant holds them.
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::Slice(s) => s,
+            Self::Bytes(b) => b,
+            Self::Vec(v) => v,
+            Self::String(s) => s.as_bytes(),
+            Self::Mmap(map, range) => &map[range.clone()],
+        }
+    }
+
+    /// Returns the bytes as a `str`, or `None` if they aren't valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => std::str::from_utf8(self.as_slice()).ok(),
+        }
+    }
+
+    /// Copies borrowed bytes out of the database, so the value can outlive its transaction.
+    pub fn into_owned(self) -> Bytes<'static> {
+        match self {
+            Self::Slice(s) => Bytes::Vec(Rc::new(s.to_vec())),
+            Self::Bytes(b) => Bytes::Bytes(b),
+            Self::Vec(v) => Bytes::Vec(v),
+            Self::String(s) => Bytes::String(s),
+            Self::Mmap(map, range) => Bytes::Vec(Rc::new(map[range].to_vec())),
+        }
+    }
+}
+
+impl<'a> Deref for Bytes<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl<'a> AsRef<[u8]> for Bytes<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+/// Compares two byte strings with a single `memcmp`, the shorter one
+/// sorting first when it is a prefix of the other.
+#[inline]
+pub(crate) fn compare(a: &[u8], b: &[u8]) -> Ordering {
+    let len = a.len().min(b.len());
+    match unsafe { libc::memcmp(a.as_ptr().cast(), b.as_ptr().cast(), len) } {
+        0 => a.len().cmp(&b.len()),
+        n if n < 0 => Ordering::Less,
+        _ => Ordering::Greater,
+    }
+}
+
+// Keys read from pages are `Slice`s, or `Bytes` when they come from the page
+// cache, so those pairs are compared without going through `as_slice`.
+macro_rules! with_slices {
+    ($a:expr, $b:expr, $f:expr) => {
+        match ($a, $b) {
+            (Bytes::Slice(a), Bytes::Slice(b)) => $f(a, b),
+            (Bytes::Bytes(a), Bytes::Bytes(b)) => $f(a, b),
+            (a, b) => $f(a.as_slice(), b.as_slice()),
+        }
+    };
+}
+
+// Comparisons and hashing only look at the bytes, so the same key
+// matches whether it was read from a page or supplied by the user.
+impl<'a> PartialEq for Bytes<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        with_slices!(self, other, |a: &[u8], b: &[u8]| a.len() == b.len() && compare(a, b).is_eq())
+    }
+}
+
+impl<'a> Eq for Bytes<'a> {}
+
+impl<'a> PartialOrd for Bytes<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> Ord for Bytes<'a> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        with_slices!(self, other, compare)
+    }
+}
+
+impl<'a> Hash for Bytes<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_slice().hash(state)
+    }
+}
+
+// Lets maps keyed by `Bytes` be searched with a plain slice
+impl<'a> Borrow<[u8]> for Bytes<'a> {
+    fn borrow(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl<'a> From<&'a [u8]> for Bytes<'a> {
+    fn from(s: &'a [u8]) -> Self {
+        Self::Slice(s)
+    }
+}
+
+impl<'a> From<&'a str> for Bytes<'a> {
+    fn from(s: &'a str) -> Self {
+        Self::Slice(s.as_bytes())
+    }
+}
+
+impl<'a> From<bytes::Bytes> for Bytes<'a> {
+    fn from(b: bytes::Bytes) -> Self {
+        Self::Bytes(b)
+    }
+}
+
+impl<'a> From<Vec<u8>> for Bytes<'a> {
+    fn from(v: Vec<u8>) -> Self {
+        Self::Vec(Rc::new(v))
+    }
+}
+
+impl<'a> From<String> for Bytes<'a> {
+    fn from(s: String) -> Self {
+        Self::String(Rc::new(s))
+    }
+}
+
+// Checks the bytes are UTF-8, without copying them if they're already owned and unshared
+impl<'a> TryFrom<Bytes<'a>> for String {
+    type Error = FromUtf8Error;
+
+    fn try_from(b: Bytes<'a>) -> Result<String, FromUtf8Error> {
+        match b {
+            Bytes::String(s) => Ok(Rc::try_unwrap(s).unwrap_or_else(|s| s.to_string())),
+            Bytes::Vec(v) => String::from_utf8(Rc::try_unwrap(v).unwrap_or_else(|v| v.to_vec())),
+            b => String::from_utf8(b.as_slice().to_vec()),
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+
+    use super::*;
+
+    fn hash(b: &Bytes) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        b.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_variants_compare_by_content() {
+        let slice = Bytes::Slice(b"abc");
+        let vec = Bytes::from(b"abc".to_vec());
+        assert_eq!(slice, vec);
+        assert_eq!(slice.cmp(&vec), Ordering::Equal);
+        assert_eq!(hash(&slice), hash(&vec));
+        assert_eq!(&*vec, b"abc");
+
+        let bigger = Bytes::from(String::from("abd"));
+        assert!(slice < bigger);
+        assert!(bigger > vec);
+        assert_eq!(bigger.as_slice(), b"abd");
+        assert!(Bytes::Slice(b"ab") < slice);
+        assert!(Bytes::Slice(b"") < Bytes::Slice(b"\x00"));
+        assert_eq!(Bytes::Slice(b"\xff").cmp(&Bytes::Slice(b"\x01")), Ordering::Greater);
+    }
 
+    #[test]
+    fn test_utf8() {
+        let slice = Bytes::Slice("héllo".as_bytes());
+        assert_eq!(slice.as_str(), Some("héllo"));
+        assert_eq!(String::try_from(slice).unwrap(), "héllo");
+
+        let invalid = Bytes::Slice(b"\xff\xfe");
+        assert_eq!(invalid.as_str(), None);
+        let err = String::try_from(invalid).unwrap_err();
+        assert_eq!(err.into_bytes(), b"\xff\xfe");
+        assert!(String::try_from(Bytes::from(vec![b'a', 0x80])).is_err());
+
+        let string = Bytes::from(String::from("text"));
+        assert_eq!(string.as_str(), Some("text"));
+        let shared = string.clone();
+        assert_eq!(String::try_from(string).unwrap(), "text");
+        assert_eq!(String::try_from(shared).unwrap(), "text");
+    }
+
+    // What comparing keys one byte at a time costs
+    fn naive_cmp(a: &[u8], b: &[u8]) -> Ordering {
+        for i in 0..a.len().min(b.len()) {
+            match a[i].cmp(&b[i]) {
+                Ordering::Equal => continue,
+                ord => return ord,
+            }
+        }
+        a.len().cmp(&b.len())
+    }
+
+    #[test]
+    fn test_compare_faster_than_naive() {
+        use std::hint::black_box;
+        use std::time::Instant;
+
+        // keys that only differ in their last byte, as neighbouring keys often do
+        let a = [7u8; 64];
+        let mut b = a;
+        b[63] = 8;
+        let (x, y) = (Bytes::Slice(&a), Bytes::Slice(&b));
+        let time = |f: &dyn Fn() -> Ordering| {
+            let start = Instant::now();
+            for _ in 0..100_000 {
+                assert_eq!(black_box(f()), Ordering::Less);
+            }
+            start.elapsed()
+        };
+        let naive = time(&|| naive_cmp(black_box(&a), black_box(&b)));
+        let fast = time(&|| black_box(&x).cmp(black_box(&y)));
+        assert!(fast < naive, "memcmp took {:?}, a byte at a time took {:?}", fast, naive);
+    }
+}
@@ -0,0 +1,162 @@
+use std::rc::Rc;
+
+use crate::bytes::Bytes;
+use crate::config::Codec;
+use crate::errors::{Error, Result};
+
+// The first byte of every value in a database with compression on says how the rest is encoded
+const RAW: u8 = 0;
+const LZ4: u8 = 1;
+const ZSTD: u8 = 2;
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+impl Codec {
+    /// Encodes `value` to be stored in a page, tagged with how it was encoded.
+    /// Values that don't shrink are stored as they are, behind the tag.
+    pub(crate) fn compress(self, value: &[u8]) -> Vec<u8> {
+        let (tag, encoded) = match self.encode(value) {
+            Some((tag, compressed)) if compressed.len() < value.len() => (tag, compressed),
+            _ => (RAW, value.to_vec()),
+        };
+        let mut out = Vec::with_capacity(encoded.len() + 1);
+        out.push(tag);
+        out.extend_from_slice(&encoded);
+        out
+    }
+
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    fn encode(self, value: &[u8]) -> Option<(u8, Vec<u8>)> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => Some((LZ4, lz4_flex::compress_prepend_size(value))),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd::bulk::compress(value, ZSTD_LEVEL).ok().map(|c| (ZSTD, c)),
+        }
+    }
+}
+
+/// Decodes a value written by [`Codec::compress`], whichever codec it was written with.
+///
+/// Values stored as they are are returned without a copy. Returns
+/// [`Error::Unsupported`] for a codec this build doesn't include.
+pub(crate) fn decompress(value: Bytes<'_>) -> Result<Bytes<'_>> {
+    let (&tag, rest) = match value.as_slice().split_first() {
+        Some(split) => split,
+        None => return Err(Error::InvalidDB("compressed value is missing its tag".to_string())),
+    };
+    let decompressed = match tag {
+        RAW => {
+            return Ok(match value {
+                Bytes::Slice(s) => Bytes::Slice(&s[1..]),
+                Bytes::Bytes(b) => Bytes::Bytes(b.slice(1..)),
+                Bytes::Mmap(map, range) => Bytes::Mmap(map, range.start + 1..range.end),
+                value => Bytes::Vec(Rc::new(value[1..].to_vec())),
+            })
+        }
+        LZ4 => lz4_decompress(rest)?,
+        ZSTD => zstd_decompress(rest)?,
+        tag => return Err(Error::InvalidDB(format!("unknown compression tag {}", tag))),
+    };
+    Ok(Bytes::Vec(Rc::new(decompressed)))
+}
+
+#[cfg(feature = "lz4")]
+fn lz4_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    lz4_flex::decompress_size_prepended(data)
+        .map_err(|e| Error::InvalidDB(format!("lz4 compressed value is corrupt: {}", e)))
+}
+
+#[cfg(not(feature = "lz4"))]
+fn lz4_decompress(_: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::Unsupported("values compressed with lz4 need the lz4 feature"))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    zstd::stream::decode_all(data)
+        .map_err(|e| Error::InvalidDB(format!("zstd compressed value is corrupt: {}", e)))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decompress(_: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::Unsupported("values compressed with zstd need the zstd feature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_values_unchanged() {
+        let value = Bytes::Slice(b"\x00value");
+        assert!(matches!(decompress(value).unwrap(), Bytes::Slice(b"value")));
+        assert_eq!(decompress(Bytes::from(vec![RAW])).unwrap().as_slice(), b"");
+        assert!(matches!(decompress(Bytes::Slice(b"\x09value")), Err(Error::InvalidDB(_))));
+        assert!(matches!(decompress(Bytes::Slice(b"")), Err(Error::InvalidDB(_))));
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    #[test]
+    fn test_round_trip() {
+        let codecs = [
+            #[cfg(feature = "lz4")]
+            Codec::Lz4,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd,
+        ];
+        for codec in codecs {
+            let compressible = vec![b'a'; 4000];
+            let encoded = codec.compress(&compressible);
+            assert!(encoded.len() < compressible.len());
+            assert_eq!(decompress(Bytes::from(encoded)).unwrap().as_slice(), &compressible[..]);
+
+            // too short to shrink, so stored as it is
+            let encoded = codec.compress(b"ab");
+            assert_eq!(encoded, b"\x00ab");
+            assert_eq!(decompress(Bytes::from(encoded)).unwrap().as_slice(), b"ab");
+        }
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    #[test]
+    fn test_compressed_values_use_fewer_pages() {
+        use crate::config::Config;
+
+        #[cfg(feature = "lz4")]
+        let codec = Codec::Lz4;
+        #[cfg(not(feature = "lz4"))]
+        let codec = Codec::Zstd;
+        let write = |config: Config| {
+            let db = config.open().unwrap();
+            let tx = db.tx(true).unwrap();
+            let bucket = tx.create_bucket(b"data").unwrap();
+            for i in 0..2000 {
+                bucket.put(format!("key{:04}", i), vec![b'a' + (i % 26) as u8; 1000]).unwrap();
+            }
+            tx.commit().unwrap();
+            let num_pages = db.tx(false).unwrap().meta().num_pages;
+            num_pages
+        };
+        let plain_dir = tempfile::tempdir().unwrap();
+        let plain = write(Config::new().path(plain_dir.path()));
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::new().path(dir.path()).compression(Some(codec));
+        let compressed = write(config.clone());
+        assert!(compressed < plain / 2, "{} pages compressed, {} plain", compressed, plain);
+
+        let db = config.open().unwrap();
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert_eq!(bucket.get(b"key0027").unwrap().unwrap().as_slice(), &[b'b'; 1000][..]);
+        let (key, value) = bucket.cursor().next().unwrap().unwrap();
+        assert_eq!((key.as_slice(), value.as_slice()), (&b"key0000"[..], &[b'a'; 1000][..]));
+        drop(tx);
+        drop(db);
+
+        // whether values are compressed can't change
+        let err = Config::new().path(dir.path()).open().err().unwrap();
+        assert!(matches!(err, Error::InvalidDB(msg) if msg.contains("use_compression")));
+    }
+}
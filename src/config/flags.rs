@@ -1,12 +1,68 @@
+use std::collections::HashMap;
+
+use crate::errors::{Error, Result};
+
 /// A persisted configuration about high-level
 /// storage file information
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
-struct StorageParameters {
+pub(crate) struct StorageParameters {
     pub segment_size: usize,
     pub use_compression: bool,
     pub version: (usize, usize),
 }
 
+impl StorageParameters {
+    /// Serializes the parameters as `key: value` lines followed by a crc32 of them.
+    pub(crate) fn serialize(&self) -> Vec<u8> {
+        let mut out = format!(
+            "segment_size: {}\nuse_compression: {}\nversion: {}.{}\n",
+            self.segment_size, self.use_compression, self.version.0, self.version.1
+        )
+        .into_bytes();
+        let crc = crc32fast::hash(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    pub(crate) fn deserialize(bytes: &[u8]) -> Result<StorageParameters> {
+        if bytes.len() < 4 {
+            return Err(corrupt("it is too short"));
+        }
+        let (body, crc) = bytes.split_at(bytes.len() - 4);
+        if crc32fast::hash(body).to_le_bytes() != crc {
+            return Err(corrupt("its checksum does not match"));
+        }
+        let body = std::str::from_utf8(body).map_err(|_| corrupt("it is not valid utf8"))?;
+        let params: HashMap<&str, &str> = body
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .collect();
+
+        let segment_size = params
+            .get("segment_size")
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| corrupt("segment_size is missing"))?;
+        let use_compression = params
+            .get("use_compression")
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| corrupt("use_compression is missing"))?;
+        let version = params
+            .get("version")
+            .and_then(|v| v.split_once('.'))
+            .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+            .ok_or_else(|| corrupt("version is missing"))?;
+        Ok(StorageParameters {
+            segment_size,
+            use_compression,
+            version,
+        })
+    }
+}
+
+fn corrupt(reason: &str) -> Error {
+    Error::InvalidDB(format!("the persisted config is corrupt: {}", reason))
+}
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct DBFlags {
     pub(crate) strict_mode: bool,
@@ -2,27 +2,35 @@ use std::fs;
 use std::fs::File;
 use std::io::ErrorKind;
 use std::ops::Deref;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use std::time::{Duration, Instant, SystemTime};
 
 use log::error;
 use page_size::get as get_page_size;
 use parking_lot::lock_api::Mutex;
-use parking_lot::RwLock;
+use parking_lot::{Condvar, RwLock};
 
-use crate::config::running_config::RunningConfig;
+use crate::config::flags::{DBFlags, StorageParameters};
+use crate::config::running_config::{CommitGroup, RunningConfig};
 use crate::db::DB;
 use crate::errors::{Error, Result};
+use crate::event_log::EventLog;
 use crate::freelist::Freelist;
-use crate::inner::Inner;
+use crate::inner::{FlushErrorHandler, Inner};
 use crate::maybe_fsync_directory;
+use crate::metrics::Metrics;
+use crate::node::{Comparator, CompareFn};
+use crate::options::init_pages;
 use crate::sys::sys_limits;
-use crate::utils::mmap;
+use crate::utils::{io_alignment, mmap};
 
 pub(crate) mod flags;
 pub(crate) mod running_config;
+pub(crate) mod snapshot;
 
 
 macro_rules! supported {
@@ -34,25 +42,60 @@ macro_rules! supported {
 }
 
 macro_rules! builder {
-    ($(($name:ident, $t:ty, $desc:expr)),*) => {
+    ($(($name:ident, $try_name:ident, $t:ty, $desc:expr)),*) => {
         $(
             #[doc=$desc]
-            pub fn $name(mut self, to: $t) -> Self {
-                if Arc::strong_count(&self.0) != 1 {
-                    error!(
-                        "config has already been used to start \
-                         the system and probably should not be \
-                         mutated",
-                    );
-                }
-                let m = Arc::make_mut(&mut self.0);
-                m.$name = to;
-                self
+            pub fn $name(self, to: $t) -> Self {
+                self.update(|m| m.$name = to)
+            }
+
+            #[doc=concat!(
+                "Like [`Config::", stringify!($name), "`], but returns [`Error::Unsupported`] \
+                 if the config is shared, such as with an open database, whose settings \
+                 a change wouldn't reach."
+            )]
+            pub fn $try_name(self, to: $t) -> Result<Self> {
+                self.try_update(|m| m.$name = to)
             }
         )*
     }
 }
 
+const TEMP_PREFIX: &str = "pagecache.tmp.";
+
+const MIN_SEGMENT_SIZE: usize = 256;
+const MAX_SEGMENT_SIZE: usize = 1 << 24;
+// Two meta pages, the freelist and the root leaf
+const MIN_INITIAL_PAGES: usize = 4;
+
+#[cfg(target_os = "linux")]
+fn memory_file() -> Result<File> {
+    use std::os::fd::FromRawFd;
+
+    let fd = unsafe { libc::memfd_create(c"memdb".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn memory_file() -> Result<File> {
+    Err(Error::Unsupported("in-memory databases rely on memfd, which is only available on Linux"))
+}
+
+// The power of two closest to `size` that validate() accepts as a segment_size
+fn nearest_segment_size(size: usize) -> usize {
+    let size = size.clamp(MIN_SEGMENT_SIZE, MAX_SEGMENT_SIZE);
+    let above = size.next_power_of_two();
+    let below = above / 2;
+    if size - below < above - size {
+        below
+    } else {
+        above
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Mode {
     /// In this mode, the database will make
@@ -68,6 +111,34 @@ pub enum Mode {
     HighThroughput,
 }
 
+/// How the database expects to access its memory map, passed on to the OS
+/// to tune read-ahead and caching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MmapAdvice {
+    /// No particular pattern
+    Normal,
+    /// Pages are read in no particular order, such as by point lookups,
+    /// so reading ahead is wasted
+    #[default]
+    Random,
+    /// Pages are read in order, such as by full scans, so reading ahead pays off
+    Sequential,
+    /// The whole file will be needed soon, so it's worth reading it in ahead of time
+    WillNeed,
+}
+
+/// How values are compressed before they're written to pages. Each codec is
+/// behind a feature of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// LZ4, which is fast but compresses less
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Zstandard, which compresses more but is slower
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
 
 #[derive(Default, Debug, Clone)]
 pub struct Config(Arc<Inner>);
@@ -92,52 +163,275 @@ impl Config {
         self
     }
 
+    /// Returns a copy of this config for a separate, persistent database at `path`.
+    pub(crate) fn with_new_path<P: AsRef<Path>>(&self, path: P) -> Config {
+        let mut inner = (*self.0).clone();
+        inner.path = path.as_ref().to_path_buf();
+        inner.temporary = false;
+        inner.read_only = false;
+        Config(Arc::new(inner))
+    }
+
+    pub(crate) fn flags(mut self, flags: DBFlags) -> Config {
+        let m = Arc::make_mut(&mut self.0);
+        m.flags = flags;
+        self
+    }
+
     pub fn open(&self) -> Result<DB> {
+        self.validate()?;
+        let file = self.open_file()?;
+        self.start(file, true)
+    }
+
+    /// Opens the database stored in an already open (and locked) file.
+    ///
+    /// Unlike [`Config::open`], this never touches the filesystem by path:
+    /// no directories are created and no lock is taken, so the file can come
+    /// from a sandbox or be a sealed `memfd`. The meta is still read and
+    /// validated as usual.
+    pub fn open_file_handle(&self, file: File) -> Result<DB> {
+        self.validate()?;
+        self.start(file, false)
+    }
+
+    /// Opens a new, empty database held in memory rather than in a file.
+    ///
+    /// The database is backed by an anonymous `memfd`, so nothing on the
+    /// filesystem is created or locked, and nothing is ever synced or
+    /// snapshotted. Everything is lost when the database is dropped.
+    /// Only available on Linux.
+    pub fn open_in_memory(&self) -> Result<DB> {
         self.validate()?;
+        let mut config = self.clone();
+        Arc::make_mut(&mut config.0).in_memory = true;
+        config.start(memory_file()?, false)
+    }
+
+    // `owns_lock` is whether the lock on `file` was taken by `open_file`, to be released on drop
+    fn start(&self, file: File, owns_lock: bool) -> Result<DB> {
         let mut config = self.clone();
         config.limit_cache_max_memory();
 
-        let file = config.open_file()?;
-        let data = mmap(&file, self.flags.mmap_populate)?;
+        let data = mmap(&file, self.flags.mmap_populate, self.mmap_advise)?;
         let pagesize = get_page_size() as u64;
         if pagesize < 1024 {
             panic!("Pagesize must be 1024 bytes minimum");
         }
 
+        let io_align = io_alignment(&file, self.flags.direct_writes, pagesize)?;
+
+        let sync_file = file.try_clone()?;
         let config = RunningConfig {
             inner: config,
             file: Mutex::new(Arc::new(file)),
+            owns_lock,
             data: Mutex::new(Arc::new(data)),
             freelist: Mutex::new(Freelist::new()),
             open_ro_txs: Mutex::new(Vec::new()),
             mmap_lock: RwLock::new(()),
             pagesize,
+            io_align,
+            degraded: AtomicBool::new(false),
+            sync_file,
+            unflushed: AtomicU64::new(0),
+            commits: Mutex::new(CommitGroup::default()),
+            commit_synced: Condvar::new(),
         };
         DB::start_inner(config)
     }
 
     pub fn open_file(&self) -> Result<File> {
+        if self.read_only {
+            self.verify_config()?;
+            let file = fs::OpenOptions::new().read(true).open(self.db_path())?;
+            return self.try_lock(file);
+        }
+
         let heap_dir: PathBuf = self.get_path().join("heap");
         if !heap_dir.exists() {
             fs::create_dir_all(heap_dir)?;
         }
 
         self.verify_config()?;
-        let mut options = fs::OpenOptions::new();
-        let _ = options.create(true);
-        let _ = options.read(true);
-        let _ = options.write(true);
-
         let _ = File::create(
             self.get_path().join("DO_NOT_USE_THIS_DIRECTORY_FOR_ANYTHING"),
         );
 
-        let file = self.try_lock(options.open(&self.db_path())?)?;
+        if !self.db_path().exists() {
+            let pagesize = get_page_size() as u64;
+            self.create_db_file(|file, align| {
+                init_pages(file, pagesize, self.initial_num_pages(pagesize), self.application_id, align)
+            })?;
+        }
+        let file = self.try_lock(self.db_open_options().open(self.db_path())?)?;
         maybe_fsync_directory(self.get_path())?;
         Ok(file)
     }
 
-    pub fn flush_every_ms(mut self, every_ms: Option<u64>) -> Self {
+    fn db_open_options(&self) -> fs::OpenOptions {
+        let mut options = fs::OpenOptions::new();
+        let _ = options.read(true);
+        let _ = options.write(true);
+        // validate() has already rejected direct_writes on other platforms
+        #[cfg(target_os = "linux")]
+        if self.flags.direct_writes {
+            let _ = options.custom_flags(libc::O_DIRECT);
+        }
+        options
+    }
+
+    /// Creates the `db` file with the pages written by `init`, given the file and
+    /// its I/O alignment, so that it either doesn't exist or is fully initialized.
+    ///
+    /// The pages go to a temporary file in the same directory, which is synced and
+    /// only then linked in as `db`, so a crash part way leaves no half written
+    /// database for the next open to trip over. Linking, unlike a rename, never
+    /// replaces a database another process created in the meantime.
+    fn create_db_file(&self, init: impl FnOnce(&File, usize) -> Result<()>) -> Result<()> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let counter = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let tmp = self.get_path().join(format!("db.init.{}.{}", std::process::id(), counter));
+
+        let mut options = self.db_open_options();
+        let _ = options.create_new(true);
+        let file = options.open(&tmp)?;
+        let created = io_alignment(&file, self.flags.direct_writes, get_page_size() as u64)
+            .and_then(|align| init(&file, align))
+            .and_then(|()| {
+                file.sync_all()?;
+                match fs::hard_link(&tmp, self.db_path()) {
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(()),
+                    result => Ok(result?),
+                }
+            });
+        let _ = fs::remove_file(&tmp);
+        created?;
+        maybe_fsync_directory(self.get_path())?;
+        Ok(())
+    }
+
+    /// Deletes the database after drop. If no path is set, uses /dev/shm on linux.
+    pub fn temporary(self, to: bool) -> Self {
+        self.update(|m| m.set_temporary(to))
+    }
+
+    /// Like [`Config::temporary`], but returns [`Error::Unsupported`] if the config is
+    /// shared, such as with an open database, whose settings a change wouldn't reach.
+    pub fn try_temporary(self, to: bool) -> Result<Self> {
+        self.try_update(|m| m.set_temporary(to))
+    }
+
+    /// Sets how often the background flusher syncs the database, in milliseconds.
+    ///
+    /// `None` disables the flusher, so every commit syncs before it returns.
+    /// `Some(0)` would have the flusher spin, so opening fails with it.
+    ///
+    /// With a flusher, commits return before they're synced, so a crash or power loss can
+    /// lose the commits since the last flush. Their meta page is written without ordering
+    /// it after their pages, so after a power loss the newest meta may point at pages that
+    /// never reached the disk; [`DB::repair`](crate::DB::repair) recovers what it can.
+    /// Pages freed by a commit aren't reused until a flush has made it durable.
+    pub fn flush_every_ms(self, every_ms: Option<u64>) -> Self {
+        self.update(|m| m.flush_every_ms = every_ms)
+    }
+
+    /// Sets a callback invoked when the background flusher fails to sync the database.
+    ///
+    /// After such a failure, writable transactions are refused until a call to
+    /// `DB::flush` succeeds, so durability is never silently lost.
+    pub fn on_flush_error(self, handler: Box<dyn Fn(&Error) + Send + Sync>) -> Self {
+        self.update(|m| m.on_flush_error = Some(FlushErrorHandler(Arc::from(handler))))
+    }
+
+    /// Like [`Config::on_flush_error`], but returns [`Error::Unsupported`] if the config is
+    /// shared, such as with an open database, whose settings a change wouldn't reach.
+    pub fn try_on_flush_error(self, handler: Box<dyn Fn(&Error) + Send + Sync>) -> Result<Self> {
+        self.try_update(|m| m.on_flush_error = Some(FlushErrorHandler(Arc::from(handler))))
+    }
+
+    /// Orders the keys of the top level bucket named `bucket`, and of every
+    /// bucket nested in it, with `cmp` instead of by their bytes.
+    ///
+    /// The comparator isn't stored in the database, so it must be set every time
+    /// the database is opened. Lookups in a bucket opened with a different order
+    /// than its keys were written in can miss keys; `strict_mode` catches this
+    /// by checking the order of every page read, returning [`Error::InvalidDB`].
+    pub fn comparator(self, bucket: &[u8], cmp: Box<CompareFn>) -> Self {
+        self.update(|m| {
+            Arc::make_mut(&mut m.comparators).insert(bucket.to_vec(), Comparator(Some(Arc::from(cmp))));
+        })
+    }
+
+    /// Like [`Config::comparator`], but returns [`Error::Unsupported`] if the config is
+    /// shared, such as with an open database, whose settings a change wouldn't reach.
+    pub fn try_comparator(self, bucket: &[u8], cmp: Box<CompareFn>) -> Result<Self> {
+        self.try_update(|m| {
+            Arc::make_mut(&mut m.comparators).insert(bucket.to_vec(), Comparator(Some(Arc::from(cmp))));
+        })
+    }
+
+    /// Sets the codec values are compressed with before they're written, or
+    /// `None`, the default, to store them as they are.
+    ///
+    /// Whether compression is on is saved in the `conf` file, and has to match
+    /// when the database is reopened. Values remember which codec compressed
+    /// them, so the codec itself can change between opens.
+    pub fn compression(self, codec: Option<Codec>) -> Self {
+        self.update(|m| {
+            m.compression = codec;
+            m.use_compression = codec.is_some();
+        })
+    }
+
+    /// Like [`Config::compression`], but returns [`Error::Unsupported`] if the config is
+    /// shared, such as with an open database, whose settings a change wouldn't reach.
+    pub fn try_compression(self, codec: Option<Codec>) -> Result<Self> {
+        self.try_update(|m| {
+            m.compression = codec;
+            m.use_compression = codec.is_some();
+        })
+    }
+
+    /// Keeps a temporary database once it's closed, by moving the directory
+    /// generated for it to `path` instead of removing it. It can then be reopened
+    /// from `path` like any other database.
+    ///
+    /// A temporary database given a path of its own is never removed, so this
+    /// only applies to one opened without a path. If something already exists at
+    /// `path` when the database is closed, the generated directory is left where it
+    /// is. Returns [`Error::Unsupported`] if the config is in use by an open database.
+    pub fn persist_temporary<P: AsRef<Path>>(self, path: P) -> Result<Self> {
+        self.try_update(|m| m.persist_path = Some(path.as_ref().to_path_buf()))
+    }
+
+    /// Installs an [`EventLog`] that is told when transactions begin and commit
+    /// and when pages are freed.
+    pub fn event_log(self, handler: Arc<dyn EventLog>) -> Self {
+        self.update(|m| m.event_log = handler)
+    }
+
+    /// Like [`Config::event_log`], but returns [`Error::Unsupported`] if the config is
+    /// shared, such as with an open database, whose settings a change wouldn't reach.
+    pub fn try_event_log(self, handler: Arc<dyn EventLog>) -> Result<Self> {
+        self.try_update(|m| m.event_log = handler)
+    }
+
+    /// Installs a [`Metrics`] that is given counters and gauges to pass on to
+    /// a metrics system.
+    pub fn metrics(self, metrics: Arc<dyn Metrics>) -> Self {
+        self.update(|m| m.metrics = metrics)
+    }
+
+    /// Like [`Config::metrics`], but returns [`Error::Unsupported`] if the config is
+    /// shared, such as with an open database, whose settings a change wouldn't reach.
+    pub fn try_metrics(self, metrics: Arc<dyn Metrics>) -> Result<Self> {
+        self.try_update(|m| m.metrics = metrics)
+    }
+
+    // Applies a setter, logging an error if the config is shared, such as with an
+    // open database, which the change won't reach
+    fn update(mut self, set: impl FnOnce(&mut Inner)) -> Self {
         if Arc::strong_count(&self.0) != 1 {
             error!(
                 "config has already been used to start \
@@ -145,11 +439,19 @@ impl Config {
                  mutated",
             );
         }
-        let m = Arc::make_mut(&mut self.0);
-        m.flush_every_ms = every_ms;
+        set(Arc::make_mut(&mut self.0));
         self
     }
 
+    // Applies a setter, or returns Unsupported if the config is shared
+    fn try_update(mut self, set: impl FnOnce(&mut Inner)) -> Result<Self> {
+        if Arc::strong_count(&self.0) != 1 {
+            return Err(Error::Unsupported("config already in use"));
+        }
+        set(Arc::make_mut(&mut self.0));
+        Ok(self)
+    }
+
     fn limit_cache_max_memory(&mut self) {
         if let Some(limit) = sys_limits::get_memory_limit() {
             if self.cache_capacity > limit {
@@ -165,15 +467,15 @@ impl Config {
     }
 
     fn try_lock(&self, file: File) -> Result<File> {
-        #[cfg(all(
-        any(target_os = "linux", target_os = "macos")
-        ))]
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
         {
             use fs2::FileExt;
-            let try_lock = if cfg!(any(feature = "for-internal-testing-only", feature = "light_testing")) {
-                file.lock_exclusive()
-            } else {
-                file.try_lock_exclusive()
+            // Any number of readers can share the file, but a writer needs it to itself
+            let try_lock = match (cfg!(any(feature = "for-internal-testing-only", feature = "light_testing")), self.read_only) {
+                (true, true) => FileExt::lock_shared(&file),
+                (true, false) => file.lock_exclusive(),
+                (false, true) => self.retry_lock(|| FileExt::try_lock_shared(&file)),
+                (false, false) => self.retry_lock(|| file.try_lock_exclusive()),
             };
 
             if try_lock.is_err() {
@@ -185,69 +487,661 @@ impl Config {
         }
         Ok(file)
     }
+
+    // Retries `try_lock` with exponential backoff until it succeeds or `lock_timeout` elapses
+    fn retry_lock(&self, try_lock: impl Fn() -> std::io::Result<()>) -> std::io::Result<()> {
+        let deadline = self.lock_timeout.map(|timeout| Instant::now() + timeout);
+        let mut backoff = Duration::from_millis(1);
+        loop {
+            let err = match try_lock() {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            let now = Instant::now();
+            match deadline {
+                Some(deadline) if now < deadline => {
+                    std::thread::sleep(backoff.min(deadline - now));
+                    backoff = (backoff * 2).min(Duration::from_millis(100));
+                }
+                _ => return Err(err),
+            }
+        }
+    }
+
     pub(crate) fn gen_temp_path() -> PathBuf {
         static SALT_COUNTER: AtomicUsize = AtomicUsize::new(0);
-        let seed = SALT_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst) as u128;
+        let seed = SALT_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
         let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() << 48;
-
+            .duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
 
-        let pid = u128::from(std::process::id());
-        let salt = (pid << 16) + now + seed;
-        if cfg!(target_os = "linux") {
-            // use shared memory for temporary linux files
-            format!("/dev/shm/pagecache.tmp.{}", salt).into()
-        } else {
-            std::env::temp_dir().join(format!("pagecache.tmp.{}", salt))
-        }
+        // The pid leads as a field of its own, for cleanup_orphaned_temps to parse
+        let pid = std::process::id();
+        temp_dir().join(format!("{}{}-{}-{}", TEMP_PREFIX, pid, now, seed))
     }
 
     fn validate(&self) -> Result<()> {
+        if !self.segment_size.is_power_of_two() {
+            return Err(Error::InvalidConfig(format!(
+                "segment_size should be a power of 2, such as {} instead of {}",
+                nearest_segment_size(self.segment_size),
+                self.segment_size
+            )));
+        }
         supported!(
-            self.segment_size.count_ones() == 1,
-            "segment_size should be a power of 2"
-        );
-        supported!(
-            self.segment_size >= 256,
+            self.segment_size >= MIN_SEGMENT_SIZE,
             "segment_size should be hundreds of kb at minimum, and we won't start if below 256"
         );
         supported!(
-            self.segment_size <= 1 << 24,
+            self.segment_size <= MAX_SEGMENT_SIZE,
             "segment_size should be <= 16mb"
         );
+        supported!(
+            !self.flags.direct_writes || cfg!(target_os = "linux"),
+            "direct_writes relies on O_DIRECT, which is only available on Linux"
+        );
+        supported!(
+            (0.1..=1.0).contains(&self.fill_percent),
+            "fill_percent should be between 0.1 and 1.0"
+        );
+        if self.initial_pages < MIN_INITIAL_PAGES {
+            return Err(Error::InvalidConfig(format!(
+                "initial_pages should be at least {}, for the meta pages, the freelist and the root",
+                MIN_INITIAL_PAGES
+            )));
+        }
+        if self.flush_every_ms == Some(0) {
+            return Err(Error::InvalidConfig(
+                "flush_every_ms should be at least 1, or None to sync on every commit".to_string(),
+            ));
+        }
         Ok(())
     }
 
     builder!(
         (
             cache_capacity,
+            try_cache_capacity,
             usize,
             "maximum size in bytes for the system page cache"
         ),
         (
             mode,
+            try_mode,
             Mode,
             "specify whether the system should run in \"small\" or \"fast\" mode"
         ),
-        (
-            temporary,
-            bool,
-            "deletes the database after drop. if no path is set, uses /dev/shm on linux"
-        ),
         (
             create_new,
+            try_create_new,
             bool,
             "attempts to exclusively open the database, failing if it already exists"
         ),
+        (
+            segment_size,
+            try_segment_size,
+            usize,
+            "granularity in bytes of file growth, which is persisted and can't change once the database is created"
+        ),
         (
             snapshot_after_ops,
+            try_snapshot_after_ops,
             u64,
             "take a fuzzy snapshot of pagecache metadata after this many ops"
+        ),
+        (
+            read_only,
+            try_read_only,
+            bool,
+            "opens the database for reading only, sharing it with other read-only opens \
+             and refusing writable transactions"
+        ),
+        (
+            fill_percent,
+            try_fill_percent,
+            f32,
+            "how full, from 0.1 to 1.0, leaf pages are filled before starting a new one. \
+             high values suit sequential inserts, lower ones leave room for random inserts"
+        ),
+        (
+            max_value_size,
+            try_max_value_size,
+            usize,
+            "largest value in bytes that can be put, so one runaway value can't fill the file \
+             with overflow pages. 512mb by default"
+        ),
+        (
+            max_tx_dirty_bytes,
+            try_max_tx_dirty_bytes,
+            usize,
+            "most bytes of keys and values a writable transaction can put before commit, \
+             so a bulk load done in one transaction can't run out of memory. Unlimited by default"
+        ),
+        (
+            initial_pages,
+            try_initial_pages,
+            usize,
+            "number of pages a new database file is allocated with, rounded up to a whole \
+             segment, so bulk loads don't have to grow the file over and over. 32 by default"
+        ),
+        (
+            scan_prefetch,
+            try_scan_prefetch,
+            usize,
+            "how many pages ahead cursors moving forward ask the OS to read in, \
+             so scans don't wait on the disk one page at a time. 0, the default, disables it"
+        ),
+        (
+            lock_timeout,
+            try_lock_timeout,
+            Option<Duration>,
+            "how long opening waits for another process to release the database lock, \
+             retrying with backoff, before giving up. None, the default, fails at once"
+        ),
+        (
+            mmap_advise,
+            try_mmap_advise,
+            MmapAdvice,
+            "the access pattern the memory map is advised to expect, random by default"
+        ),
+        (
+            application_id,
+            try_application_id,
+            u32,
+            "magic number written to new databases and required to match on open, \
+             so that files belonging to other applications are refused"
         )
     );
+
+    /// Checks the storage parameters persisted by the first open against this config,
+    /// persisting them instead if this is the first open.
     fn verify_config(&self) -> Result<()> {
-        todo!()
+        let old = match self.read_config()? {
+            Some(old) => old,
+            None if self.read_only => return Ok(()),
+            None => return self.write_config(),
+        };
+        if old.segment_size != self.segment_size {
+            return Err(Error::InvalidDB(format!(
+                "segment_size is {} but the database was created with {}",
+                self.segment_size, old.segment_size
+            )));
+        }
+        if old.use_compression != self.use_compression {
+            return Err(Error::InvalidDB(format!(
+                "use_compression is {} but the database was created with {}",
+                self.use_compression, old.use_compression
+            )));
+        }
+        Ok(())
+    }
+
+    fn storage_parameters(&self) -> StorageParameters {
+        StorageParameters {
+            segment_size: self.segment_size,
+            use_compression: self.use_compression,
+            version: self.version,
+        }
+    }
+
+    fn read_config(&self) -> Result<Option<StorageParameters>> {
+        match fs::read(self.config_path()) {
+            Ok(bytes) => StorageParameters::deserialize(&bytes).map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_config(&self) -> Result<()> {
+        let path = self.config_path();
+        let tmp_path = path.with_extension("in___motion");
+        fs::write(&tmp_path, self.storage_parameters().serialize())?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+pub(crate) fn temp_dir() -> PathBuf {
+    if cfg!(target_os = "linux") {
+        // use shared memory for temporary linux files
+        PathBuf::from("/dev/shm")
+    } else {
+        std::env::temp_dir()
+    }
+}
+
+/// Removes temporary databases left behind by processes that
+/// exited without dropping them, returning how many were removed.
+///
+/// The pid of the process that created a temporary database is the
+/// first field of its name, as `Config::gen_temp_path` generates it, so any
+/// `pagecache.tmp.<pid>-*` entry whose pid is no longer alive is orphaned.
+#[cfg(unix)]
+pub fn cleanup_orphaned_temps() -> Result<usize> {
+    cleanup_orphaned_temps_in(&temp_dir())
+}
+
+#[cfg(unix)]
+fn cleanup_orphaned_temps_in(dir: &Path) -> Result<usize> {
+    let mut cleaned = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let pid = match name
+            .to_str()
+            .and_then(|name| name.strip_prefix(TEMP_PREFIX))
+            .and_then(|salt| salt.split_once('-'))
+            .and_then(|(pid, _)| pid.parse::<u32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if pid == std::process::id() || pid_alive(pid) {
+            continue;
+        }
+
+        let path = entry.path();
+        let removed = if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match removed {
+            Ok(()) => cleaned += 1,
+            // another process may be cleaning up concurrently
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(cleaned)
+}
+
+#[cfg(unix)]
+fn pid_alive(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) => pid,
+        Err(_) => return false,
+    };
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
     }
+    // EPERM means the process exists but belongs to someone else
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_application_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).application_id(0xC0FFEE).open().unwrap();
+        assert_eq!(db.context.meta().unwrap().integrity_code, 0xC0FFEE);
+        drop(db);
+
+        assert!(Config::new().path(dir.path()).application_id(0xC0FFEE).open().is_ok());
+        assert!(matches!(
+            Config::new().path(dir.path()).open(),
+            Err(Error::InvalidDB(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify_config() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(Config::new().path(dir.path()).open().unwrap());
+        assert!(dir.path().join("conf").exists());
+        drop(Config::new().path(dir.path()).open().unwrap());
+
+        let err = Config::new()
+            .path(dir.path())
+            .segment_size(1 << 20)
+            .open()
+            .err();
+        assert!(matches!(err, Some(Error::InvalidDB(_))));
+
+        fs::write(dir.path().join("conf"), b"segment_size: 1\n").unwrap();
+        let err = Config::new().path(dir.path()).open().err();
+        assert!(matches!(err, Some(Error::InvalidDB(_))));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_direct_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let flags = DBFlags {
+            strict_mode: true,
+            mmap_populate: false,
+            direct_writes: true,
+        };
+        let db = Config::new().path(dir.path()).flags(flags).open().unwrap();
+        assert_eq!(db.context.io_align % 512, 0);
+
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap().put("key", "value").unwrap();
+        tx.commit().unwrap();
+        drop(db);
+
+        let db = Config::new().path(dir.path()).flags(flags).open().unwrap();
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert_eq!(bucket.get(b"key").unwrap().unwrap().as_slice(), b"value");
+    }
+
+    #[test]
+    fn test_fill_percent_range() {
+        let dir = tempfile::tempdir().unwrap();
+        for fill_percent in [0.0, 0.05, 1.5, f32::NAN] {
+            let err = Config::new().path(dir.path()).fill_percent(fill_percent).open().err();
+            assert_eq!(err, Some(Error::Unsupported("fill_percent should be between 0.1 and 1.0")));
+        }
+        assert!(Config::new().path(dir.path()).fill_percent(0.5).open().is_ok());
+    }
+
+    #[test]
+    fn test_segment_size_suggestion() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = Config::new().path(dir.path()).segment_size(500_000).open().err();
+        assert_eq!(
+            err,
+            Some(Error::InvalidConfig(
+                "segment_size should be a power of 2, such as 524288 instead of 500000".to_string()
+            ))
+        );
+        assert_eq!(nearest_segment_size(300_000), 262_144);
+        assert_eq!(nearest_segment_size(0), 256);
+        assert_eq!(nearest_segment_size(usize::MAX), 1 << 24);
+        assert!(Config::new().path(dir.path()).segment_size(524_288).open().is_ok());
+    }
+
+    #[test]
+    fn test_flush_every_ms_zero() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = Config::new().path(dir.path()).flush_every_ms(Some(0)).open().err();
+        assert_eq!(
+            err,
+            Some(Error::InvalidConfig(
+                "flush_every_ms should be at least 1, or None to sync on every commit".to_string()
+            ))
+        );
+        assert!(Config::new().path(dir.path()).flush_every_ms(Some(1)).open().is_ok());
+        assert!(Config::new().path(dir.path()).flush_every_ms(None).open().is_ok());
+    }
+
+    #[test]
+    fn test_mmap_advise() {
+        assert_eq!(Config::new().mmap_advise, MmapAdvice::Random);
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).mmap_advise(MmapAdvice::Sequential).open().unwrap();
+        assert_eq!(db.context.mmap_advise, MmapAdvice::Sequential);
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        // enough to grow the file, which maps it again with the same advice
+        for i in 0..20000 {
+            bucket.put(format!("key{:05}", i), "v".repeat(500)).unwrap();
+        }
+        tx.commit().unwrap();
+        db.warmup().unwrap();
+        let tx = db.tx(false).unwrap();
+        assert_eq!(tx.bucket(b"data").unwrap().keys().count(), 20000);
+    }
+
+    #[test]
+    fn test_try_setters() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::new().path(dir.path()).try_cache_capacity(1 << 20).unwrap();
+        let db = config.open().unwrap();
+        // the open database shares the config, so a change would never reach it
+        assert_eq!(
+            config.clone().try_cache_capacity(1 << 30).err(),
+            Some(Error::Unsupported("config already in use"))
+        );
+        assert_eq!(db.context.cache_capacity, 1 << 20);
+        assert_eq!(
+            config.clone().try_compression(None).err(),
+            Some(Error::Unsupported("config already in use"))
+        );
+        assert_eq!(
+            config.clone().try_comparator(b"data", Box::new(|a, b| b.cmp(a))).err(),
+            Some(Error::Unsupported("config already in use"))
+        );
+        assert!(config.clone().try_on_flush_error(Box::new(|_| {})).is_err());
+        assert!(config.clone().try_event_log(Arc::new(crate::event_log::NoopEventLog)).is_err());
+        assert!(config.try_metrics(Arc::new(crate::metrics::NoopMetrics)).is_err());
+        drop(db);
+    }
+
+    #[test]
+    fn test_persist_temporary() {
+        let dir = tempfile::tempdir().unwrap();
+        let kept = dir.path().join("kept");
+        let config = Config::new().temporary(true).persist_temporary(&kept).unwrap();
+        let db = config.open().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap().put("key", "value").unwrap();
+        tx.commit().unwrap();
+        let tmp = db.path();
+        assert!(!kept.exists());
+        // an open database can't be promoted
+        assert_eq!(
+            config.persist_temporary(dir.path().join("other")).err(),
+            Some(Error::Unsupported("config already in use"))
+        );
+
+        drop(db);
+        assert!(!tmp.exists());
+        let db = Config::new().path(&kept).open().unwrap();
+        let tx = db.tx(false).unwrap();
+        assert_eq!(tx.bucket(b"data").unwrap().get(b"key").unwrap().unwrap().as_slice(), b"value");
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Config::new().path(dir.path()).open().unwrap();
+            let tx = db.tx(true).unwrap();
+            tx.create_bucket(b"data").unwrap().put("key", "value").unwrap();
+            tx.commit().unwrap();
+        }
+
+        let first = Config::new().path(dir.path()).read_only(true).open().unwrap();
+        let second = Config::new().path(dir.path()).read_only(true).open().unwrap();
+        for db in [&first, &second] {
+            let tx = db.tx(false).unwrap();
+            let bucket = tx.bucket(b"data").unwrap();
+            assert_eq!(bucket.get(b"key").unwrap().unwrap().as_slice(), b"value");
+            assert_eq!(db.tx(true).err(), Some(Error::ReadOnlyTx));
+        }
+        assert!(matches!(
+            Config::new().path(dir.path()).open(),
+            Err(Error::Io(ErrorKind::Other, "could not acquire database file lock"))
+        ));
+
+        drop((first, second));
+        let empty = tempfile::tempdir().unwrap();
+        assert!(Config::new().path(empty.path()).read_only(true).open().is_err());
+    }
+
+    #[test]
+    fn test_on_flush_error() {
+        use std::sync::atomic::Ordering;
+
+        let dir = tempfile::tempdir().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let db = Config::new()
+            .path(dir.path())
+            .on_flush_error(Box::new(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }))
+            .open()
+            .unwrap();
 
+        db.context.flush_failed(&Error::Io(ErrorKind::Other, "disk full"));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(matches!(
+            db.tx(true),
+            Err(Error::Io(ErrorKind::Other, "background flush failed"))
+        ));
+        assert!(db.tx(false).is_ok());
+    }
+
+    #[test]
+    fn test_open_file_handle() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.path().join("handle.db"))
+            .unwrap();
+        let db = Config::new().open_file_handle(file).unwrap();
+        db.tx(true).unwrap();
+        // nothing is created next to the file
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_open_file_handle_keeps_caller_lock() {
+        use fs2::FileExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("handle.db");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).unwrap();
+        file.lock_exclusive().unwrap();
+        // a duplicate shares the lock, which the caller keeps holding through it
+        let held = file.try_clone().unwrap();
+        drop(Config::new().open_file_handle(file).unwrap());
+
+        let other = File::open(&path).unwrap();
+        assert!(other.try_lock_exclusive().is_err());
+        held.unlock().unwrap();
+        assert!(other.try_lock_exclusive().is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_open_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new()
+            .path(dir.path())
+            .snapshot_after_ops(1)
+            .open_in_memory()
+            .unwrap();
+        assert!(db.context.flusher.lock().is_none());
+        for i in 0..3 {
+            let tx = db.tx(true).unwrap();
+            let bucket = match i {
+                0 => tx.create_bucket(b"data").unwrap(),
+                _ => tx.bucket(b"data").unwrap(),
+            };
+            for j in 0..1000 {
+                bucket.put(format!("key{}-{:04}", i, j), "value").unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        db.flush().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert_eq!(bucket.get(b"key2-0999").unwrap().unwrap().as_slice(), b"value");
+        let mut count = 0;
+        bucket
+            .for_each(|_, _| {
+                count += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, 3000);
+        // not even the path it was given was touched
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_temporary_removed_on_drop() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let path = db.context.get_path();
+        assert!(path.starts_with(temp_dir()));
+        assert!(path.join("db").exists());
+        drop(db);
+        assert!(!path.exists());
+
+        // a path the user chose is kept
+        let dir = tempfile::tempdir().unwrap();
+        drop(Config::new().path(dir.path()).temporary(true).open().unwrap());
+        assert!(dir.path().join("db").exists());
+    }
+
+    #[test]
+    fn test_cleanup_orphaned_temps() {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        // a seed past 16 bits once carried into the pid
+        let orphan = dir.path().join(format!("{}{}-1-{}", TEMP_PREFIX, dead_pid, 1 << 20));
+        fs::create_dir_all(&orphan).unwrap();
+        File::create(orphan.join("db")).unwrap();
+
+        let live = dir.path().join(Config::gen_temp_path().file_name().unwrap());
+        fs::create_dir_all(&live).unwrap();
+        let live_seed = dir.path().join(format!("{}{}-1-{}", TEMP_PREFIX, std::process::id(), 1 << 20));
+        fs::create_dir_all(&live_seed).unwrap();
+        let unrelated = dir.path().join(format!("{}{}", TEMP_PREFIX, dead_pid));
+        fs::create_dir_all(&unrelated).unwrap();
+
+        assert_eq!(cleanup_orphaned_temps_in(dir.path()).unwrap(), 1);
+        assert!(!orphan.exists());
+        assert!(live.exists());
+        assert!(live_seed.exists());
+        assert!(unrelated.exists());
+    }
+
+    #[test]
+    fn test_interrupted_init() {
+        use std::os::unix::fs::FileExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::new().path(dir.path());
+        // fails after writing part of the first page, as a crash would
+        let err = config.create_db_file(|file, _| {
+            file.write_all_at(&[1; 100], 0)?;
+            Err(Error::Io(ErrorKind::Other, "interrupted"))
+        });
+        assert_eq!(err.err(), Some(Error::Io(ErrorKind::Other, "interrupted")));
+        assert!(!config.db_path().exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // the next open creates the database from scratch
+        let db = config.open().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap().put("key", "value").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(db.stats().unwrap().tx_id, 1);
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+
+        let start = Instant::now();
+        let config = Config::new().path(dir.path()).lock_timeout(Some(Duration::from_millis(50)));
+        assert_eq!(config.open().err(), Some(Error::Io(ErrorKind::Other, "could not acquire database file lock")));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // released while the second open is still waiting
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(db);
+        });
+        let config = Config::new().path(dir.path()).lock_timeout(Some(Duration::from_secs(10)));
+        assert!(config.open().is_ok());
+        release.join().unwrap();
+    }
+}
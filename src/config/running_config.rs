@@ -1,24 +1,63 @@
-use std::fs::File;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
 use std::io;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
 
+use fs2::FileExt;
+use log::warn;
 use memmap2::Mmap;
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Condvar, Mutex, RwLock};
 
 use crate::config::Config;
+use crate::config::snapshot::{parse_snapshot_name, SnapshotState, SNAPSHOT_PREFIX};
+use crate::defaults::MIN_ALLOC_SIZE;
+use crate::errors::{Error, Result};
 use crate::freelist::Freelist;
+use crate::meta::Meta;
+use crate::page::{PageID, Pages};
+use crate::utils::mmap;
 
 pub struct RunningConfig {
     pub(crate) inner: Config,
     pub(crate) file: Mutex<Arc<File>>,
+    // Whether memdb locked the file itself, rather than being handed a file the caller locked
+    pub(crate) owns_lock: bool,
     pub(crate) data: Mutex<Arc<Mmap>>,
     pub(crate) freelist: Mutex<Freelist>,
     pub(crate) open_ro_txs: Mutex<Vec<u64>>,
 
     pub(crate) mmap_lock: RwLock<()>,
     pub(crate) pagesize: u64,
+    // Alignment required of buffers written to the file
+    pub(crate) io_align: usize,
+    // Set when a background flush fails, and cleared by a successful manual flush
+    pub(crate) degraded: AtomicBool,
+    // A second handle on the db file, so syncing never waits on the writer lock
+    pub(crate) sync_file: File,
+    // Bytes written by commits that haven't been synced yet
+    pub(crate) unflushed: AtomicU64,
+    // Commits waiting for their meta to be written and synced, when commits sync
+    pub(crate) commits: Mutex<CommitGroup>,
+    // Notified whenever a group sync finishes
+    pub(crate) commit_synced: Condvar,
+}
+
+/// Commits whose pages are written but whose meta isn't durable yet.
+///
+/// Rather than each syncing by itself, the first of them to find no sync in
+/// flight syncs on behalf of all of them, and the others wait for it.
+#[derive(Default)]
+pub(crate) struct CommitGroup {
+    // Metas of the newest commits not yet durable, oldest first, at most two
+    pub(crate) pending: Vec<Meta>,
+    // Id of the newest transaction whose meta is durable
+    pub(crate) durable: u64,
+    // Set while a group sync is in flight
+    pub(crate) syncing: bool,
 }
 
 impl Deref for RunningConfig {
@@ -31,24 +70,466 @@ impl Deref for RunningConfig {
 
 impl Drop for RunningConfig {
     fn drop(&mut self) {
-        use fs2::FileExt;
-        let file = self.file.try_lock();
-
-        match file {
-            None => {}
-            Some(file) => {
-                if Arc::strong_count(&file) == 1 {
-                    let _ = file.unlock();
+        // A lock the caller took on a file handle it passed in is theirs to release
+        let file = self.file.try_lock().filter(|_| self.owns_lock);
+
+        if let Some(file) = file {
+            if Arc::strong_count(&file) == 1 {
+                let _ = file.unlock();
+            }
+        }
+
+        // A path chosen by the user is never deleted, even for a temporary database
+        if let Some(path) = self.tmp_path() {
+            if let Some(to) = &self.persist_path {
+                if to.exists() {
+                    warn!("not persisting temporary database {:?}: {:?} already exists", path, to);
+                    return;
+                }
+                match self.sync_file.sync_all().and_then(|()| move_dir(path, to)) {
+                    Ok(()) => return,
+                    Err(e) => warn!("failed to persist temporary database {:?} to {:?}: {}", path, to, e),
                 }
             }
+            if let Err(e) = fs::remove_dir_all(path) {
+                warn!("failed to remove temporary database {:?}: {}", path, e);
+            }
         }
     }
 }
 
+// Renames `from` to `to`, or copies it over if they're on different filesystems,
+// as the directories of temporary databases are kept in shared memory.
+fn move_dir(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_dir(from, to)?;
+    fs::remove_dir_all(from)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+            File::open(&target)?.sync_all()?;
+        }
+    }
+    File::open(to)?.sync_all()
+}
+
 
 impl RunningConfig {
+    /// Records that a background flush failed, refusing writes until a manual flush succeeds.
+    pub(crate) fn flush_failed(&self, err: &Error) {
+        self.degraded.store(true, Ordering::SeqCst);
+        if let Some(handler) = &self.on_flush_error {
+            (handler.0)(err);
+        }
+    }
+
+    pub(crate) fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::SeqCst)
+    }
+
+    /// Syncs the db file, returning the number of bytes written since the last sync.
+    pub(crate) fn flush(&self) -> Result<usize> {
+        let bytes = self.unflushed.swap(0, Ordering::SeqCst);
+        // Every meta in the file before the sync is durable after it
+        let tx_id = self.meta()?.tx_id;
+        self.sync_data()?;
+        let mut commits = self.commits.lock();
+        commits.durable = commits.durable.max(tx_id);
+        drop(commits);
+        // The pages those commits freed are no longer needed by an older meta
+        let up_to_tx = self.reusable_before(tx_id + 1);
+        self.freelist.lock().release(up_to_tx);
+        Ok(bytes as usize)
+    }
+
+    // Syncs the db file's data, reporting how long it took
+    fn sync_data(&self) -> io::Result<()> {
+        let start = Instant::now();
+        self.sync_file.sync_data()?;
+        self.metrics.fsync_duration(start.elapsed());
+        Ok(())
+    }
+
+    /// Maps `file` again and swaps the new mapping in for the current one.
+    ///
+    /// Callers must hold the `mmap_lock` write guard. Transactions that still
+    /// hold the old mapping keep it alive until they finish.
+    pub(crate) fn remap(&self, file: &File) -> Result<()> {
+        let data = mmap(file, self.flags.mmap_populate, self.mmap_advise)?;
+        *self.data.lock() = Arc::new(data);
+        Ok(())
+    }
+
+    /// Makes sure the file and the mmap hold at least `min_pages` pages, growing
+    /// the file by at least [`MIN_ALLOC_SIZE`] at a time, rounded up to a whole segment.
+    ///
+    /// Read-only transactions keep the mapping they started with alive, so the new
+    /// one is swapped in without waiting for them. If the file can't be mapped again,
+    /// [`Error::Remap`] is returned and the current mapping is kept.
+    pub(crate) fn grow(&self, file: &File, min_pages: u64) -> Result<()> {
+        let min_size = min_pages * self.pagesize;
+        if (self.data.lock().len() as u64) >= min_size {
+            return Ok(());
+        }
+        let len = file.metadata()?.len();
+        if len < min_size {
+            let segment_size = self.segment_size as u64;
+            let size = self.normalize(min_size.max(len + MIN_ALLOC_SIZE) + segment_size - 1);
+            file.allocate(size)?;
+        }
+
+        let _mmap_lock = self.mmap_lock.write();
+        self.remap(file).map_err(|e| Error::Remap(e.to_string()))
+    }
+
+    /// Returns the tx_id of the oldest open read-only transaction, or `default` if there are none.
+    pub(crate) fn oldest_reader(&self, default: u64) -> u64 {
+        self.open_ro_txs.lock().iter().copied().min().unwrap_or(default)
+    }
+
+    /// Returns the id of the oldest transaction whose freed pages can't be reused
+    /// yet, or `default` if there is none, to be passed to [`Freelist::release`].
+    ///
+    /// Pages freed by a commit are still needed by open readers older than it,
+    /// and by the previous meta until the commit is durable: synced by the commit
+    /// itself, or with a background flusher, by the next flush. An in-memory
+    /// database has no previous meta to survive a crash.
+    pub(crate) fn reusable_before(&self, default: u64) -> u64 {
+        let oldest = self.oldest_reader(default);
+        if self.in_memory {
+            oldest
+        } else {
+            oldest.min(self.commits.lock().durable + 1)
+        }
+    }
+
+    /// Whether commits must be durable before they return, which is the case
+    /// when there is no background flusher to sync them later.
+    pub(crate) fn syncs_on_commit(&self) -> bool {
+        !self.in_memory && (self.flush_every_ms.is_none() || self.flags.direct_writes)
+    }
+
+    /// Returns the meta of the last commit, which may still be waiting for a group sync to write it.
+    pub(crate) fn latest_meta(&self) -> Result<Meta> {
+        match self.commits.lock().pending.last() {
+            Some(meta) => Ok(meta.clone()),
+            None => self.meta(),
+        }
+    }
+
+    /// Queues the meta of a commit whose pages have been written, to be made
+    /// durable by [`RunningConfig::sync_commit`].
+    pub(crate) fn queue_commit(&self, meta: Meta) {
+        let mut commits = self.commits.lock();
+        commits.pending.push(meta);
+        if commits.pending.len() > 2 {
+            commits.pending.remove(0);
+        }
+    }
+
+    /// Waits until the commit `tx_id` is durable.
+    ///
+    /// If a group sync is in flight, it waits for it, since it may cover `tx_id`.
+    /// Otherwise it syncs the pages of every queued commit, then writes and syncs
+    /// the newest meta, making all of them durable with two syncs.
+    pub(crate) fn sync_commit(&self, tx_id: u64) -> Result<()> {
+        loop {
+            let mut commits = self.commits.lock();
+            while commits.syncing && commits.durable < tx_id {
+                self.commit_synced.wait(&mut commits);
+            }
+            if commits.durable >= tx_id {
+                return Ok(());
+            }
+            commits.syncing = true;
+            let pending = commits.pending.clone();
+            drop(commits);
+
+            let result = self.sync_group(&pending);
+            let mut commits = self.commits.lock();
+            commits.syncing = false;
+            if let Ok(durable) = result {
+                commits.durable = commits.durable.max(durable);
+                commits.pending.retain(|meta| meta.tx_id > durable);
+            }
+            self.commit_synced.notify_all();
+            drop(commits);
+
+            let durable = result?;
+            let up_to_tx = self.reusable_before(durable + 1);
+            self.freelist.lock().release(up_to_tx);
+        }
+    }
+
+    // Makes the newest meta in `pending` that can be written durable, returning its tx_id
+    fn sync_group(&self, pending: &[Meta]) -> Result<u64> {
+        // Metas alternate between the two meta pages by the parity of their tx_id, and the
+        // current one must not be overwritten, so the newest meta may have to wait a round
+        let current = self.meta()?.tx_id;
+        let mut meta = match pending.iter().rev().find(|meta| meta.tx_id % 2 != current % 2) {
+            Some(meta) => meta.clone(),
+            None => return Err(Error::InvalidDB(format!("no queued commit follows tx {}", current))),
+        };
+        // Data pages have to reach the disk before the meta that points at them
+        self.sync_data()?;
+        self.event_log.on_sync();
+        meta.write(&self.sync_file, self.pagesize, self.io_align)?;
+        self.sync_data()?;
+        self.event_log.on_sync();
+        Ok(meta.tx_id)
+    }
+
+    /// Returns the newest valid meta from the two meta pages of the current mmap.
+    pub(crate) fn meta(&self) -> Result<Meta> {
+        Meta::newest(&Pages::new(self.data.lock().clone(), self.pagesize), self.application_id)
+    }
+
+    /// Returns the snapshot files in the database directory, oldest first.
+    ///
+    /// Files that look like snapshots but whose names can't be parsed are skipped.
     pub fn get_snapshot_files(&self) -> io::Result<Vec<PathBuf>> {
-        let config_path = self.get_path().join("snap.");
-        todo!()
+        let mut snapshots = Vec::new();
+        for entry in fs::read_dir(self.get_path())? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) if name.starts_with(SNAPSHOT_PREFIX) => name,
+                _ => continue,
+            };
+            match parse_snapshot_name(name) {
+                Some((tx_id, delta)) => snapshots.push((tx_id, delta, entry.path())),
+                None => warn!("skipping snapshot file with a malformed name: {}", name),
+            }
+        }
+        snapshots.sort();
+        Ok(snapshots.into_iter().map(|(_, _, path)| path).collect())
+    }
+
+    /// Rebuilds the free pages as of `meta` from the snapshot files, applying each
+    /// delta to its base. Returns `None` unless the newest snapshot was taken at
+    /// the very commit `meta` belongs to, as the free pages of any other commit
+    /// can't be trusted, or if a snapshot file fails its checks.
+    pub(crate) fn recover_free_pages(&self, meta: &Meta) -> Option<BTreeSet<PageID>> {
+        let files = self.get_snapshot_files().ok()?;
+        match SnapshotState::recover(&files) {
+            Ok(Some(state)) if state.meta.hash == meta.hash => Some(state.free_pages),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("failed to recover the freelist from snapshots: {}", e);
+                None
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+
+    use super::*;
+
+    #[test]
+    fn test_get_snapshot_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        for name in ["snap.10", "snap.2", "snap.x", "snap.5.delta", "snapshot"] {
+            File::create(dir.path().join(name)).unwrap();
+        }
+        let files = db.context.get_snapshot_files().unwrap();
+        let names: Vec<_> = files.iter().map(|p| p.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, vec!["snap.2", "snap.5.delta", "snap.10"]);
+    }
+
+    #[test]
+    fn test_grow() {
+        let dir = tempfile::tempdir().unwrap();
+        // segments small enough that the initial file isn't already past 40 pages
+        let db = Config::new().path(dir.path()).segment_size(1 << 16).open().unwrap();
+        let file = db.context.file.lock().clone();
+        let pagesize = db.context.pagesize;
+
+        // a short file is extended by at least MIN_ALLOC_SIZE, in whole segments
+        file.set_len(4 * pagesize).unwrap();
+        db.context.grow(&file, 40).unwrap();
+        let len = file.metadata().unwrap().len();
+        assert!(len >= 4 * pagesize + MIN_ALLOC_SIZE);
+        assert_eq!(len % db.context.segment_size as u64, 0);
+        assert_eq!(db.context.data.lock().len() as u64, len);
+        // nothing to do when the mapping is already large enough
+        db.context.grow(&file, 40).unwrap();
+
+        // a file that can't be mapped fails to remap, keeping the old mapping
+        let write_only = fs::OpenOptions::new().write(true).open(dir.path().join("db")).unwrap();
+        let err = db.context.grow(&write_only, len / pagesize + 1).unwrap_err();
+        assert!(matches!(err, Error::Remap(_)));
+        assert_eq!(db.context.data.lock().len() as u64, len);
+        assert!(db.tx(false).is_ok());
+    }
+
+    #[test]
+    fn test_grow_with_open_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap().put("key", "old").unwrap();
+        tx.commit().unwrap();
+        let len = db.context.file.lock().metadata().unwrap().len();
+
+        // a reader on the same thread doesn't keep a commit from growing the file
+        let reader = db.tx(false).unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        bucket.put("key", "new").unwrap();
+        for i in 0..(len / db.pagesize()) {
+            bucket.put(format!("key{:05}", i), vec![0u8; db.pagesize() as usize]).unwrap();
+        }
+        tx.commit().unwrap();
+        assert!(db.context.file.lock().metadata().unwrap().len() > len);
+
+        // and it still reads from the mapping it started with
+        let bucket = reader.bucket(b"data").unwrap();
+        assert_eq!(bucket.get(b"key").unwrap().unwrap().as_slice(), b"old");
+        assert!(bucket.get(b"key00000").unwrap().is_none());
+        drop(reader);
+        let tx = db.tx(false).unwrap();
+        assert_eq!(tx.bucket(b"data").unwrap().get(b"key").unwrap().unwrap().as_slice(), b"new");
+    }
+
+    #[test]
+    fn test_initial_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).initial_pages(10_000).open().unwrap();
+        let pagesize = db.context.pagesize;
+        let segment_size = db.context.segment_size as u64;
+        let len = db.context.file.lock().metadata().unwrap().len();
+        assert_eq!(len, (10_000 * pagesize).div_ceil(segment_size) * segment_size);
+
+        // a bulk load that fits doesn't grow the file
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..5000 {
+            bucket.put(format!("key{:05}", i), vec![0u8; pagesize as usize]).unwrap();
+        }
+        tx.commit().unwrap();
+        assert_eq!(db.context.file.lock().metadata().unwrap().len(), len);
+        drop(db);
+
+        // the pages are only allocated when the file is created
+        let db = Config::new().path(dir.path()).initial_pages(100_000).open().unwrap();
+        assert_eq!(db.context.file.lock().metadata().unwrap().len(), len);
+
+        let dir = tempfile::tempdir().unwrap();
+        let err = Config::new().path(dir.path()).initial_pages(3).open().err().unwrap();
+        assert!(matches!(err, Error::InvalidConfig(_)));
+    }
+
+    #[test]
+    fn test_commit_grows_by_segments() {
+        use crate::defaults::DEFAULT_NUM_PAGES;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let file = db.context.file.lock().clone();
+        let initial = file.metadata().unwrap().len();
+        assert_eq!(initial, db.context.initial_num_pages(db.context.pagesize) as u64 * db.context.pagesize);
+        assert_eq!(initial % db.context.segment_size as u64, 0);
+
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..(2 * DEFAULT_NUM_PAGES) {
+            bucket.put(format!("key{}", i), vec![0u8; db.context.pagesize as usize]).unwrap();
+        }
+        tx.commit().unwrap();
+
+        // one grow of at least MIN_ALLOC_SIZE covers many more commits like it
+        let len = file.metadata().unwrap().len();
+        assert!(len >= initial + MIN_ALLOC_SIZE);
+        assert_eq!(len % db.context.segment_size as u64, 0);
+        let tx = db.tx(true).unwrap();
+        tx.bucket(b"data").unwrap().put("more", vec![0u8; db.context.pagesize as usize * 4]).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(file.metadata().unwrap().len(), len);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_commits_share_syncs() {
+        use crate::event_log::{Event, VecEventLog};
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(VecEventLog::new());
+        let db = Config::new()
+            .path(dir.path())
+            .flush_every_ms(None)
+            .event_log(log.clone())
+            .open()
+            .unwrap();
+        let syncs = || log.events().iter().filter(|e| **e == Event::Sync).count();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(syncs(), 2);
+        let base = db.stats().unwrap().tx_id;
+
+        // hold the group sync back until every commit has queued its meta
+        let n = 8;
+        db.context.commits.lock().syncing = true;
+        std::thread::scope(|s| {
+            let db = &db;
+            for i in 0..n {
+                s.spawn(move || {
+                    let tx = db.tx(true).unwrap();
+                    tx.bucket(b"data").unwrap().put(format!("key{}", i), "value").unwrap();
+                    tx.commit().unwrap();
+                });
+            }
+            while db.context.commits.lock().pending.last().map_or(0, |meta| meta.tx_id) < base + n {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            db.context.commits.lock().syncing = false;
+            db.context.commit_synced.notify_all();
+        });
+        // the newest meta lands on the current meta page, so it takes a second round
+        assert_eq!(syncs() - 2, 4);
+        assert_eq!(db.stats().unwrap().tx_id, base + n);
+
+        drop(db);
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(false).unwrap();
+        assert_eq!(tx.bucket(b"data").unwrap().keys().count(), n as usize);
+    }
+
+    #[test]
+    fn test_flusher_defers_page_reuse() {
+        let dir = tempfile::tempdir().unwrap();
+        // long enough that the flusher never runs during the test
+        let db = Config::new().path(dir.path()).flush_every_ms(Some(60_000)).open().unwrap();
+        let durable = db.context.commits.lock().durable;
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap();
+        tx.commit().unwrap();
+        for value in [vec![1u8; 64 * 1024], vec![2u8; 64 * 1024]] {
+            let tx = db.tx(true).unwrap();
+            tx.bucket(b"data").unwrap().put(b"key".to_vec(), value).unwrap();
+            tx.commit().unwrap();
+        }
+        // the overwrite freed the first value's pages, but neither commit is durable yet
+        assert_eq!(db.context.reusable_before(u64::MAX), durable + 1);
+        let stats = db.stats().unwrap();
+        assert!(stats.pending_pages > 0);
+
+        db.flush().unwrap();
+        assert_eq!(db.context.commits.lock().durable, stats.tx_id);
+        let flushed = db.stats().unwrap();
+        assert_eq!(flushed.pending_pages, 0);
+        assert!(flushed.free_pages >= stats.pending_pages);
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::errors::{Error, Result};
+use crate::meta::Meta;
+use crate::page::PageID;
+
+pub(crate) const SNAPSHOT_PREFIX: &str = "snap.";
+pub(crate) const DELTA_SUFFIX: &str = ".delta";
+
+const KIND_BASE: u8 = 0;
+const KIND_DELTA: u8 = 1;
+
+/// The metadata captured by a fuzzy snapshot: the meta that was live
+/// when it was taken, and every page that was free at that point.
+#[derive(Debug, Clone)]
+pub(crate) struct SnapshotState {
+    pub(crate) meta: Meta,
+    pub(crate) free_pages: BTreeSet<PageID>,
+}
+
+impl SnapshotState {
+    /// Writes the full state to `snap.<tx_id>` in `dir`.
+    pub(crate) fn write_base(&self, dir: &Path) -> Result<PathBuf> {
+        let mut buf = BytesMut::new();
+        buf.put_u8(KIND_BASE);
+        buf.put_slice(&self.meta.encode());
+        put_ids(&mut buf, self.free_pages.iter());
+        write_checksummed(&snapshot_path(dir, self.meta.tx_id, false), buf)
+    }
+
+    /// Writes only the pages allocated and freed since `prev` to
+    /// `snap.<tx_id>.delta` in `dir`.
+    pub(crate) fn write_delta(&self, prev: &SnapshotState, dir: &Path) -> Result<PathBuf> {
+        let mut buf = BytesMut::new();
+        buf.put_u8(KIND_DELTA);
+        buf.put_u64(prev.meta.tx_id);
+        buf.put_slice(&self.meta.encode());
+        put_ids(&mut buf, prev.free_pages.difference(&self.free_pages));
+        put_ids(&mut buf, self.free_pages.difference(&prev.free_pages));
+        write_checksummed(&snapshot_path(dir, self.meta.tx_id, true), buf)
+    }
+
+    /// Rebuilds the state from snapshot files in apply order: a base
+    /// snapshot followed by the deltas taken after it.
+    ///
+    /// Every file's checksum is validated, and each delta must follow
+    /// directly on from the state before it.
+    pub(crate) fn recover(files: &[PathBuf]) -> Result<Option<SnapshotState>> {
+        let mut state: Option<SnapshotState> = None;
+        for path in files {
+            let data = read_checksummed(path)?;
+            let mut buf = &data[..];
+            let kind = buf.get_u8();
+            if kind == KIND_BASE {
+                let meta = decode_meta(&mut buf, path)?;
+                let free_pages = get_ids(&mut buf, path)?.into_iter().collect();
+                state = Some(SnapshotState { meta, free_pages });
+                continue;
+            }
+            if kind != KIND_DELTA || buf.remaining() < 8 {
+                return Err(corrupt(path));
+            }
+            let prev_tx_id = buf.get_u64();
+            let meta = decode_meta(&mut buf, path)?;
+            let allocated = get_ids(&mut buf, path)?;
+            let freed = get_ids(&mut buf, path)?;
+            let current = match state.as_mut() {
+                Some(current) if current.meta.tx_id == prev_tx_id => current,
+                _ => {
+                    return Err(Error::InvalidDB(format!(
+                        "snapshot delta {} does not follow the previous snapshot",
+                        path.display()
+                    )))
+                }
+            };
+            for id in allocated {
+                current.free_pages.remove(&id);
+            }
+            current.free_pages.extend(freed);
+            current.meta = meta;
+        }
+        Ok(state)
+    }
+}
+
+/// Parses a snapshot file name into its tx_id and whether it is a delta.
+pub(crate) fn parse_snapshot_name(name: &str) -> Option<(u64, bool)> {
+    let rest = name.strip_prefix(SNAPSHOT_PREFIX)?;
+    match rest.strip_suffix(DELTA_SUFFIX) {
+        Some(id) => id.parse().ok().map(|id| (id, true)),
+        None => rest.parse().ok().map(|id| (id, false)),
+    }
+}
+
+pub(crate) fn snapshot_path(dir: &Path, tx_id: u64, delta: bool) -> PathBuf {
+    if delta {
+        dir.join(format!("{}{}{}", SNAPSHOT_PREFIX, tx_id, DELTA_SUFFIX))
+    } else {
+        dir.join(format!("{}{}", SNAPSHOT_PREFIX, tx_id))
+    }
+}
+
+fn put_ids<'a>(buf: &mut BytesMut, ids: impl Iterator<Item=&'a PageID>) {
+    let ids: Vec<&PageID> = ids.collect();
+    buf.put_u64(ids.len() as u64);
+    ids.into_iter().for_each(|id| buf.put_u64(*id));
+}
+
+fn get_ids(buf: &mut &[u8], path: &Path) -> Result<Vec<PageID>> {
+    if buf.remaining() < 8 {
+        return Err(corrupt(path));
+    }
+    let count = buf.get_u64() as usize;
+    if buf.remaining() / 8 < count {
+        return Err(corrupt(path));
+    }
+    Ok((0..count).map(|_| buf.get_u64()).collect())
+}
+
+fn decode_meta(buf: &mut &[u8], path: &Path) -> Result<Meta> {
+    match Meta::decode(buf) {
+        Some(meta) if meta.valid() => Ok(meta),
+        _ => Err(corrupt(path)),
+    }
+}
+
+fn write_checksummed(path: &Path, mut buf: BytesMut) -> Result<PathBuf> {
+    let crc = crc32fast::hash(&buf);
+    buf.put_u32(crc);
+    fs::write(path, &buf)?;
+    Ok(path.to_path_buf())
+}
+
+fn read_checksummed(path: &Path) -> Result<Vec<u8>> {
+    let mut data = fs::read(path)?;
+    if data.len() < 5 {
+        return Err(corrupt(path));
+    }
+    let crc_start = data.len() - 4;
+    let crc = (&data[crc_start..]).get_u32();
+    data.truncate(crc_start);
+    if crc32fast::hash(&data) != crc {
+        return Err(Error::InvalidDB(format!(
+            "snapshot {} failed checksum validation",
+            path.display()
+        )));
+    }
+    Ok(data)
+}
+
+fn corrupt(path: &Path) -> Error {
+    Error::InvalidDB(format!("snapshot {} is malformed", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bucket::BucketMeta;
+
+    use super::*;
+
+    fn state(tx_id: u64, free_pages: &[PageID]) -> SnapshotState {
+        let mut meta = Meta {
+            meta_page: 0,
+            integrity_code: 0,
+            version: 0,
+            pagesize: 4096,
+            root: BucketMeta::default(),
+            num_pages: 32,
+            freelist_page: 2,
+            tx_id,
+            user_version: 0,
+            hash: [0; 32],
+        };
+        meta.hash = meta.hash_self();
+        SnapshotState {
+            meta,
+            free_pages: free_pages.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn test_delta_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = state(3, &[4, 5, 6]);
+        let first = state(7, &[5, 6, 9]);
+        let second = state(12, &[6, 9, 10, 11]);
+        let files = vec![
+            base.write_base(dir.path()).unwrap(),
+            first.write_delta(&base, dir.path()).unwrap(),
+            second.write_delta(&first, dir.path()).unwrap(),
+        ];
+        assert_eq!(files[2], dir.path().join("snap.12.delta"));
+
+        let recovered = SnapshotState::recover(&files).unwrap().unwrap();
+        assert_eq!(recovered.meta.tx_id, 12);
+        assert_eq!(recovered.free_pages, second.free_pages);
+
+        // a delta applied out of order is rejected
+        let out_of_order = vec![files[0].clone(), files[2].clone()];
+        assert!(SnapshotState::recover(&out_of_order).is_err());
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = state(3, &[4, 5]).write_base(dir.path()).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        data[10] ^= 0xFF;
+        fs::write(&path, data).unwrap();
+        assert!(SnapshotState::recover(&[path]).is_err());
+    }
+
+    #[test]
+    fn test_parse_snapshot_name() {
+        assert_eq!(parse_snapshot_name("snap.42"), Some((42, false)));
+        assert_eq!(parse_snapshot_name("snap.42.delta"), Some((42, true)));
+        assert_eq!(parse_snapshot_name("snap.x"), None);
+        assert_eq!(parse_snapshot_name("conf"), None);
+    }
+}
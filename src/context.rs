@@ -1,13 +1,37 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+
 use crate::config::running_config::RunningConfig;
+use crate::config::snapshot::SnapshotState;
+use crate::errors::Result;
+use crate::flusher::Flusher;
+use crate::freelist::Freelist;
+use crate::meta::Meta;
+use crate::pagecache::PageCache;
+
+// Deltas written after a base snapshot before the next full one, which bounds
+// how many recovery has to apply
+const MAX_DELTAS: usize = 16;
 
 #[derive(Clone)]
 pub struct Context {
-    config: RunningConfig,
+    config: Arc<RunningConfig>,
+    // Write operations since the last metadata snapshot
+    pub(crate) ops: Arc<AtomicU64>,
+    // The last snapshot written, which the next delta is taken against, and
+    // how many deltas have followed its base. None until a base is written.
+    last_snapshot: Arc<Mutex<Option<(SnapshotState, usize)>>>,
 
-    // #[cfg(not(miri))]
-    // pub(crate) flusher: Arc<Mutex<Option<flusher::Flusher>>>,
-    // #[doc(hidden)]
-    // pub pagecache: PageCache,
+    // Joined, after a final sync, once the last handle to the database is dropped.
+    // Only tests look at it, everything else keeps it for its Drop.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) flusher: Arc<Mutex<Option<Flusher>>>,
+    // None when cache_capacity is 0, so pages are read straight from the mmap
+    pub(crate) pagecache: Option<Arc<PageCache>>,
 }
 
 impl std::ops::Deref for Context {
@@ -18,4 +42,68 @@ impl std::ops::Deref for Context {
     }
 }
 
-impl Context {}
\ No newline at end of file
+impl Context {
+    pub(crate) fn new(config: RunningConfig) -> Context {
+        let config = Arc::new(config);
+        // Read-only and in-memory databases never have anything to flush
+        let flusher = config
+            .flush_every_ms
+            .filter(|_| !config.read_only && !config.in_memory)
+            .map(|ms| Flusher::new("flusher".to_string(), config.clone(), ms));
+        let pagecache = match config.cache_capacity {
+            0 => None,
+            capacity => Some(Arc::new(PageCache::new(capacity as u64, config.pagesize))),
+        };
+        Context {
+            config,
+            ops: Arc::new(AtomicU64::new(0)),
+            last_snapshot: Arc::new(Mutex::new(None)),
+            pagecache,
+            flusher: Arc::new(Mutex::new(flusher)),
+        }
+    }
+
+    /// Counts a committed write, taking a snapshot of the meta and freelist
+    /// once `snapshot_after_ops` writes have been counted since the last one.
+    ///
+    /// The first snapshot after opening is a full base, and the following ones
+    /// are deltas against the one before, up to `MAX_DELTAS` of them before the
+    /// next base. Writing a base deletes every older snapshot file, since recovery
+    /// starts from the newest base. Returns the path of the snapshot if one was taken.
+    pub(crate) fn record_op(&self, meta: &Meta, freelist: &Freelist) -> Result<Option<PathBuf>> {
+        if self.ops.fetch_add(1, Ordering::SeqCst) + 1 < self.snapshot_after_ops {
+            return Ok(None);
+        }
+        self.ops.store(0, Ordering::SeqCst);
+        // Databases opened from a file handle or in memory have no directory to put snapshots in
+        let dir = self.get_path();
+        if self.in_memory || !dir.is_dir() {
+            return Ok(None);
+        }
+
+        // The meta is stored as it's written to its meta page, so recovery can match them up
+        let mut meta = meta.clone();
+        meta.meta_page = (meta.tx_id % 2) as u32;
+        meta.hash = meta.hash_self();
+        let state = SnapshotState {
+            meta,
+            free_pages: freelist.free_on_open(),
+        };
+        // Taken until the snapshot is written, so a failed write is followed by a new base
+        // rather than by a delta against a file that may not be there
+        let mut last = self.last_snapshot.lock();
+        let (path, deltas) = match last.take() {
+            Some((prev, deltas)) if deltas < MAX_DELTAS => (state.write_delta(&prev, &dir)?, deltas + 1),
+            _ => (state.write_base(&dir)?, 0),
+        };
+        if deltas == 0 {
+            for old in self.get_snapshot_files()? {
+                if old != path {
+                    fs::remove_file(old)?;
+                }
+            }
+        }
+        *last = Some((state, deltas));
+        Ok(Some(path))
+    }
+}
@@ -0,0 +1,571 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::rc::Rc;
+
+use crate::bucket::InnerBucket;
+use crate::bytes::Bytes;
+use crate::errors::Result;
+use crate::node::{branch_index, Branch, Leaf, Node, NodeData, PageNodeID};
+
+#[derive(Clone)]
+struct Elem<'tx> {
+    node: Rc<RefCell<Node<'tx>>>,
+    index: usize,
+}
+
+/// Walks the key / value pairs of a [`Bucket`](crate::Bucket) in key order.
+///
+/// Nested buckets are skipped. A new cursor starts before the first pair, so
+/// [`Cursor::next`] returns the first pair and [`Cursor::prev`] the last one.
+/// Once the cursor runs off either end it stays on the pair it last returned.
+pub struct Cursor<'b, 'tx: 'b> {
+    bucket: Rc<RefCell<InnerBucket<'tx>>>,
+    // The path from the root to the current leaf element
+    stack: Vec<Elem<'tx>>,
+    _phantom: PhantomData<&'b ()>,
+}
+
+impl<'b, 'tx> Cursor<'b, 'tx> {
+    pub(crate) fn new(bucket: Rc<RefCell<InnerBucket<'tx>>>) -> Cursor<'b, 'tx> {
+        Cursor {
+            bucket,
+            stack: Vec::new(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Moves to the first key / value pair in the bucket.
+    pub fn first(&mut self) -> Result<Option<(Bytes<'b>, Bytes<'b>)>> {
+        self.stack = Self::descend(&self.bucket, vec![self.root()?], false, false)?;
+        self.settle(true)
+    }
+
+    /// Moves to the last key / value pair in the bucket.
+    pub fn last(&mut self) -> Result<Option<(Bytes<'b>, Bytes<'b>)>> {
+        let mut root = self.root()?;
+        root.index = root.node.borrow().data.len().saturating_sub(1);
+        self.stack = Self::descend(&self.bucket, vec![root], true, false)?;
+        self.settle(false)
+    }
+
+    /// Moves to the next key / value pair.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<(Bytes<'b>, Bytes<'b>)>> {
+        if self.stack.is_empty() {
+            return self.first();
+        }
+        self.advance(true)
+    }
+
+    /// Moves to the previous key / value pair.
+    pub fn prev(&mut self) -> Result<Option<(Bytes<'b>, Bytes<'b>)>> {
+        if self.stack.is_empty() {
+            return self.last();
+        }
+        self.advance(false)
+    }
+
+    /// Moves to the first key / value pair with a key greater than or equal to `key`.
+    pub fn seek(&mut self, key: &[u8]) -> Result<Option<(Bytes<'b>, Bytes<'b>)>> {
+        self.position(key)?;
+        self.settle(true)
+    }
+
+    /// Like [`Cursor::seek`], but stops at nested buckets too.
+    pub(crate) fn seek_leaf(&mut self, key: &[u8]) -> Result<Option<Leaf<'b>>> {
+        self.position(key)?;
+        self.settle_leaf(true, true)
+    }
+
+    // Points the cursor at where `key` is or would be, which may be past the end of a leaf
+    fn position(&mut self, key: &[u8]) -> Result<()> {
+        let bucket = self.bucket.borrow();
+        let mut stack = vec![self.root()?];
+        loop {
+            let top = stack.last_mut().unwrap();
+            let child = match &top.node.borrow().data {
+                NodeData::Branches(branches) if !branches.is_empty() => {
+                    top.index = branch_index(branches, key, &bucket.cmp);
+                    Self::prefetch(&bucket, branches, top.index, false);
+                    branches[top.index].page
+                }
+                NodeData::Branches(_) => break,
+                NodeData::Leaves(leaves) => {
+                    top.index = match leaves.binary_search_by(|l| bucket.cmp.compare(l.key(), key)) {
+                        Ok(index) => index,
+                        Err(index) => index,
+                    };
+                    break;
+                }
+            };
+            stack.push(Elem {
+                node: bucket.read_node(PageNodeID::Page(child))?,
+                index: 0,
+            });
+        }
+        drop(bucket);
+        self.stack = stack;
+        Ok(())
+    }
+
+    fn root(&self) -> Result<Elem<'tx>> {
+        let bucket = self.bucket.borrow();
+        Ok(Elem {
+            node: bucket.read_node(bucket.root())?,
+            index: 0,
+        })
+    }
+
+    // Follows the branches at the top of the stack down to a leaf,
+    // taking each node's first or last element on the way. `stepped`
+    // is set if the top of the stack was just stepped forward to.
+    fn descend(
+        bucket: &Rc<RefCell<InnerBucket<'tx>>>,
+        mut stack: Vec<Elem<'tx>>,
+        last: bool,
+        mut stepped: bool,
+    ) -> Result<Vec<Elem<'tx>>> {
+        let bucket = bucket.borrow();
+        loop {
+            let top = stack.last().unwrap();
+            let child = match &top.node.borrow().data {
+                NodeData::Branches(branches) if top.index < branches.len() => {
+                    if !last {
+                        Self::prefetch(&bucket, branches, top.index, stepped);
+                    }
+                    Some(branches[top.index].page)
+                }
+                _ => None,
+            };
+            stepped = false;
+            let child = match child {
+                Some(child) => child,
+                None => return Ok(stack),
+            };
+            let node = bucket.read_node(PageNodeID::Page(child))?;
+            let index = match last {
+                true => node.borrow().data.len().saturating_sub(1),
+                false => 0,
+            };
+            stack.push(Elem { node, index });
+        }
+    }
+
+    // Advises the OS that the pages following `index` in `branches` will be needed soon,
+    // up to the configured number of them. After a step forward from `index - 1`, all
+    // but the last of them were already advised.
+    fn prefetch(bucket: &InnerBucket<'tx>, branches: &[Branch<'tx>], index: usize, stepped: bool) {
+        let window = bucket.pages.prefetch_window();
+        let start = match stepped {
+            true => index + window,
+            false => index + 1,
+        };
+        for branch in branches.iter().take(index + window + 1).skip(start) {
+            bucket.pages.will_need(branch.page);
+        }
+    }
+
+    // Moves one element forwards or backwards, crossing into the neighbouring leaf if needed.
+    // Returns false, leaving the cursor where it was, if there is nothing in that direction.
+    fn step(&mut self, forward: bool) -> Result<bool> {
+        let mut stack = self.stack.clone();
+        loop {
+            let top = match stack.last_mut() {
+                Some(top) => top,
+                None => return Ok(false),
+            };
+            let len = top.node.borrow().data.len();
+            if forward && top.index + 1 < len {
+                top.index += 1;
+                break;
+            }
+            if !forward && top.index > 0 && len > 0 {
+                top.index = top.index.min(len) - 1;
+                break;
+            }
+            stack.pop();
+        }
+        self.stack = Self::descend(&self.bucket, stack, !forward, forward)?;
+        Ok(true)
+    }
+
+    fn advance(&mut self, forward: bool) -> Result<Option<(Bytes<'b>, Bytes<'b>)>> {
+        let start = self.stack.clone();
+        let pair = match self.step(forward)? {
+            true => self.settle(forward)?,
+            false => None,
+        };
+        if pair.is_none() {
+            self.stack = start;
+        }
+        Ok(pair)
+    }
+
+    /// Moves to the next element, nested buckets included.
+    pub(crate) fn next_leaf(&mut self) -> Result<Option<Leaf<'b>>> {
+        if self.stack.is_empty() {
+            self.stack = Self::descend(&self.bucket, vec![self.root()?], false, false)?;
+        } else if !self.step(true)? {
+            return Ok(None);
+        }
+        self.settle_leaf(true, true)
+    }
+
+    fn settle(&mut self, forward: bool) -> Result<Option<(Bytes<'b>, Bytes<'b>)>> {
+        match self.settle_leaf(forward, false)? {
+            Some(Leaf::Kv(key, value)) => Ok(Some((key, value))),
+            _ => Ok(None),
+        }
+    }
+
+    // Steps past anything that isn't a key / value pair, or a nested bucket if
+    // `buckets` is set, such as empty leaves or the end of a leaf after a seek.
+    fn settle_leaf(&mut self, forward: bool, buckets: bool) -> Result<Option<Leaf<'b>>> {
+        loop {
+            if let Some(top) = self.stack.last() {
+                if let NodeData::Leaves(leaves) = &top.node.borrow().data {
+                    match leaves.get(top.index) {
+                        Some(Leaf::Kv(key, value)) => {
+                            let value = self.bucket.borrow().pages.decode(value.clone())?;
+                            return Ok(Some(Leaf::Kv(key.clone(), value)));
+                        }
+                        Some(leaf @ Leaf::Bucket(..)) if buckets => return Ok(Some(leaf.clone())),
+                        _ => {}
+                    }
+                }
+            }
+            if !self.step(forward)? {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Iterates over the key / value pairs of a [`Bucket`](crate::Bucket) whose keys
+/// lie in a range, in key order. Returned by [`Bucket::range`](crate::Bucket::range).
+pub struct RangeIter<'b, 'tx: 'b> {
+    cursor: Cursor<'b, 'tx>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    started: bool,
+    done: bool,
+}
+
+impl<'b, 'tx> RangeIter<'b, 'tx> {
+    pub(crate) fn new<K: AsRef<[u8]> + ?Sized, R: RangeBounds<K>>(cursor: Cursor<'b, 'tx>, range: R) -> RangeIter<'b, 'tx> {
+        RangeIter {
+            cursor,
+            start: range.start_bound().map(|k| k.as_ref().to_vec()),
+            end: range.end_bound().map(|k| k.as_ref().to_vec()),
+            started: false,
+            done: false,
+        }
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.cursor.bucket.borrow().cmp.compare(a, b)
+    }
+
+    fn next_pair(&mut self) -> Result<Option<(Bytes<'b>, Bytes<'b>)>> {
+        if self.started {
+            return self.cursor.next();
+        }
+        self.started = true;
+        match &self.start {
+            Bound::Unbounded => self.cursor.first(),
+            Bound::Included(start) => self.cursor.seek(start),
+            Bound::Excluded(start) => match self.cursor.seek(start)? {
+                Some((key, _)) if self.compare(&key, start).is_eq() => self.cursor.next(),
+                pair => Ok(pair),
+            },
+        }
+    }
+}
+
+impl<'b, 'tx> Iterator for RangeIter<'b, 'tx> {
+    type Item = Result<(Bytes<'b>, Bytes<'b>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let pair = match self.next_pair() {
+            Ok(Some(pair)) => pair,
+            Ok(None) => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        let within = match &self.end {
+            Bound::Unbounded => true,
+            Bound::Included(end) => self.compare(&pair.0, end).is_le(),
+            Bound::Excluded(end) => self.compare(&pair.0, end).is_lt(),
+        };
+        if !within {
+            self.done = true;
+            return None;
+        }
+        Some(Ok(pair))
+    }
+}
+
+/// Iterates over the key / value pairs of a [`Bucket`](crate::Bucket) whose keys
+/// start with a prefix, in key order. Returned by [`Bucket::prefix`](crate::Bucket::prefix).
+pub struct PrefixIter<'b, 'tx: 'b> {
+    cursor: Cursor<'b, 'tx>,
+    prefix: Vec<u8>,
+    started: bool,
+    done: bool,
+}
+
+impl<'b, 'tx> PrefixIter<'b, 'tx> {
+    pub(crate) fn new(cursor: Cursor<'b, 'tx>, prefix: &[u8]) -> PrefixIter<'b, 'tx> {
+        PrefixIter {
+            cursor,
+            prefix: prefix.to_vec(),
+            started: false,
+            done: false,
+        }
+    }
+}
+
+impl<'b, 'tx> Iterator for PrefixIter<'b, 'tx> {
+    type Item = Result<(Bytes<'b>, Bytes<'b>)>;
+
+    // Stops at the first key without the prefix rather than at an upper bound,
+    // which a prefix of 0xFF bytes doesn't have
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let pair = match self.started {
+            true => self.cursor.next(),
+            false => {
+                self.started = true;
+                self.cursor.seek(&self.prefix)
+            }
+        };
+        match pair {
+            Ok(Some(pair)) if pair.0.starts_with(&self.prefix) => Some(Ok(pair)),
+            Ok(_) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Iterates over the keys of a [`Bucket`](crate::Bucket), in key order.
+/// Returned by [`Bucket::keys`](crate::Bucket::keys).
+///
+/// Values are never read, so the overflow pages holding the rest of a large
+/// value aren't faulted in unless the page cache copies them.
+pub struct KeyIter<'b, 'tx: 'b> {
+    cursor: Cursor<'b, 'tx>,
+    done: bool,
+}
+
+impl<'b, 'tx> KeyIter<'b, 'tx> {
+    pub(crate) fn new(cursor: Cursor<'b, 'tx>) -> KeyIter<'b, 'tx> {
+        KeyIter { cursor, done: false }
+    }
+}
+
+impl<'b, 'tx> Iterator for KeyIter<'b, 'tx> {
+    type Item = Result<Bytes<'b>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.cursor.next() {
+            Ok(Some((key, _))) => Some(Ok(key)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use crate::config::Config;
+
+    use super::RangeIter;
+
+    #[test]
+    fn test_cursor_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let mut expected: Vec<Vec<u8>> = (0..1000u32).map(|i| (i * 7919 % 1000).to_be_bytes().to_vec()).collect();
+
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"numbers").unwrap();
+        for key in expected.iter() {
+            bucket.put(key.clone(), key.clone()).unwrap();
+        }
+        bucket.create_bucket(b"nested").unwrap();
+        tx.commit().unwrap();
+        expected.sort();
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"numbers").unwrap();
+        let mut cursor = bucket.cursor();
+        let mut keys = Vec::new();
+        while let Some((key, value)) = cursor.next().unwrap() {
+            assert_eq!(key.as_ref(), value.as_ref());
+            keys.push(key.as_ref().to_vec());
+        }
+        assert_eq!(keys, expected);
+        // running off the end leaves the cursor on the last pair
+        assert_eq!(cursor.prev().unwrap().unwrap().0.as_ref(), &expected[998][..]);
+
+        let mut cursor = bucket.cursor();
+        let mut keys = Vec::new();
+        while let Some((key, _)) = cursor.prev().unwrap() {
+            keys.push(key.as_ref().to_vec());
+        }
+        keys.reverse();
+        assert_eq!(keys, expected);
+
+        let (key, _) = cursor.seek(&500u32.to_be_bytes()).unwrap().unwrap();
+        assert_eq!(key.as_ref(), 500u32.to_be_bytes());
+        let (key, _) = cursor.next().unwrap().unwrap();
+        assert_eq!(key.as_ref(), 501u32.to_be_bytes());
+        assert!(cursor.seek(&[0xFF; 5]).unwrap().is_none());
+        assert_eq!(cursor.first().unwrap().unwrap().0.as_ref(), &expected[0][..]);
+        assert_eq!(cursor.last().unwrap().unwrap().0.as_ref(), &expected[999][..]);
+    }
+
+    #[test]
+    fn test_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"numbers").unwrap();
+        for i in 0..100u32 {
+            bucket.put(i.to_be_bytes().to_vec(), i.to_string()).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"numbers").unwrap();
+        let key = |i: u32| i.to_be_bytes();
+        let keys = |range: RangeIter| -> Vec<u32> {
+            range
+                .map(|pair| u32::from_be_bytes(pair.unwrap().0.as_slice().try_into().unwrap()))
+                .collect()
+        };
+        let (k10, k20) = (key(10), key(20));
+        assert_eq!(keys(bucket.range(&k10[..]..&k20[..])), (10..20).collect::<Vec<_>>());
+        assert_eq!(keys(bucket.range(&k10[..]..=&k20[..])), (10..=20).collect::<Vec<_>>());
+        let exclusive = (Bound::Excluded(k10.to_vec()), Bound::Included(k20.to_vec()));
+        assert_eq!(keys(bucket.range(exclusive)), (11..=20).collect::<Vec<_>>());
+        assert_eq!(keys(bucket.range(..&k10[..])), (0..10).collect::<Vec<_>>());
+        assert_eq!(keys(bucket.range(&key(95)[..]..)), (95..100).collect::<Vec<_>>());
+        assert_eq!(keys(bucket.range::<[u8], _>(..)).len(), 100);
+        // bounds between keys
+        assert_eq!(keys(bucket.range(&[0, 0, 0, 10, 0][..]..&[0, 0, 0, 12, 0][..])), vec![11, 12]);
+        assert!(keys(bucket.range(&k20[..]..&k10[..])).is_empty());
+    }
+
+    #[test]
+    fn test_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"paths").unwrap();
+        let keys: [&[u8]; 9] = [
+            b"user", b"user/1", b"user/1/name", b"user/2", b"userx", b"users/1", b"group/1",
+            b"\xff\xff", b"\xff\xff\x01",
+        ];
+        for key in keys {
+            bucket.put(key, "value").unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"paths").unwrap();
+        let matches = |prefix: &[u8]| -> Vec<Vec<u8>> {
+            bucket.prefix(prefix).map(|pair| pair.unwrap().0.to_vec()).collect()
+        };
+        assert_eq!(matches(b"user/"), vec![b"user/1".to_vec(), b"user/1/name".to_vec(), b"user/2".to_vec()]);
+        assert_eq!(matches(b"user/1/"), vec![b"user/1/name".to_vec()]);
+        assert!(matches(b"nobody").is_empty());
+        // no upper bound exists for a prefix of 0xFF bytes
+        assert_eq!(matches(b"\xff\xff"), vec![b"\xff\xff".to_vec(), b"\xff\xff\x01".to_vec()]);
+        assert_eq!(matches(b"").len(), keys.len());
+    }
+
+    #[test]
+    fn test_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).cache_capacity(0).open().unwrap();
+        let value = vec![7u8; 16 * db.pagesize() as usize];
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..20 {
+            bucket.put(format!("key{:02}", i), value.clone()).unwrap();
+        }
+        bucket.create_bucket(b"key05x").unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        let keys: Vec<Vec<u8>> = bucket.keys().map(|key| key.unwrap().to_vec()).collect();
+        let expected: Vec<Vec<u8>> = (0..20).map(|i| format!("key{:02}", i).into_bytes()).collect();
+        assert_eq!(keys, expected);
+        // only the first page of each node was read, none of the overflow pages holding the values
+        let pages_read = tx.stats().pages_read;
+        assert!(pages_read < 30, "{} pages read", pages_read);
+        assert!(db.stats().unwrap().num_pages > 20 * 16);
+    }
+
+    #[test]
+    fn test_scan_prefetch() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).scan_prefetch(8).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        // enough leaves for the tree to grow a level of branches
+        for i in 0..50000 {
+            bucket.put(format!("key{:05}", i), format!("value{}", i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        let mut i = 0;
+        bucket
+            .for_each(|key, value| {
+                assert_eq!(key.as_slice(), format!("key{:05}", i).as_bytes());
+                assert_eq!(value.as_slice(), format!("value{}", i).as_bytes());
+                i += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(i, 50000);
+
+        let start = b"key12345".to_vec();
+        let keys: Vec<_> = bucket.range(start..).map(|pair| pair.unwrap().0.to_vec()).collect();
+        let expected: Vec<_> = (12345..50000).map(|i| format!("key{:05}", i).into_bytes()).collect();
+        assert_eq!(keys, expected);
+    }
+}
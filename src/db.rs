@@ -1,18 +1,56 @@
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::mem::align_of;
+use std::os::unix::fs::FileExt as _;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
+use fs2::FileExt;
+use log::error;
+use page_size::get as get_page_size;
+
+use crate::bucket::Bucket;
+use crate::config::MmapAdvice;
 use crate::config::running_config::RunningConfig;
 use crate::context::Context;
-use crate::errors::Result;
-use crate::inner::Inner;
-use crate::options::Options;
+use crate::defaults::DATABASE_INTEGRITY_CODE;
+use crate::errors::{Error, Result};
+use crate::freelist::Freelist;
+use crate::maybe_fsync_directory;
+use crate::meta::Meta;
+use crate::migrate::migrate;
+use crate::node::{Comparator, Leaf, NodeData};
+use crate::options::{init_pages, Options};
+use crate::page::{Page, PageID, Pages};
+use crate::snapshot::Snapshot;
 use crate::transaction::Tx;
+use crate::utils::{mmap, AlignedBuf};
+
+/// A point-in-time summary of the database's pages, from [`DB::stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Pages in use by the database, free or not
+    pub num_pages: u64,
+    /// Pages available for reuse
+    pub free_pages: u64,
+    /// Freed pages that can't be reused until older read-only transactions finish
+    pub pending_pages: u64,
+    /// Id of the last committed transaction
+    pub tx_id: u64,
+    /// Size of each page in bytes
+    pub pagesize: u64,
+    /// Page reads answered by the page cache
+    pub cache_hits: u64,
+    /// Page reads that had to decode the page
+    pub cache_misses: u64,
+}
 
 #[derive(Clone)]
 #[doc(alias = "database")]
 pub struct DB {
     pub context: Context,
-    pub(crate) inner: Arc<Inner>,
 }
 
 
@@ -21,14 +59,1479 @@ impl DB {
         Options::new().open(path)
     }
 
-    pub fn tx(&self, writable: bool) -> Result<Tx> {
+    pub fn tx(&self, writable: bool) -> Result<Tx<'_>> {
         Tx::new(self, writable)
     }
+
+    /// Starts a transaction, waiting at most `timeout` for the lock.
+    ///
+    /// Writable transactions wait on the single writer lock, while read-only
+    /// transactions take no lock and start at once.
+    /// Returns [`Error::Timeout`](crate::errors::Error::Timeout) if the
+    /// lock could not be acquired in time.
+    pub fn tx_timeout(&self, writable: bool, timeout: Duration) -> Result<Tx<'_>> {
+        Tx::with_timeout(self, writable, timeout)
+    }
+
+    /// Runs `f` in a writable transaction, committing it if `f` returns `Ok`.
+    ///
+    /// If `f` returns an error or panics, the transaction is dropped without
+    /// being committed, which rolls it back.
+    pub fn update<T, F: FnOnce(&Tx) -> Result<T>>(&self, f: F) -> Result<T> {
+        let tx = self.tx(true)?;
+        let value = f(&tx)?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    /// Runs `f` in a read-only transaction, which ends when `f` returns.
+    pub fn view<T, F: FnOnce(&Tx) -> Result<T>>(&self, f: F) -> Result<T> {
+        f(&self.tx(false)?)
+    }
+
+    /// Takes a read-only [`Snapshot`] of the database that can outlive any borrow of the `DB`.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Snapshot::new(&self.context)
+    }
+
+    /// Gives free pages at the end of the file back to the filesystem,
+    /// returning the number of bytes reclaimed.
+    ///
+    /// Only the contiguous run of free pages ending at the last page can be
+    /// reclaimed, so this is a cheap alternative to a full compaction after
+    /// a large delete. Fails while any read-only transactions are open.
+    pub fn shrink(&self) -> Result<u64> {
+        let old_len = self.context.file.lock().metadata()?.len();
+        // The trailing free pages leave the freelist in a commit of their own
+        let tx = self.tx(true)?;
+        let truncated = {
+            let inner = tx.inner.borrow();
+            let mut freelist = inner.freelist.borrow_mut();
+            let num_pages = freelist.meta.num_pages;
+            freelist.meta.num_pages = freelist.inner.truncate(num_pages);
+            freelist.meta.num_pages < num_pages
+        };
+        if truncated {
+            tx.commit()?;
+        } else {
+            tx.rollback()?;
+        }
+
+        // Later commits may have allocated past the truncated end again, so the file
+        // is cut down to the newest meta's pages, once every commit up to it is durable
+        let file = self.context.file.lock();
+        let meta = self.context.latest_meta()?;
+        if self.context.syncs_on_commit() {
+            self.context.sync_commit(meta.tx_id)?;
+        } else {
+            self.context.flush()?;
+        }
+        // Readers would still see pages past the new end, and waiting for them to end
+        // could block forever on their own thread. Holding the lock they register
+        // under keeps new ones from starting until the file is cut down.
+        let open_ro_txs = self.context.open_ro_txs.lock();
+        if !open_ro_txs.is_empty() {
+            return Err(Error::Unsupported("cannot shrink while read transactions are open"));
+        }
+        let _mmap_lock = self.context.mmap_lock.write();
+        let new_len = meta.num_pages * self.context.pagesize;
+        if file.metadata()?.len() <= new_len {
+            return Ok(0);
+        }
+        file.set_len(new_len)?;
+        file.sync_all()?;
+        self.context.remap(&file)?;
+        Ok(old_len.saturating_sub(new_len))
+    }
+
+    /// Copies every bucket and key / value pair into a new database at `path`,
+    /// and returns it.
+    ///
+    /// Pairs are written in key order, so the new database is densely packed and
+    /// holds none of this one's free pages. It is a full rewrite, so it's much
+    /// slower than [`DB::shrink`] but reclaims space from anywhere in the file.
+    /// The new file is no larger than the pages in use. The new database uses
+    /// the same config apart from its path, and `path` must not already hold a database.
+    pub fn compact<P: AsRef<Path>>(&self, path: P) -> Result<DB> {
+        let config = self.context.inner.with_new_path(path);
+        if config.db_path().metadata().is_ok_and(|m| m.len() > 0) {
+            return Err(Error::Unsupported("cannot compact into an existing database"));
+        }
+        let db = config.open()?;
+
+        let src = self.tx(false)?;
+        let dst = db.tx(true)?;
+        copy_bucket(&src.root_bucket(), &dst.root_bucket())?;
+        dst.commit()?;
+
+        // Drop the room the file was grown by in advance, so it holds only the copy
+        {
+            let file = db.context.file.lock();
+            let _mmap_lock = db.context.mmap_lock.write();
+            file.set_len(db.context.meta()?.num_pages * db.context.pagesize)?;
+            file.sync_all()?;
+            db.context.remap(&file)?;
+        }
+        Ok(db)
+    }
+
+    /// Writes a consistent copy of the database file to `out`, returning the
+    /// number of bytes written. The copy can be opened like any other db file.
+    ///
+    /// The copy is taken from a read-only transaction, so commits carry on while
+    /// it's written without reusing any page it copies. A commit that needs to
+    /// grow the file waits until the copy is done.
+    pub fn backup<W: Write>(&self, out: &mut W) -> Result<u64> {
+        self.tx(false)?.write_to(out)
+    }
+
+    /// Writes every page changed by a transaction after `since_tx` to `out`,
+    /// and returns the id of the last committed transaction, to be passed as
+    /// `since_tx` to the next incremental backup.
+    ///
+    /// Applying the pages on top of a backup taken at `since_tx` brings it up to
+    /// date. Each block of pages is written as its first page id and its number
+    /// of pages, both as little endian `u64`s, followed by its contents. Both meta
+    /// pages are always written first. Commits wait until the backup is done.
+    pub fn backup_since<W: Write>(&self, since_tx: u64, out: &mut W) -> Result<u64> {
+        let _file = self.context.file.lock();
+        let meta = self.context.meta()?;
+        let pages = Pages::new(self.context.data.lock().clone(), self.context.pagesize);
+        let pagesize = self.context.pagesize;
+        let mut write_block = |id: PageID, num_pages: u64| -> Result<()> {
+            let start = (id * pagesize) as usize;
+            out.write_all(&id.to_le_bytes())?;
+            out.write_all(&num_pages.to_le_bytes())?;
+            out.write_all(&pages.data[start..start + (num_pages * pagesize) as usize])?;
+            Ok(())
+        };
+        write_block(0, 2)?;
+
+        // Free pages may hold stale headers, so every page is looked at rather than
+        // skipping over blocks that haven't changed. Copying a stale block is harmless.
+        let mut id = 2;
+        while id < meta.num_pages {
+            let page = pages.try_page(id)?;
+            if page.id != id || page.tx_id <= since_tx {
+                id += 1;
+                continue;
+            }
+            let num_pages = (page.overflow + 1).min(meta.num_pages - id);
+            write_block(id, num_pages)?;
+            id += num_pages;
+        }
+        Ok(meta.tx_id)
+    }
+
+    /// Rebuilds the freelist of the database file at `path`, for when a corrupt
+    /// freelist page keeps it from opening.
+    ///
+    /// Every page reachable from the newest valid meta page is in use, and every
+    /// other page below `num_pages` is free. The new freelist is appended to the
+    /// file, and a new meta pointing at it is written. The database must not be open.
+    /// The meta pages are checked against the application id stored in them, as set by
+    /// [`Config::application_id`](crate::Config::application_id).
+    ///
+    /// Pages pointed at beyond `num_pages`, already reached another way, or corrupt
+    /// are skipped, logging them, so a damaged tree can't make the walk fail or loop.
+    /// A corrupt page stays in use, as the tree still points at it.
+    pub fn repair<P: AsRef<Path>>(path: P) -> Result<()> {
+        let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        if file.try_lock_exclusive().is_err() {
+            return Err(Error::Io(ErrorKind::Other, "could not acquire database file lock"));
+        }
+        let data = Arc::new(mmap(&file, false, MmapAdvice::Sequential)?);
+        let first = Pages::new(data.clone(), get_page_size() as u64).try_page(0)?.try_meta();
+        let pagesize = match first {
+            Ok(first) if first.valid() => first.pagesize,
+            _ => get_page_size() as u64,
+        };
+        let pages = Pages::new(data, pagesize);
+        // Which id the database was created with is only known from its meta
+        let application_id = (0..2)
+            .filter_map(|id| pages.try_page(id).ok()?.try_meta().ok().filter(|meta| meta.valid()))
+            .max_by_key(|meta| meta.tx_id)
+            .map_or(DATABASE_INTEGRITY_CODE, |meta| meta.integrity_code);
+        let mut meta = Meta::newest(&pages, application_id)?;
+
+        let mut used = vec![false; meta.num_pages as usize];
+        used[0] = true;
+        used[1] = true;
+        let mut corrupt = 0;
+        let mut stack = vec![meta.root.root_page];
+        while let Some(id) = stack.pop() {
+            if id >= meta.num_pages {
+                error!("skipping page {}, beyond the last page {}", id, meta.num_pages - 1);
+                continue;
+            }
+            if used[id as usize] {
+                continue;
+            }
+            used[id as usize] = true;
+            let page = match pages.checked_page(id) {
+                Ok(page) => page,
+                Err(e) => {
+                    error!("skipping page {}: {}", id, e);
+                    corrupt += 1;
+                    continue;
+                }
+            };
+            let end = (id + page.overflow + 1).min(meta.num_pages);
+            used[id as usize..end as usize].iter_mut().for_each(|u| *u = true);
+            let data = match NodeData::from_page(page) {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("skipping page {}, which can't be decoded: {}", id, e);
+                    corrupt += 1;
+                    continue;
+                }
+            };
+            match data {
+                NodeData::Branches(branches) => stack.extend(branches.iter().map(|b| b.page)),
+                NodeData::Leaves(leaves) => stack.extend(leaves.iter().filter_map(|l| match l {
+                    Leaf::Bucket(_, meta) if meta.root_page != 0 => Some(meta.root_page),
+                    _ => None,
+                })),
+            }
+        }
+        if corrupt > 0 {
+            error!("skipped {} corrupt pages, whose contents are lost", corrupt);
+        }
+        let free_pages: Vec<PageID> = (0..meta.num_pages).filter(|id| !used[*id as usize]).collect();
+        let mut freelist = Freelist::new();
+        freelist.init(&free_pages);
+
+        let num_pages = write_freelist(&file, &freelist, meta.num_pages, meta.tx_id + 1, pagesize, align_of::<Page>())?;
+        meta.freelist_page = meta.num_pages;
+        meta.num_pages += num_pages;
+        meta.tx_id += 1;
+        if file.metadata()?.len() < meta.num_pages * pagesize {
+            file.allocate(meta.num_pages * pagesize)?;
+        }
+        file.sync_all()?;
+        meta.write(&file, pagesize, align_of::<Page>())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Walks every page reachable from the newest meta, returning a description of
+    /// each problem found, or nothing for a healthy database.
+    ///
+    /// Reports meta pages that fail their checks, pages that are corrupt, reachable
+    /// twice, both reachable and free, or beyond the last page, and keys out of order.
+    /// It's the read-only counterpart of [`DB::repair`]: nothing is written, and it
+    /// reads from a read-only transaction, so commits carry on meanwhile.
+    pub fn check(&self) -> Result<Vec<String>> {
+        let tx = self.tx(false)?;
+        let meta = tx.inner.borrow().meta.clone();
+        let pages = Pages::new(self.context.data.lock().clone(), self.context.pagesize);
+        let mut problems = Vec::new();
+
+        for id in 0..2 {
+            let page = pages.try_page(id)?;
+            if page.page_type != Page::TYPE_META {
+                problems.push(format!("meta page {} is not a meta page", id));
+            } else if let Some(problem) = page.meta().check(self.context.application_id) {
+                problems.push(format!("meta page {} is invalid: {}", id, problem));
+            }
+        }
+
+        let mut used = vec![false; meta.num_pages as usize];
+        used[0] = true;
+        used[1] = true;
+        let mut mark_used = |id: PageID, num_pages: u64, problems: &mut Vec<String>| {
+            for id in id..id + num_pages {
+                match used.get_mut(id as usize) {
+                    Some(true) => problems.push(format!("page {} is referenced twice", id)),
+                    Some(used) => *used = true,
+                    None => {
+                        problems.push(format!("page {} is beyond the last page {}", id, meta.num_pages - 1));
+                        break;
+                    }
+                }
+            }
+        };
+
+        // Pages to visit, with the page pointing at them, their bucket's key order,
+        // and whether that bucket is the root one, whose children have their own order
+        let mut stack = vec![(meta.root.root_page, PageID::from(meta.meta_page), Comparator::default(), true)];
+        while let Some((id, parent, cmp, is_root)) = stack.pop() {
+            if id >= meta.num_pages {
+                problems.push(format!("page {} points at page {}, beyond the last page {}", parent, id, meta.num_pages - 1));
+                continue;
+            }
+            let page = match pages.checked_page(id) {
+                Ok(page) => page,
+                Err(e) => {
+                    problems.push(e.to_string());
+                    continue;
+                }
+            };
+            mark_used(id, page.overflow + 1, &mut problems);
+            let data = match NodeData::from_page(page) {
+                Ok(data) => data,
+                Err(e) => {
+                    problems.push(format!("page {} can't be decoded: {}", id, e));
+                    continue;
+                }
+            };
+            if (1..data.len()).any(|i| !cmp.compare(&data.key(i - 1), &data.key(i)).is_lt()) {
+                problems.push(format!("the keys on page {} are out of order", id));
+            }
+            match data {
+                NodeData::Branches(branches) => {
+                    stack.extend(branches.iter().map(|b| (b.page, id, cmp.clone(), is_root)));
+                }
+                NodeData::Leaves(leaves) => {
+                    for leaf in leaves {
+                        if let Leaf::Bucket(name, bucket) = leaf {
+                            if bucket.root_page == 0 {
+                                continue;
+                            }
+                            let cmp = match is_root {
+                                true => self.context.comparators.get(name.as_ref()).cloned().unwrap_or_default(),
+                                false => cmp.clone(),
+                            };
+                            stack.push((bucket.root_page, id, cmp, false));
+                        }
+                    }
+                }
+            }
+        }
+
+        match pages.checked_page(meta.freelist_page) {
+            Ok(page) if page.page_type == Page::TYPE_FREELIST => {
+                mark_used(meta.freelist_page, page.overflow + 1, &mut problems);
+                for id in page.freelist() {
+                    if used.get(*id as usize) == Some(&true) {
+                        problems.push(format!("page {} is both in use and free", id));
+                    }
+                }
+            }
+            Ok(_) => problems.push(format!("freelist page {} is not a freelist page", meta.freelist_page)),
+            Err(e) => problems.push(e.to_string()),
+        }
+        Ok(problems)
+    }
+
+    /// Syncs the db file and its directory, returning the number of bytes
+    /// committed since the file was last synced.
+    ///
+    /// Commits only sync by themselves when there is no background flusher, so
+    /// this makes everything committed so far durable. It doesn't take the
+    /// writer lock, so it can run while transactions are open. A successful
+    /// flush also lifts the refusal of writes after a background flush failed.
+    pub fn flush(&self) -> Result<usize> {
+        let bytes = self.context.flush()?;
+        // Databases opened from a file handle or in memory have no directory to sync
+        let dir = self.context.get_path();
+        if !self.context.in_memory && dir.is_dir() {
+            maybe_fsync_directory(dir)?;
+        }
+        self.context.degraded.store(false, Ordering::SeqCst);
+        Ok(bytes)
+    }
+
+    /// Faults every page in use into memory, so a latency-sensitive workload that
+    /// follows doesn't wait on the disk.
+    ///
+    /// The mapping is advised to be read sequentially while the pages are
+    /// touched, then set back to the configured `mmap_advise`. Does nothing for a database
+    /// opened with `mmap_populate`, whose pages are faulted in when it's mapped.
+    pub fn warmup(&self) -> Result<()> {
+        if self.context.flags.mmap_populate {
+            return Ok(());
+        }
+        let _mmap_lock = self.context.mmap_lock.read();
+        let num_pages = self.context.meta()?.num_pages;
+        let data = self.context.data.lock().clone();
+        data.advise(memmap2::Advice::Sequential)?;
+        let len = (num_pages * self.context.pagesize).min(data.len() as u64) as usize;
+        for offset in (0..len).step_by(self.context.pagesize as usize) {
+            // A volatile read, so the touch isn't optimised away
+            unsafe { std::ptr::read_volatile(data.as_ptr().add(offset)) };
+        }
+        data.advise(self.context.mmap_advise.into())?;
+        Ok(())
+    }
+
+    /// Returns counts of the database's pages, which help diagnose freelist growth and fragmentation.
+    pub fn stats(&self) -> Result<Stats> {
+        // Commits publish their meta and freelist under the freelist lock, so the two match.
+        // Unlike the writer lock, it's never held for long, nor by an open transaction.
+        let freelist = self.context.freelist.lock();
+        let meta = self.context.latest_meta()?;
+        Ok(Stats {
+            num_pages: meta.num_pages,
+            free_pages: freelist.free_pages().len() as u64,
+            pending_pages: freelist.pending_count() as u64,
+            tx_id: meta.tx_id,
+            pagesize: self.context.pagesize,
+            cache_hits: self.context.pagecache.as_ref().map_or(0, |c| c.hits()),
+            cache_misses: self.context.pagecache.as_ref().map_or(0, |c| c.misses()),
+        })
+    }
+
+    /// Returns the number of key / value pairs in the database, nested buckets
+    /// included, as of the last commit.
+    ///
+    /// Every bucket keeps a count of its pairs, so this doesn't scan anything.
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.context.meta()?.root.num_keys as usize)
+    }
+
+    /// Returns true if the database holds no key / value pairs, though it may hold empty buckets.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns the bytes taken up by the page cache, which stays within `cache_capacity`.
+    pub fn cache_size(&self) -> usize {
+        self.context.pagecache.as_ref().map_or(0, |c| c.size() as usize)
+    }
+
+    /// Returns the application-defined schema version stored in the database.
+    ///
+    /// This is independent of the crate's on-disk format version, and is `0`
+    /// until [`DB::set_user_version`] is called.
+    pub fn user_version(&self) -> Result<u64> {
+        Ok(self.context.meta()?.user_version)
+    }
+
+    /// Stores an application-defined schema version in the meta page, which
+    /// applications can use to gate their own migrations.
+    ///
+    /// The version is set by a writable transaction of its own, so it's as
+    /// durable as any other commit.
+    pub fn set_user_version(&self, version: u64) -> Result<()> {
+        let tx = self.tx(true)?;
+        tx.inner.borrow_mut().meta.user_version = version;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns the size of the database's pages in bytes.
+    ///
+    /// Values too large to share a page with others are written to overflow pages,
+    /// so keeping values well below this size avoids them.
     pub fn pagesize(&self) -> u64 {
-        todo!()
+        self.context.pagesize
+    }
+
+    /// Returns the directory holding the database's files.
+    ///
+    /// For a temporary database opened without a path of its own, this is the
+    /// directory generated for it, which is removed when the database is dropped.
+    pub fn path(&self) -> PathBuf {
+        self.context.get_path()
     }
 
-    pub(crate) fn start_inner(config: RunningConfig) -> Result<Self> {
-        todo!()
+    /// Finishes opening the database: writes the initial pages if the file is
+    /// new, then picks the newest valid meta page and loads the freelist.
+    pub(crate) fn start_inner(mut config: RunningConfig) -> Result<Self> {
+        let file = config.file.get_mut().clone();
+        if file.metadata()?.len() == 0 {
+            if config.read_only {
+                return Err(Error::InvalidDB("a read-only database can't be created".to_string()));
+            }
+            init_pages(
+                &file,
+                config.pagesize,
+                config.initial_num_pages(config.pagesize),
+                config.application_id,
+                config.io_align,
+            )?;
+            let _mmap_lock = config.mmap_lock.write();
+            config.remap(&file)?;
+        }
+
+        // A file shorter than its two meta pages was truncated, and reading
+        // them would go past the end of the map.
+        let mut data = config.data.get_mut().clone();
+        let len = data.len() as u64;
+        let too_small = |pagesize: u64| len < 2 * pagesize;
+        if too_small(config.pagesize) {
+            return Err(Error::InvalidDB("file too small".to_string()));
+        }
+        // A file in an older format is upgraded before anything else reads it
+        if migrate(&file, &data, config.pagesize, config.application_id, config.io_align, config.read_only)? {
+            let _mmap_lock = config.mmap_lock.write();
+            config.remap(&file)?;
+            data = config.data.get_mut().clone();
+        }
+        // The database may have been created with a different pagesize than this
+        // host's, and it's needed to find the second meta page. A first page that
+        // isn't a valid meta is left for Meta::newest to report, or to skip.
+        if let Ok(first) = Pages::new(data.clone(), config.pagesize).try_page(0)?.try_meta() {
+            if first.valid() {
+                if too_small(first.pagesize) {
+                    return Err(Error::InvalidDB("file too small".to_string()));
+                }
+                config.pagesize = first.pagesize;
+            }
+        }
+        let meta = config.meta()?;
+        config.pagesize = meta.pagesize;
+        config.commits.get_mut().durable = meta.tx_id;
+
+        let pages = Pages::new(data, config.pagesize);
+        let freelist = match pages.checked_page(meta.freelist_page) {
+            Ok(page) => Freelist::read(page),
+            Err(e) if config.read_only => return Err(e),
+            // An unreadable freelist page is restored from metadata snapshots if they're
+            // up to date, and otherwise only DB::repair can rebuild it
+            Err(e) => {
+                let free_pages: Vec<PageID> = match config.recover_free_pages(&meta) {
+                    Some(free_pages) => free_pages.into_iter().collect(),
+                    None => return Err(e),
+                };
+                error!("freelist page {} is unreadable, restoring it from snapshots: {}", meta.freelist_page, e);
+                let mut freelist = Freelist::new();
+                freelist.init(&free_pages);
+                write_freelist(&file, &freelist, meta.freelist_page, meta.tx_id, config.pagesize, config.io_align)?;
+                file.sync_all()?;
+                freelist
+            }
+        };
+        let free_pages: Vec<PageID> = freelist.free_pages().range(..meta.num_pages).copied().collect();
+        config.freelist.get_mut().init(&free_pages);
+
+        Ok(DB {
+            context: Context::new(config),
+        })
+    }
+}
+
+// Writes `freelist` to the block of pages starting at `page_id` as the commit `tx_id`
+// would have, returning how many pages it takes
+pub(crate) fn write_freelist(file: &File, freelist: &Freelist, page_id: PageID, tx_id: u64, pagesize: u64, align: usize) -> Result<u64> {
+    let num_pages = freelist.size().div_ceil(pagesize).max(1);
+    let mut buf = AlignedBuf::new((num_pages * pagesize) as usize, align)?;
+    #[allow(clippy::cast_ptr_alignment)]
+    let page = unsafe { &mut *(buf.as_mut_slice().as_mut_ptr() as *mut Page) };
+    page.id = page_id;
+    page.overflow = num_pages - 1;
+    page.tx_id = tx_id;
+    freelist.write(page);
+    file.write_all_at(buf.as_slice(), page_id * pagesize)?;
+    Ok(num_pages)
+}
+
+// Copies the contents of `src` into `dst`, recreating nested buckets
+fn copy_bucket(src: &Bucket, dst: &Bucket) -> Result<()> {
+    let mut cursor = src.cursor();
+    while let Some(leaf) = cursor.next_leaf()? {
+        match leaf {
+            Leaf::Kv(key, value) => dst.put(key.to_vec(), value.to_vec())?,
+            Leaf::Bucket(name, _) => copy_bucket(&src.bucket(&name)?, &dst.create_bucket(&name)?)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::mem::size_of;
+    use std::os::unix::fs::FileExt;
+
+    use crate::config::{Config, Mode};
+    use crate::db::DB;
+    use crate::errors::Error;
+    use crate::node::Leaf;
+    use crate::page::{BranchElement, Page};
+
+    #[test]
+    fn test_start_inner() {
+        let dir = tempfile::tempdir().unwrap();
+        let meta = {
+            let db = Config::new().path(dir.path()).open().unwrap();
+            let meta = db.context.meta().unwrap();
+            assert_eq!(meta.tx_id, 0);
+            assert_eq!(meta.freelist_page, 2);
+            assert_eq!(meta.root.root_page, 3);
+            meta
+        };
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let reopened = db.context.meta().unwrap();
+        assert_eq!(reopened.hash, meta.hash);
+        assert_eq!(reopened.root, meta.root);
+        assert_eq!(reopened.num_pages, meta.num_pages);
+    }
+
+    #[test]
+    fn test_update_and_view() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        db.update(|tx| tx.create_bucket(b"data")?.put("key", "value")).unwrap();
+
+        // an error rolls everything back
+        let err = db.update(|tx| {
+            let bucket = tx.bucket(b"data")?;
+            bucket.put("key", "changed")?;
+            bucket.put("other", "value")?;
+            tx.create_bucket(b"more")?;
+            tx.delete_bucket(b"nope")
+        });
+        assert_eq!(err, Err(Error::BucketMissing));
+        // and so does a panic
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            db.update(|tx| -> Result<(), Error> {
+                tx.bucket(b"data")?.put("key", "changed")?;
+                panic!("in the middle of a transaction");
+            })
+        }));
+        assert!(panicked.is_err());
+
+        let (value, other, names) = db
+            .view(|tx| {
+                let bucket = tx.bucket(b"data")?;
+                let value = bucket.get(b"key")?.map(|v| v.to_vec());
+                let names = tx.bucket_names()?.iter().map(|n| n.to_vec()).collect::<Vec<_>>();
+                Ok((value, bucket.get(b"other")?.is_some(), names))
+            })
+            .unwrap();
+        assert_eq!(value.as_deref(), Some(&b"value"[..]));
+        assert!(!other);
+        assert_eq!(names, vec![b"data".to_vec()]);
+        assert_eq!(db.context.meta().unwrap().tx_id, 1);
+        assert!(db.view(|tx| tx.create_bucket(b"more").map(|_| ())).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_short_files() {
+        // an empty file is initialized
+        let dir = tempfile::tempdir().unwrap();
+        fs::File::create(dir.path().join("db")).unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap().put("key", "value").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(db.len().unwrap(), 1);
+
+        // one truncated to less than the meta pages is refused
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("db"), [0xAB; 100]).unwrap();
+        let err = Config::new().path(dir.path()).open().err();
+        assert_eq!(err, Some(Error::InvalidDB("file too small".to_string())));
+    }
+
+    // What the db file looks like after a crash part way through a commit that
+    // turned `before` into `after`: the data pages were written, but only the first
+    // `meta_bytes` of the new meta page, and the file was cut off at `len` bytes.
+    fn crash_image(before: &[u8], after: &[u8], meta_page: usize, pagesize: usize, meta_bytes: usize, len: usize) -> Vec<u8> {
+        let mut image = after.to_vec();
+        let torn = meta_page * pagesize + meta_bytes..(meta_page + 1) * pagesize;
+        image[torn.clone()].copy_from_slice(&before[torn]);
+        image.truncate(len);
+        image
+    }
+
+    #[test]
+    fn test_crash_during_commit_recovers() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let pagesize = db.pagesize() as usize;
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..500 {
+            bucket.put(format!("key{:05}", i), "before").unwrap();
+        }
+        tx.commit().unwrap();
+        let before = std::fs::read(dir.path().join("db")).unwrap();
+        let used = (db.context.meta().unwrap().num_pages as usize) * pagesize;
+
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        for i in 0..20000 {
+            bucket.put(format!("key{:05}", i), "after").unwrap();
+        }
+        tx.commit().unwrap();
+        let meta = db.context.meta().unwrap();
+        let after = std::fs::read(dir.path().join("db")).unwrap();
+        assert!(after.len() > used);
+        drop(db);
+
+        let meta_page = (meta.tx_id % 2) as usize;
+        // nothing, just the page header, or all but the end of the hash
+        let hash_end = crate::page::Page::HEADER_SIZE as usize + std::mem::offset_of!(crate::meta::Meta, hash) + 32;
+        for meta_bytes in [0, 16, hash_end - 8] {
+            for len in [used, (used + after.len()) / 2, after.len()] {
+                let crashed = tempfile::tempdir().unwrap();
+                let image = crash_image(&before, &after, meta_page, pagesize, meta_bytes, len);
+                std::fs::write(crashed.path().join("db"), image).unwrap();
+                std::fs::copy(dir.path().join("conf"), crashed.path().join("conf")).unwrap();
+
+                let db = Config::new().path(crashed.path()).open().unwrap();
+                assert_eq!(db.context.meta().unwrap().tx_id, meta.tx_id - 1);
+                let tx = db.tx(false).unwrap();
+                let bucket = tx.bucket(b"data").unwrap();
+                assert_eq!(bucket.get(b"key00000").unwrap().unwrap().as_slice(), b"before");
+                assert!(bucket.get(b"key00500").unwrap().is_none());
+                drop(tx);
+
+                // the recovered database takes new commits as usual
+                let tx = db.tx(true).unwrap();
+                tx.bucket(b"data").unwrap().put("key00500", "again").unwrap();
+                tx.commit().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_torn_meta_falls_back() {
+        use std::os::unix::fs::FileExt;
+
+        use crate::meta::Meta;
+
+        let dir = tempfile::tempdir().unwrap();
+        let pagesize = {
+            let db = Config::new().path(dir.path()).open().unwrap();
+            let tx = db.tx(true).unwrap();
+            tx.create_bucket(b"data").unwrap();
+            tx.commit().unwrap();
+            // tx 1 wrote its meta to page 1
+            assert_eq!(db.context.meta().unwrap().tx_id, 1);
+            db.pagesize()
+        };
+
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(Config::new().path(dir.path()).db_path())
+            .unwrap();
+        let offset = pagesize + crate::page::Page::HEADER_SIZE + std::mem::offset_of!(Meta, hash) as u64;
+        file.write_all_at(&[0; 32], offset).unwrap();
+        drop(file);
+
+        let db = Config::new().path(dir.path()).open().unwrap();
+        assert_eq!(db.context.meta().unwrap().tx_id, 0);
+        assert_eq!(db.tx(false).unwrap().bucket(b"data").err(), Some(crate::errors::Error::BucketMissing));
+    }
+
+    #[test]
+    fn test_zeroed_meta_header_falls_back() {
+        use std::os::unix::fs::FileExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let pagesize = {
+            let db = Config::new().path(dir.path()).open().unwrap();
+            let tx = db.tx(true).unwrap();
+            tx.create_bucket(b"data").unwrap();
+            tx.commit().unwrap();
+            db.pagesize()
+        };
+
+        // a torn write can leave the newest meta page, page 1, without its header
+        let path = Config::new().path(dir.path()).db_path();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&[0; 64], pagesize).unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        assert_eq!(db.context.meta().unwrap().tx_id, 0);
+        assert_eq!(db.tx(false).unwrap().bucket(b"data").err(), Some(Error::BucketMissing));
+    }
+
+    #[test]
+    fn test_open_not_a_database() {
+        use std::os::unix::fs::FileExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let pagesize = {
+            let db = Config::new().path(dir.path()).open().unwrap();
+            let tx = db.tx(true).unwrap();
+            tx.create_bucket(b"data").unwrap();
+            tx.commit().unwrap();
+            db.pagesize()
+        };
+        let path = Config::new().path(dir.path()).db_path();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+
+        // the first meta page is only needed for the pagesize, so page 1 is still used
+        file.write_all_at(&[0; 64], 0).unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        assert_eq!(db.context.meta().unwrap().tx_id, 1);
+        drop(db);
+
+        file.write_all_at(&[0; 64], pagesize).unwrap();
+        let err = Config::new().path(dir.path()).open().err().unwrap();
+        assert!(matches!(err, Error::InvalidDB(msg) if msg.contains("is not a meta page")));
+        assert!(matches!(DB::repair(&path), Err(Error::InvalidDB(_))));
+
+        // random bytes aren't mistaken for a database either
+        let junk: Vec<u8> = (0..8 * pagesize).map(|_| rand::random()).collect();
+        std::fs::write(&path, junk).unwrap();
+        assert!(matches!(Config::new().path(dir.path()).open(), Err(Error::InvalidDB(_))));
+    }
+
+    #[test]
+    fn test_pagesize() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        assert_eq!(db.pagesize(), page_size::get() as u64);
+    }
+
+    #[test]
+    fn test_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        assert_eq!(db.path(), dir.path());
+
+        let db = Config::new().temporary(true).open().unwrap();
+        let path = db.path();
+        assert!(path.starts_with(crate::config::temp_dir()));
+        assert!(path.join("db").exists());
+        drop(db);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let before = db.stats().unwrap();
+        assert_eq!(before.pagesize, db.pagesize());
+
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..1000 {
+            bucket.put(format!("key{:04}", i), "value").unwrap();
+        }
+        tx.commit().unwrap();
+        let after_put = db.stats().unwrap();
+        assert_eq!(after_put.tx_id, before.tx_id + 1);
+        assert!(after_put.num_pages > before.num_pages);
+
+        let tx = db.tx(true).unwrap();
+        tx.delete_bucket(b"data").unwrap();
+        tx.commit().unwrap();
+        db.flush().unwrap();
+        let after_delete = db.stats().unwrap();
+        assert_eq!(after_delete.tx_id, before.tx_id + 2);
+        assert!(after_delete.free_pages > after_put.free_pages);
+        assert_eq!(after_delete.pending_pages, 0);
+
+        // stats don't wait for the writer lock, so a writer can read them too
+        let tx = db.tx(true).unwrap();
+        assert_eq!(db.stats().unwrap().tx_id, after_delete.tx_id);
+        drop(tx);
+
+        drop(db);
+        let db = Config::new().path(dir.path()).open().unwrap();
+        assert_eq!(db.stats().unwrap().free_pages, after_delete.free_pages);
+    }
+
+    #[test]
+    fn test_len() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        assert!(db.is_empty().unwrap());
+
+        let tx = db.tx(true).unwrap();
+        let a = tx.create_bucket(b"a").unwrap();
+        for i in 0..100 {
+            a.put(format!("key{}", i), "value").unwrap();
+        }
+        let nested = a.create_bucket(b"nested").unwrap();
+        for i in 0..50 {
+            nested.put(format!("key{}", i), "value").unwrap();
+        }
+        let c = tx.create_bucket(b"c").unwrap();
+        for i in 0..10 {
+            c.put(format!("key{}", i), "value").unwrap();
+        }
+        tx.commit().unwrap();
+        assert_eq!(db.len().unwrap(), 160);
+        assert!(!db.is_empty().unwrap());
+
+        let tx = db.tx(true).unwrap();
+        let a = tx.bucket(b"a").unwrap();
+        // overwriting a key doesn't add one
+        a.put("key0", "changed").unwrap();
+        for i in 0..10 {
+            a.bucket(b"nested").unwrap().delete(format!("key{}", i).as_bytes()).unwrap();
+        }
+        // nor does failing to delete a missing one take one away
+        assert_eq!(a.delete(b"missing"), Err(Error::KeyValueMissing));
+        assert_eq!(a.delete(b"nested"), Err(Error::IncompatibleValue));
+        tx.commit().unwrap();
+        assert_eq!(db.len().unwrap(), 150);
+
+        let tx = db.tx(true).unwrap();
+        tx.bucket(b"c").unwrap().put("more", "value").unwrap();
+        tx.delete_bucket(b"c").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(db.len().unwrap(), 140);
+
+        // uncommitted changes aren't counted
+        let tx = db.tx(true).unwrap();
+        tx.bucket(b"a").unwrap().put("uncommitted", "value").unwrap();
+        tx.rollback().unwrap();
+        drop(db);
+        let db = Config::new().path(dir.path()).open().unwrap();
+        assert_eq!(db.len().unwrap(), 140);
+    }
+
+    #[test]
+    fn test_warmup() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..5000 {
+            bucket.put(format!("key{:05}", i), format!("value{}", i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        db.warmup().unwrap();
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        for i in (0..5000).step_by(100) {
+            let value = bucket.get(format!("key{:05}", i).as_bytes()).unwrap().unwrap();
+            assert_eq!(value.as_slice(), format!("value{}", i).as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_shrink() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).mode(Mode::LowSpace).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"kept").unwrap().put("key", "value").unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..2000 {
+            bucket.put(format!("key{:04}", i), vec![0u8; 1000]).unwrap();
+        }
+        tx.commit().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.delete_bucket(b"data").unwrap();
+        tx.commit().unwrap();
+        // the pages the delete freed only become free once it's flushed, and the
+        // next commit moves the freelist off the end of the file
+        db.flush().unwrap();
+        db.tx(true).unwrap().commit().unwrap();
+        db.flush().unwrap();
+
+        // an open reader, even on this thread, makes shrinking fail rather than wait
+        let tx = db.tx(false).unwrap();
+        assert!(matches!(db.shrink(), Err(Error::Unsupported(_))));
+        drop(tx);
+        let snapshot = db.snapshot().unwrap();
+        assert!(matches!(db.shrink(), Err(Error::Unsupported(_))));
+        drop(snapshot);
+
+        let len = db.context.file.lock().metadata().unwrap().len();
+        let reclaimed = db.shrink().unwrap();
+        assert!(reclaimed > 0);
+        assert_eq!(db.context.file.lock().metadata().unwrap().len(), len - reclaimed);
+        assert_eq!(db.shrink().unwrap(), 0);
+        let num_pages = db.context.meta().unwrap().num_pages;
+        assert!(db.context.freelist.lock().free_pages().range(num_pages..).next().is_none());
+        drop(db);
+
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(false).unwrap();
+        assert_eq!(tx.bucket(b"kept").unwrap().get(b"key").unwrap().unwrap().as_slice(), b"value");
+        assert_eq!(tx.bucket(b"data").err(), Some(Error::BucketMissing));
+    }
+
+    #[test]
+    fn test_repair() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let (freelist_page, pagesize) = {
+            let db = Config::new().path(dir.path()).open().unwrap();
+            let tx = db.tx(true).unwrap();
+            let bucket = tx.create_bucket(b"data").unwrap();
+            for i in 0..2000 {
+                bucket.put(format!("key{:04}", i), format!("value{}", i)).unwrap();
+            }
+            bucket.create_bucket(b"nested").unwrap().put("key", "value").unwrap();
+            tx.commit().unwrap();
+            // free some pages, so there is a freelist to lose
+            let tx = db.tx(true).unwrap();
+            let bucket = tx.bucket(b"data").unwrap();
+            for i in 0..1000 {
+                bucket.delete(format!("key{:04}", i).as_bytes()).unwrap();
+            }
+            tx.commit().unwrap();
+            (db.context.meta().unwrap().freelist_page, db.pagesize())
+        };
+
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&[0xff; 64], freelist_page * pagesize).unwrap();
+        drop(file);
+        let config = Config::new().path(dir.path()).flags(crate::config::flags::DBFlags {
+            strict_mode: true,
+            mmap_populate: false,
+            direct_writes: false,
+        });
+        assert!(matches!(config.open().err(), Some(Error::InvalidDB(_))));
+
+        DB::repair(&path).unwrap();
+        let db = config.open().unwrap();
+        let stats = db.stats().unwrap();
+        assert!(stats.free_pages > 0);
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert!(bucket.get(b"key0999").unwrap().is_none());
+        assert_eq!(bucket.get(b"key1500").unwrap().unwrap().as_slice(), b"value1500");
+        assert_eq!(bucket.bucket(b"nested").unwrap().get(b"key").unwrap().unwrap().as_slice(), b"value");
+        drop(tx);
+
+        // the rebuilt freelist is usable by later commits
+        let tx = db.tx(true).unwrap();
+        tx.bucket(b"data").unwrap().put("more", "value").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(db.len().unwrap(), 1002);
+    }
+
+    #[test]
+    fn test_recover_freelist_from_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let config = Config::new().path(dir.path()).snapshot_after_ops(1);
+        let write = |db: &DB, range: std::ops::Range<usize>, delete: bool| {
+            let tx = db.tx(true).unwrap();
+            let bucket = match tx.bucket(b"data") {
+                Ok(bucket) => bucket,
+                Err(_) => tx.create_bucket(b"data").unwrap(),
+            };
+            for i in range {
+                match delete {
+                    true => bucket.delete(format!("key{:04}", i).as_bytes()).unwrap(),
+                    false => bucket.put(format!("key{:04}", i), vec![0u8; 100]).unwrap(),
+                }
+            }
+            tx.commit().unwrap();
+        };
+        let corrupt_freelist = |db: DB| {
+            let (freelist_page, pagesize) = (db.context.meta().unwrap().freelist_page, db.pagesize());
+            drop(db);
+            let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+            file.write_all_at(&[0xff; 64], freelist_page * pagesize).unwrap();
+        };
+
+        let db = config.open().unwrap();
+        write(&db, 0..2000, false);
+        write(&db, 0..1000, true);
+        write(&db, 2000..2100, false);
+        let free_pages = db.context.freelist.lock().free_on_open();
+        assert_eq!(db.context.get_snapshot_files().unwrap().len(), 3);
+        corrupt_freelist(db);
+
+        // the base and its deltas add up to the freelist of the last commit
+        let db = config.open().unwrap();
+        assert_eq!(*db.context.freelist.lock().free_pages(), free_pages);
+        write(&db, 2100..2200, false);
+        let tx = db.tx(false).unwrap();
+        assert_eq!(tx.bucket(b"data").unwrap().get(b"key1500").unwrap().unwrap().as_slice(), &[0u8; 100][..]);
+        drop(tx);
+
+        drop(db);
+
+        // snapshots older than the last commit can't stand in for its freelist
+        let config = Config::new().path(dir.path()).snapshot_after_ops(1000);
+        let db = config.open().unwrap();
+        write(&db, 2200..2300, false);
+        corrupt_freelist(db);
+        assert!(matches!(config.open().err(), Some(Error::InvalidDB(_))));
+    }
+
+    #[test]
+    fn test_repair_damaged_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let config = Config::new().path(dir.path()).application_id(0xC0FFEE);
+        let (root_page, num_pages, pagesize) = {
+            let db = config.open().unwrap();
+            let tx = db.tx(true).unwrap();
+            let bucket = tx.create_bucket(b"data").unwrap();
+            for i in 0..2000 {
+                bucket.put(format!("key{:04}", i), format!("value{}", i)).unwrap();
+            }
+            tx.commit().unwrap();
+            let tx = db.tx(false).unwrap();
+            let root_page = match tx.root_bucket().cursor().next_leaf().unwrap() {
+                Some(Leaf::Bucket(_, meta)) => meta.root_page,
+                _ => unreachable!(),
+            };
+            (root_page, tx.meta().num_pages, db.pagesize())
+        };
+        assert!(fs::metadata(&path).unwrap().len() > (num_pages + 10) * pagesize);
+
+        // point the bucket's root branch at a page past num_pages but within the file,
+        // at itself, and twice at the same child
+        let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let elem = |i: u64| root_page * pagesize + Page::HEADER_SIZE + i * size_of::<BranchElement>() as u64;
+        let mut child = [0; 8];
+        file.read_exact_at(&mut child, elem(3)).unwrap();
+        file.write_all_at(&(num_pages + 10).to_ne_bytes(), elem(0)).unwrap();
+        file.write_all_at(&root_page.to_ne_bytes(), elem(1)).unwrap();
+        file.write_all_at(&child, elem(2)).unwrap();
+        drop(file);
+
+        // the id the database was created with is read from its meta
+        DB::repair(&path).unwrap();
+        let db = config.open().unwrap();
+        assert!(db.stats().unwrap().free_pages > 0);
+    }
+
+    #[test]
+    fn test_repair_corrupt_leaf() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let (root_page, pagesize) = {
+            let db = Config::new().path(dir.path()).open().unwrap();
+            let tx = db.tx(true).unwrap();
+            let bucket = tx.create_bucket(b"data").unwrap();
+            for i in 0..2000 {
+                bucket.put(format!("key{:04}", i), format!("value{}", i)).unwrap();
+            }
+            tx.commit().unwrap();
+            let tx = db.tx(false).unwrap();
+            let root_page = match tx.root_bucket().cursor().next_leaf().unwrap() {
+                Some(Leaf::Bucket(_, meta)) => meta.root_page,
+                _ => unreachable!(),
+            };
+            (root_page, db.pagesize())
+        };
+
+        // clobber the header of the bucket's first leaf
+        let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let mut leaf = [0; 8];
+        file.read_exact_at(&mut leaf, root_page * pagesize + Page::HEADER_SIZE).unwrap();
+        let leaf = u64::from_ne_bytes(leaf);
+        file.write_all_at(&[0xff; 64], leaf * pagesize).unwrap();
+        drop(file);
+
+        DB::repair(&path).unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        // the corrupt leaf is still in use, so it's never handed out again
+        assert!(!db.context.freelist.lock().free_pages().contains(&leaf));
+        let tx = db.tx(false).unwrap();
+        assert_eq!(tx.bucket(b"data").unwrap().get(b"key1999").unwrap().unwrap().as_slice(), b"value1999");
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let (meta, pagesize) = {
+            let db = Config::new().path(dir.path()).open().unwrap();
+            let tx = db.tx(true).unwrap();
+            let bucket = tx.create_bucket(b"data").unwrap();
+            for i in 0..1000 {
+                bucket.put(format!("key{:04}", i), format!("value{}", i)).unwrap();
+            }
+            bucket.create_bucket(b"nested").unwrap().put("key", "value").unwrap();
+            tx.commit().unwrap();
+            assert_eq!(db.check().unwrap(), Vec::<String>::new());
+            (db.context.meta().unwrap(), db.pagesize())
+        };
+
+        let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        // tear the older meta page
+        let old_meta = (meta.tx_id + 1) % 2;
+        file.write_all_at(&[0xff; 8], old_meta * pagesize + 64).unwrap();
+        // put a key out of order
+        let contents = fs::read(&path).unwrap();
+        let offset = contents.windows(7).position(|w| w == b"key0000").unwrap();
+        file.write_all_at(b"key9", offset as u64).unwrap();
+        // list the root page as free
+        let first_free = meta.freelist_page * pagesize + crate::page::Page::HEADER_SIZE;
+        file.write_all_at(&meta.root.root_page.to_ne_bytes(), first_free).unwrap();
+        drop(file);
+
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let problems = db.check().unwrap();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].starts_with(&format!("meta page {} is invalid", old_meta)));
+        assert!(problems[1].starts_with("the keys on page"));
+        assert_eq!(problems[2], format!("page {} is both in use and free", meta.root.root_page));
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..20000 {
+            bucket.put(format!("key{:05}", i), vec![b'v'; 100]).unwrap();
+        }
+        let nested = bucket.create_bucket(b"nested").unwrap();
+        nested.put("inner", "value").unwrap();
+        nested.create_bucket(b"deeper").unwrap().put("deepest", "value").unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        for i in (0..20000).filter(|i| i % 10 != 0) {
+            bucket.delete(format!("key{:05}", i).as_bytes()).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let compacted_dir = tempfile::tempdir().unwrap();
+        let compacted = db.compact(compacted_dir.path()).unwrap();
+        let len = |db: &super::DB| db.context.file.lock().metadata().unwrap().len();
+        assert!(len(&compacted) < len(&db));
+
+        let tx = compacted.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        let mut count = 0;
+        bucket
+            .for_each(|key, value| {
+                assert_eq!(key.as_slice(), format!("key{:05}", count * 10).as_bytes());
+                assert_eq!(value.len(), 100);
+                count += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, 2000);
+        let nested = bucket.bucket(b"nested").unwrap();
+        assert_eq!(nested.get(b"inner").unwrap().unwrap().as_slice(), b"value");
+        let deeper = nested.bucket(b"deeper").unwrap();
+        assert_eq!(deeper.get(b"deepest").unwrap().unwrap().as_slice(), b"value");
+
+        drop(tx);
+        // the trimmed file grows again as usual
+        let tx = compacted.tx(true).unwrap();
+        tx.bucket(b"data").unwrap().put("key", vec![b'v'; 100_000]).unwrap();
+        tx.commit().unwrap();
+        assert!(db.compact(compacted_dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..1000 {
+            bucket.put(format!("key{:04}", i), format!("value{}", i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let num_pages = db.context.meta().unwrap().num_pages;
+
+        // a write in progress doesn't hold up the backup, nor end up in it
+        let writer = db.tx(true).unwrap();
+        writer.bucket(b"data").unwrap().put("late", "value").unwrap();
+        let backup = tempfile::tempdir().unwrap();
+        let mut file = std::fs::File::create(backup.path().join("db")).unwrap();
+        assert_eq!(db.backup(&mut file).unwrap(), num_pages * db.pagesize());
+        writer.commit().unwrap();
+        drop(file);
+
+        let restored = Config::new().path(backup.path()).open().unwrap();
+        let tx = restored.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        let mut count = 0;
+        bucket
+            .for_each(|key, value| {
+                assert_eq!(key.as_slice(), format!("key{:04}", count).as_bytes());
+                assert_eq!(value.as_slice(), format!("value{}", count).as_bytes());
+                count += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, 1000);
+    }
+
+    #[test]
+    fn test_backup_since() {
+        use std::io::Read;
+        use std::os::unix::fs::FileExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let put = |range: std::ops::Range<u32>| {
+            let tx = db.tx(true).unwrap();
+            let bucket = match tx.bucket(b"data") {
+                Ok(bucket) => bucket,
+                Err(_) => tx.create_bucket(b"data").unwrap(),
+            };
+            for i in range {
+                bucket.put(format!("key{:05}", i), vec![b'v'; 100]).unwrap();
+            }
+            tx.commit().unwrap();
+        };
+        put(0..1000);
+
+        // a full copy, as a backup would take
+        let backup = tempfile::tempdir().unwrap();
+        std::fs::copy(dir.path().join("db"), backup.path().join("db")).unwrap();
+        std::fs::copy(dir.path().join("conf"), backup.path().join("conf")).unwrap();
+        let full_tx = db.context.meta().unwrap().tx_id;
+
+        put(1000..2000);
+        put(500..600);
+        let mut out = Vec::new();
+        let tx_id = db.backup_since(full_tx, &mut out).unwrap();
+        assert_eq!(tx_id, full_tx + 2);
+        // only what changed is copied
+        let num_pages = db.stats().unwrap().num_pages;
+        assert!((out.len() as u64) < num_pages * db.pagesize());
+
+        let file = std::fs::OpenOptions::new().write(true).open(backup.path().join("db")).unwrap();
+        let mut stream = out.as_slice();
+        let mut word = [0; 8];
+        while stream.read_exact(&mut word).is_ok() {
+            let id = u64::from_le_bytes(word);
+            stream.read_exact(&mut word).unwrap();
+            let mut block = vec![0; (u64::from_le_bytes(word) * db.pagesize()) as usize];
+            stream.read_exact(&mut block).unwrap();
+            file.write_all_at(&block, id * db.pagesize()).unwrap();
+        }
+        drop(file);
+
+        let restored = Config::new().path(backup.path()).open().unwrap();
+        let tx = restored.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        for i in 0..2000 {
+            assert!(bucket.get(format!("key{:05}", i).as_bytes()).unwrap().is_some());
+        }
+
+        // nothing changed since the last backup but the meta pages
+        let mut out = Vec::new();
+        assert_eq!(db.backup_since(tx_id, &mut out).unwrap(), tx_id);
+        assert_eq!(out.len() as u64, 16 + 2 * db.pagesize());
+    }
+
+    #[test]
+    fn test_flusher_survives_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new()
+            .path(dir.path())
+            .flush_every_ms(Some(10))
+            .open()
+            .unwrap();
+        assert!(db.context.flusher.lock().is_some());
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap().put("key", "value").unwrap();
+        tx.commit().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        // Copy the files while the database is still open, as a crash would leave them
+        let crashed = tempfile::tempdir().unwrap();
+        for name in ["db", "conf"] {
+            std::fs::copy(dir.path().join(name), crashed.path().join(name)).unwrap();
+        }
+        let db = Config::new().path(crashed.path()).open().unwrap();
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert_eq!(bucket.get(b"key").unwrap().unwrap().as_slice(), b"value");
+
+        let unflushed = tempfile::tempdir().unwrap();
+        let db = Config::new().path(unflushed.path()).flush_every_ms(None).open().unwrap();
+        assert!(db.context.flusher.lock().is_none());
+    }
+
+    #[test]
+    fn test_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Config::new().path(dir.path()).flush_every_ms(Some(60_000)).open().unwrap();
+            let tx = db.tx(true).unwrap();
+            tx.create_bucket(b"data").unwrap().put("key", "value").unwrap();
+            tx.commit().unwrap();
+            assert!(db.flush().unwrap() >= 2 * db.pagesize() as usize);
+            assert_eq!(db.flush().unwrap(), 0);
+        }
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert_eq!(bucket.get(b"key").unwrap().unwrap().as_slice(), b"value");
+    }
+
+    #[test]
+    fn test_mode_num_pages() {
+        use crate::config::Mode;
+
+        let num_pages = |mode: Mode| {
+            let dir = tempfile::tempdir().unwrap();
+            let db = Config::new().path(dir.path()).mode(mode).open().unwrap();
+            let value = vec![7u8; db.pagesize() as usize * 5 / 2];
+            for round in 0..3 {
+                let tx = db.tx(true).unwrap();
+                let bucket = match round {
+                    0 => tx.create_bucket(b"data").unwrap(),
+                    _ => tx.bucket(b"data").unwrap(),
+                };
+                for i in 0..1000u32 {
+                    match round {
+                        1 if i % 10 != 0 => bucket.delete(&i.to_be_bytes()).unwrap(),
+                        1 => (),
+                        _ if i % 50 == 5 => bucket.put(i.to_be_bytes().to_vec(), value.clone()).unwrap(),
+                        _ => bucket.put(i.to_be_bytes().to_vec(), "small").unwrap(),
+                    }
+                }
+                tx.commit().unwrap();
+                // freed pages are only reused once the commit that freed them is durable
+                db.flush().unwrap();
+            }
+            let tx = db.tx(false).unwrap();
+            let mut cursor = tx.bucket(b"data").unwrap().cursor();
+            let mut count = 0;
+            while cursor.next().unwrap().is_some() {
+                count += 1;
+            }
+            assert_eq!(count, 1000);
+            drop(tx);
+            db.stats().unwrap().num_pages
+        };
+        assert!(num_pages(Mode::LowSpace) < num_pages(Mode::HighThroughput));
+    }
+
+    #[test]
+    fn test_strict_mode_detects_corrupt_page() {
+        use std::os::unix::fs::FileExt;
+
+        use crate::config::flags::DBFlags;
+        use crate::errors::Error;
+
+        let dir = tempfile::tempdir().unwrap();
+        let flags = DBFlags {
+            strict_mode: true,
+            mmap_populate: false,
+            direct_writes: false,
+        };
+        let (root_page, pagesize) = {
+            let db = Config::new().path(dir.path()).flags(flags).open().unwrap();
+            let tx = db.tx(true).unwrap();
+            tx.create_bucket(b"data").unwrap();
+            tx.commit().unwrap();
+            (db.context.meta().unwrap().root.root_page, db.pagesize())
+        };
+
+        // Claim far more elements than the root leaf can hold
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(Config::new().path(dir.path()).db_path())
+            .unwrap();
+        file.write_all_at(&(1u64 << 40).to_le_bytes(), root_page * pagesize + 16).unwrap();
+        drop(file);
+
+        let db = Config::new().path(dir.path()).flags(flags).open().unwrap();
+        let tx = db.tx(false).unwrap();
+        assert!(matches!(tx.bucket(b"data").err(), Some(Error::InvalidDB(_))));
+    }
+
+    #[test]
+    fn test_user_version() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Config::new().path(dir.path()).open().unwrap();
+            assert_eq!(db.user_version().unwrap(), 0);
+            let tx_id = db.stats().unwrap().tx_id;
+            db.set_user_version(7).unwrap();
+            assert_eq!(db.user_version().unwrap(), 7);
+            assert_eq!(db.stats().unwrap().tx_id, tx_id + 1);
+            // later commits carry the version along
+            let tx = db.tx(true).unwrap();
+            tx.create_bucket(b"data").unwrap();
+            tx.commit().unwrap();
+        }
+        let db = Config::new().path(dir.path()).open().unwrap();
+        assert_eq!(db.user_version().unwrap(), 7);
+    }
+}
@@ -1,5 +1,5 @@
 pub const DATABASE_INTEGRITY_CODE: u32 = 0x00ABCDEF;
-pub const VERSION: u32 = 1;
+pub const VERSION: u32 = 3;
 // Minimum number of bytes to allocate when growing the databse
 pub(crate) const MIN_ALLOC_SIZE: u64 = 8 * 1024 * 1024;
 
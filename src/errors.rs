@@ -25,6 +25,37 @@ pub enum Error {
 
     /// Unsupported operation
     Unsupported(&'static str),
+    /// Timed out waiting to acquire a lock
+    Timeout,
+    /// Failed to map the database file into memory again after growing it.
+    /// The previous mapping is still in use, so the database remains readable.
+    Remap(String),
+    /// A typed key or value could not be serialized or deserialized
+    Encoding(String),
+    /// The config has a setting that can't be used, with a description of what would work instead
+    InvalidConfig(String),
+    /// Tried to put a value larger than the configured `max_value_size`, with the value's size and the limit
+    ValueTooLarge(usize, usize),
+    /// A put would take the keys and values buffered by the transaction past the configured
+    /// `max_tx_dirty_bytes`, with the bytes they would take up and the limit
+    TxTooLarge(usize, usize),
+}
+
+impl Error {
+    /// Creates an [`Error::Io`] from an error kind and a short description of what failed.
+    pub fn io(kind: std::io::ErrorKind, msg: &'static str) -> Error {
+        Error::Io(kind, msg)
+    }
+
+    /// Creates an [`Error::InvalidDB`] describing why the database is invalid.
+    pub fn invalid_db(msg: impl Into<String>) -> Error {
+        Error::InvalidDB(msg.into())
+    }
+
+    /// Returns true if the error is caused by a bucket or key / value pair that does not exist.
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Error::BucketMissing | Error::KeyValueMissing)
+    }
 }
 
 impl StdError for Error {}
@@ -37,18 +68,28 @@ impl fmt::Display for Error {
             Error::KeyValueMissing => write!(f, "Key / Value pair does not exist"),
             Error::IncompatibleValue => write!(f, "Value not compatible"),
             Error::ReadOnlyTx => write!(f, "Cannot write in a read-only transaction"),
-            Error::Io(e) => write!(f, "IO Error: {}", e),
+            Error::Io(kind, context) => write!(f, "IO Error: {}: {}", context, kind),
             Error::Sync(s) => write!(f, "Sync Error: {}", s),
             Error::InvalidDB(s) => write!(f, "Invalid DB: {}", s),
             Error::Alloc(e) => write!(f, "Allocation error: {}", e),
             Error::Unsupported(s) => write!(f, "Unsupported operation: {}", s),
+            Error::Timeout => write!(f, "Timed out waiting for a lock"),
+            Error::Remap(s) => write!(f, "Failed to remap the database file: {}", s),
+            Error::Encoding(s) => write!(f, "Encoding error: {}", s),
+            Error::InvalidConfig(s) => write!(f, "Invalid config: {}", s),
+            Error::ValueTooLarge(size, max) => {
+                write!(f, "Value of {} bytes is larger than the maximum of {} bytes", size, max)
+            }
+            Error::TxTooLarge(size, max) => {
+                write!(f, "Transaction would buffer {} bytes, more than the maximum of {} bytes", size, max)
+            }
         }
     }
 }
 
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Error {
-        Error::Io(err)
+        Error::Io(err.kind(), "I/O operation failed")
     }
 }
 
@@ -72,10 +113,47 @@ impl PartialEq for Error {
             (Error::KeyValueMissing, Error::KeyValueMissing) => true,
             (Error::IncompatibleValue, Error::IncompatibleValue) => true,
             (Error::ReadOnlyTx, Error::ReadOnlyTx) => true,
+            (Error::Io(k1, s1), Error::Io(k2, s2)) => k1 == k2 && s1 == s2,
             (Error::Sync(s1), Error::Sync(s2)) => s1 == s2,
             (Error::InvalidDB(s1), Error::InvalidDB(s2)) => s1 == s2,
+            (Error::Alloc(e1), Error::Alloc(e2)) => e1 == e2,
             (Error::Unsupported(s1), Error::Unsupported(s2)) => s1 == s2,
+            (Error::Timeout, Error::Timeout) => true,
+            (Error::Remap(s1), Error::Remap(s2)) => s1 == s2,
+            (Error::Encoding(s1), Error::Encoding(s2)) => s1 == s2,
+            (Error::InvalidConfig(s1), Error::InvalidConfig(s2)) => s1 == s2,
+            (Error::ValueTooLarge(a1, b1), Error::ValueTooLarge(a2, b2)) => a1 == a2 && b1 == b2,
+            (Error::TxTooLarge(a1, b1), Error::TxTooLarge(a2, b2)) => a1 == a2 && b1 == b2,
             _ => false,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constructors() {
+        assert_eq!(Error::invalid_db("bad meta"), Error::InvalidDB("bad meta".to_string()));
+        assert!(matches!(
+            Error::io(std::io::ErrorKind::NotFound, "missing file"),
+            Error::Io(std::io::ErrorKind::NotFound, "missing file")
+        ));
+        assert!(Error::BucketMissing.is_not_found());
+        assert!(Error::KeyValueMissing.is_not_found());
+        assert!(!Error::BucketExists.is_not_found());
+    }
+
+    #[test]
+    fn test_from_io_error() {
+        let io_err = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "no access");
+        let err = Error::from(io_err);
+        assert_eq!(err, Error::io(std::io::ErrorKind::PermissionDenied, "I/O operation failed"));
+        assert_ne!(err, Error::io(std::io::ErrorKind::NotFound, "I/O operation failed"));
+        assert_eq!(err.to_string(), "IO Error: I/O operation failed: permission denied");
+
+        let layout_err = std::alloc::Layout::from_size_align(8, 3).unwrap_err();
+        assert_eq!(Error::from(layout_err.clone()), Error::Alloc(layout_err));
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt::Debug;
+
+use parking_lot::Mutex;
+
+/// Receives notifications about what the database is doing, for debugging
+/// and tracing. Every method does nothing by default.
+///
+/// Handlers are called on the thread running the transaction, while it holds
+/// its locks, so they should return quickly.
+pub trait EventLog: Debug + Send + Sync {
+    /// A transaction started, reading the state committed by `tx_id`,
+    /// or writing `tx_id` if it is writable.
+    fn on_tx_begin(&self, _tx_id: u64) {}
+
+    /// The writable transaction `tx_id` committed, writing `pages_written` pages.
+    fn on_commit(&self, _tx_id: u64, _pages_written: u64) {}
+
+    /// A commit freed `page_id`, which can be reused once no reader needs it.
+    fn on_page_free(&self, _page_id: u64) {}
+
+    /// A commit synced the database file, possibly on behalf of other commits too.
+    fn on_sync(&self) {}
+}
+
+/// An [`EventLog`] that ignores every event. This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopEventLog;
+
+impl EventLog for NoopEventLog {}
+
+/// An event recorded by [`VecEventLog`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    TxBegin(u64),
+    Commit { tx_id: u64, pages_written: u64 },
+    PageFree(u64),
+    Sync,
+}
+
+/// An [`EventLog`] that keeps every event in memory, which is useful in tests.
+#[derive(Debug, Default)]
+pub struct VecEventLog {
+    events: Mutex<Vec<Event>>,
+}
+
+impl VecEventLog {
+    pub fn new() -> VecEventLog {
+        VecEventLog::default()
+    }
+
+    /// Returns the events recorded so far, oldest first.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().clone()
+    }
+}
+
+impl EventLog for VecEventLog {
+    fn on_tx_begin(&self, tx_id: u64) {
+        self.events.lock().push(Event::TxBegin(tx_id));
+    }
+
+    fn on_commit(&self, tx_id: u64, pages_written: u64) {
+        self.events.lock().push(Event::Commit { tx_id, pages_written });
+    }
+
+    fn on_page_free(&self, page_id: u64) {
+        self.events.lock().push(Event::PageFree(page_id));
+    }
+
+    fn on_sync(&self) {
+        self.events.lock().push(Event::Sync);
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::{ErrorKind, Read, Write};
+
+use crate::bucket::Bucket;
+use crate::config::Config;
+use crate::db::DB;
+use crate::errors::{Error, Result};
+use crate::node::Leaf;
+
+const MAGIC: &[u8; 8] = b"memdbexp";
+const FORMAT_VERSION: u32 = 1;
+
+// Each record starts with one of these tags
+const TAG_END: u8 = 0;
+const TAG_PAIR: u8 = 1;
+const TAG_BUCKET: u8 = 2;
+
+impl DB {
+    /// Writes every bucket and key / value pair to `out` in a portable format,
+    /// to be read back by [`DB::import`].
+    ///
+    /// Unlike a [`DB::backup`], the export doesn't depend on the pagesize or the
+    /// byte order of the host, so it can move data between hosts that differ in
+    /// either. It starts with a magic string and a format version. Each bucket's
+    /// records follow in key order: a pair, or a nested bucket followed by its own
+    /// records, then a record marking the end of the bucket. Keys, values and
+    /// names are prefixed by their length as a big endian `u64`.
+    pub fn export<W: Write>(&self, out: &mut W) -> Result<()> {
+        let tx = self.tx(false)?;
+        out.write_all(MAGIC)?;
+        out.write_all(&FORMAT_VERSION.to_be_bytes())?;
+        export_bucket(&tx.root_bucket(), out)
+    }
+
+    /// Opens a database with `config` and fills it with an export written by
+    /// [`DB::export`], in a single transaction.
+    ///
+    /// Returns [`Error::InvalidDB`] if `input` isn't an export this version can
+    /// read, and [`Error::BucketExists`] if the database already has one of its buckets.
+    pub fn import<R: Read>(config: &Config, input: &mut R) -> Result<DB> {
+        let mut header = [0; MAGIC.len() + 4];
+        read_exact(input, &mut header)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidDB("not an export".to_string()));
+        }
+        let version = u32::from_be_bytes(header[MAGIC.len()..].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(Error::InvalidDB(format!("unsupported export format version {}", version)));
+        }
+
+        let db = config.open()?;
+        let tx = db.tx(true)?;
+        import_bucket(&tx.root_bucket(), input)?;
+        tx.commit()?;
+        Ok(db)
+    }
+}
+
+fn export_bucket<W: Write>(bucket: &Bucket, out: &mut W) -> Result<()> {
+    let mut cursor = bucket.cursor();
+    while let Some(leaf) = cursor.next_leaf()? {
+        match leaf {
+            Leaf::Kv(key, value) => {
+                out.write_all(&[TAG_PAIR])?;
+                write_bytes(out, &key)?;
+                write_bytes(out, &value)?;
+            }
+            Leaf::Bucket(name, _) => {
+                out.write_all(&[TAG_BUCKET])?;
+                write_bytes(out, &name)?;
+                export_bucket(&bucket.bucket(&name)?, out)?;
+            }
+        }
+    }
+    out.write_all(&[TAG_END])?;
+    Ok(())
+}
+
+fn import_bucket<R: Read>(bucket: &Bucket, input: &mut R) -> Result<()> {
+    loop {
+        let mut tag = [0];
+        read_exact(input, &mut tag)?;
+        match tag[0] {
+            TAG_END => return Ok(()),
+            TAG_PAIR => {
+                let key = read_bytes(input)?;
+                let value = read_bytes(input)?;
+                bucket.put(key, value)?;
+            }
+            TAG_BUCKET => {
+                let name = read_bytes(input)?;
+                import_bucket(&bucket.create_bucket(&name)?, input)?;
+            }
+            tag => return Err(Error::InvalidDB(format!("unknown record tag {} in export", tag))),
+        }
+    }
+}
+
+fn write_bytes<W: Write>(out: &mut W, bytes: &[u8]) -> Result<()> {
+    out.write_all(&(bytes.len() as u64).to_be_bytes())?;
+    out.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_bytes<R: Read>(input: &mut R) -> Result<Vec<u8>> {
+    let mut len = [0; 8];
+    read_exact(input, &mut len)?;
+    let len = u64::from_be_bytes(len);
+    // Read through `take`, so a corrupt length can't allocate more than the input holds
+    let mut bytes = Vec::new();
+    input.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(Error::InvalidDB("export is truncated".to_string()));
+    }
+    Ok(bytes)
+}
+
+fn read_exact<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<()> {
+    match input.read_exact(buf) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(Error::InvalidDB("export is truncated".to_string())),
+        result => Ok(result?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let large = vec![9u8; 10 * db.pagesize() as usize];
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..3000 {
+            bucket.put(format!("key{:04}", i), format!("value{}", i)).unwrap();
+        }
+        bucket.put("large", large.clone()).unwrap();
+        bucket.put("empty", "").unwrap();
+        let nested = bucket.create_bucket(b"nested").unwrap();
+        nested.put("key", "value").unwrap();
+        nested.create_bucket(b"deeper").unwrap();
+        tx.create_bucket(b"other").unwrap().put("a", "b").unwrap();
+        tx.commit().unwrap();
+
+        let mut export = Vec::new();
+        db.export(&mut export).unwrap();
+        let other_dir = tempfile::tempdir().unwrap();
+        let config = Config::new().path(other_dir.path()).segment_size(1 << 20);
+        let imported = DB::import(&config, &mut export.as_slice()).unwrap();
+
+        let tx = imported.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        assert_eq!(bucket.get(b"key1234").unwrap().unwrap().as_slice(), b"value1234");
+        assert_eq!(bucket.get(b"large").unwrap().unwrap().as_slice(), &large[..]);
+        assert_eq!(bucket.get(b"empty").unwrap().unwrap().as_slice(), b"");
+        assert!(bucket.bucket(b"nested").unwrap().bucket(b"deeper").is_ok());
+        drop(tx);
+        // the import holds exactly what was exported
+        let mut again = Vec::new();
+        imported.export(&mut again).unwrap();
+        assert_eq!(again, export);
+        assert_eq!(imported.len().unwrap(), db.len().unwrap());
+
+        // importing the same buckets twice fails
+        drop(imported);
+        assert_eq!(DB::import(&config, &mut export.as_slice()).err(), Some(Error::BucketExists));
+
+        let fresh = tempfile::tempdir().unwrap();
+        let config = Config::new().path(fresh.path());
+        let truncated = &export[..export.len() / 2];
+        assert!(matches!(DB::import(&config, &mut &truncated[..]), Err(Error::InvalidDB(_))));
+        assert!(matches!(DB::import(&config, &mut &b"not an export"[..]), Err(Error::InvalidDB(_))));
+    }
+}
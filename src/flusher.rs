@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error};
+use parking_lot::{Condvar, Mutex};
+
+use crate::config::running_config::RunningConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShutdownState {
+    Running,
+    ShuttingDown,
+}
+
+/// Syncs the db file every `flush_every_ms` milliseconds, so commits that
+/// skipped their own fsync become durable shortly after.
+#[derive(Debug)]
+pub(crate) struct Flusher {
+    shutdown: Arc<Mutex<ShutdownState>>,
+    sc: Arc<Condvar>,
+    join_handle: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Flusher {
+    /// Spawns a thread that syncs the db file every `flush_every_ms` milliseconds.
+    pub(crate) fn new(name: String, config: Arc<RunningConfig>, flush_every_ms: u64) -> Flusher {
+        let shutdown = Arc::new(Mutex::new(ShutdownState::Running));
+        let sc = Arc::new(Condvar::new());
+
+        let join_handle = thread::Builder::new()
+            .name(name)
+            .spawn({
+                let shutdown = shutdown.clone();
+                let sc = sc.clone();
+                move || run(&shutdown, &sc, &config, flush_every_ms)
+            })
+            .unwrap();
+
+        Flusher {
+            shutdown,
+            sc,
+            join_handle: Mutex::new(Some(join_handle)),
+        }
+    }
+}
+
+fn run(
+    shutdown: &Mutex<ShutdownState>,
+    sc: &Condvar,
+    config: &RunningConfig,
+    flush_every_ms: u64,
+) {
+    let interval = Duration::from_millis(flush_every_ms);
+    loop {
+        let mut state = shutdown.lock();
+        if *state == ShutdownState::Running {
+            // Waiting releases the lock, so nothing is held while we sleep
+            let _ = sc.wait_for(&mut state, interval);
+        }
+        let shutting_down = *state == ShutdownState::ShuttingDown;
+        drop(state);
+
+        if let Err(e) = config.flush() {
+            error!("failed to flush the db file: {}", e);
+            config.flush_failed(&e);
+        }
+        if shutting_down {
+            debug!("flusher shutting down");
+            return;
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        *self.shutdown.lock() = ShutdownState::ShuttingDown;
+        self.sc.notify_all();
+        if let Some(join_handle) = self.join_handle.lock().take() {
+            if join_handle.join().is_err() {
+                error!("error joining flusher thread");
+            }
+        }
+    }
+}
@@ -1,8 +1,16 @@
+use std::alloc::Layout;
 use std::collections::{BTreeMap, BTreeSet};
-use std::ptr::NonNull;
+use std::mem::{align_of, size_of};
+use std::ptr::{copy_nonoverlapping, NonNull};
+use std::slice::from_raw_parts;
 
+use bumpalo::Bump;
+
+use crate::config::Mode;
+use crate::errors::Result;
 use crate::meta::Meta;
-use crate::page::PageID;
+use crate::page::{Page, PageID};
+use crate::transaction::TxStats;
 
 #[derive(Clone)]
 pub(crate) struct Freelist {
@@ -24,11 +32,410 @@ impl Freelist {
             self.free_pages.insert(*id);
         });
     }
+
+    /// Takes `count` contiguous free pages, returning the id of the first one,
+    /// or `None` if no run of free pages is long enough.
+    ///
+    /// [`Mode::LowSpace`] takes the lowest run that fits, so the end of the file
+    /// stays free and can be truncated. [`Mode::HighThroughput`] only reuses
+    /// single pages and leaves runs to be appended, rather than scanning
+    /// a fragmented freelist.
+    pub(crate) fn allocate(&mut self, count: u64, mode: Mode) -> Option<PageID> {
+        if count == 1 {
+            return self.free_pages.pop_first();
+        }
+        if matches!(mode, Mode::HighThroughput) {
+            return None;
+        }
+        let mut start = 0;
+        let mut len = 0;
+        for id in self.free_pages.iter() {
+            if len > 0 && *id == start + len {
+                len += 1;
+            } else {
+                start = *id;
+                len = 1;
+            }
+            if len == count {
+                break;
+            }
+        }
+        if count == 0 || len < count {
+            return None;
+        }
+        (start..start + count).for_each(|id| {
+            self.free_pages.remove(&id);
+        });
+        Some(start)
+    }
+
+    /// Reads a freelist written by [`Freelist::write`].
+    pub(crate) fn read(page: &Page) -> Freelist {
+        let mut freelist = Freelist::new();
+        freelist.init(page.freelist());
+        freelist
+    }
+
+    /// Number of bytes [`Freelist::write`] needs, header included.
+    pub(crate) fn size(&self) -> u64 {
+        let count = self.free_pages.len() + self.pending_count();
+        Page::HEADER_SIZE + (count * size_of::<PageID>()) as u64
+    }
+
+    /// Writes the free pages to `page` as a sorted array of ids, continuing into its
+    /// overflow pages. Pending pages are written as free: once the file is reopened
+    /// no reader can still be using them.
+    pub(crate) fn write(&self, page: &mut Page) {
+        let mut ids: Vec<PageID> = self.free_pages.iter().copied().collect();
+        self.pending_pages.values().for_each(|pages| ids.extend_from_slice(pages));
+        ids.sort_unstable();
+
+        page.page_type = Page::TYPE_FREELIST;
+        page.count = ids.len() as u64;
+        let start = &mut page.ptr as *mut u64 as *mut PageID;
+        unsafe { copy_nonoverlapping(ids.as_ptr(), start, ids.len()) };
+    }
+
+    pub(crate) fn free_pages(&self) -> &BTreeSet<PageID> {
+        &self.free_pages
+    }
+
+    /// The pages that are free once the database is reopened, as [`Freelist::write`]
+    /// records them: the free pages and the pending ones.
+    pub(crate) fn free_on_open(&self) -> BTreeSet<PageID> {
+        let mut pages = self.free_pages.clone();
+        self.pending_pages.values().for_each(|pending| pages.extend(pending));
+        pages
+    }
+
+    /// Records pages freed by the transaction `tx_id`. They stay pending until
+    /// [`Freelist::release`] is sure no reader can still see them.
+    pub(crate) fn free(&mut self, tx_id: u64, pages: &[PageID]) {
+        if pages.is_empty() {
+            return;
+        }
+        self.pending_pages
+            .entry(tx_id)
+            .or_default()
+            .extend_from_slice(pages);
+    }
+
+    /// Returns the pages freed by the transaction `tx_id` that are still pending.
+    pub(crate) fn pending(&self, tx_id: u64) -> &[PageID] {
+        self.pending_pages.get(&tx_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Makes the pages freed by transactions before `up_to_tx` available for reuse.
+    ///
+    /// `up_to_tx` must be no newer than the oldest open read-only transaction,
+    /// which may still be reading pages freed after it started.
+    pub(crate) fn release(&mut self, up_to_tx: u64) {
+        let pending = self.pending_pages.split_off(&up_to_tx);
+        let released = std::mem::replace(&mut self.pending_pages, pending);
+        for pages in released.into_values() {
+            self.free_pages.extend(pages);
+        }
+    }
+
+    /// Number of pages freed by transactions that readers may still be using.
+    pub(crate) fn pending_count(&self) -> usize {
+        self.pending_pages.values().map(Vec::len).sum()
+    }
+
+    /// Removes the contiguous run of free pages ending just below `num_pages`,
+    /// returning the new number of pages in the file.
+    pub(crate) fn truncate(&mut self, num_pages: PageID) -> PageID {
+        let mut new_num_pages = num_pages;
+        while new_num_pages > 0 && self.free_pages.remove(&(new_num_pages - 1)) {
+            new_num_pages -= 1;
+        }
+        new_num_pages
+    }
 }
 
 pub(crate) struct TxFreelist {
     pub(crate) meta: Meta,
     pub(crate) inner: Freelist,
+    // Buffers for the pages written by this transaction, keyed by the id of their first page.
+    // They point into `arena`, so they are all freed at once when the transaction ends.
     pub(crate) pages: BTreeMap<u64, (NonNull<u8>, usize)>,
-    // pub(crate) arena: Bump,
-}
\ No newline at end of file
+    arena: Bump,
+    // Alignment of page buffers, which direct writes require to match the filesystem block size
+    align: usize,
+    // Write-side counters for the transaction that owns this freelist
+    pub(crate) stats: TxStats,
+    // Pages no longer used once this transaction commits
+    pub(crate) freed: Vec<PageID>,
+    pub(crate) mode: Mode,
+    // Fraction of a page that leaves are filled to when nodes are split
+    pub(crate) fill_percent: f32,
+    // Largest value that can be put in this transaction
+    pub(crate) max_value_size: usize,
+    // Bytes of the keys and values put in this transaction, which can't exceed `max_dirty_bytes`
+    pub(crate) dirty_bytes: usize,
+    pub(crate) max_dirty_bytes: usize,
+}
+
+impl TxFreelist {
+    pub(crate) fn new(
+        meta: Meta,
+        inner: Freelist,
+        align: usize,
+        mode: Mode,
+        fill_percent: f32,
+        max_value_size: usize,
+        max_dirty_bytes: usize,
+    ) -> TxFreelist {
+        TxFreelist {
+            meta,
+            inner,
+            pages: BTreeMap::new(),
+            arena: Bump::new(),
+            align: align.max(align_of::<Page>()),
+            stats: TxStats::default(),
+            freed: Vec::new(),
+            mode,
+            fill_percent,
+            max_value_size,
+            dirty_bytes: 0,
+            max_dirty_bytes,
+        }
+    }
+
+    /// Allocates a zeroed block of pages large enough to hold `bytes`,
+    /// returning its first page with `id`, `overflow` and `tx_id` filled in.
+    pub(crate) fn allocate(&mut self, bytes: u64) -> Result<&mut Page> {
+        let pagesize = self.meta.pagesize;
+        let num_pages = bytes.div_ceil(pagesize).max(1);
+        let page_id = match self.inner.allocate(num_pages, self.mode) {
+            Some(page_id) => page_id,
+            None => {
+                let page_id = self.meta.num_pages;
+                self.meta.num_pages += num_pages;
+                page_id
+            }
+        };
+
+        let size = (num_pages * pagesize) as usize;
+        let layout = Layout::from_size_align(size, self.align)?;
+        let ptr = self.arena.alloc_layout(layout);
+        unsafe { ptr.as_ptr().write_bytes(0, size) };
+        self.pages.insert(page_id, (ptr, size));
+        self.stats.overflow_pages += num_pages - 1;
+        self.stats.pages_allocated += num_pages;
+
+        #[allow(clippy::cast_ptr_alignment)]
+        let page = unsafe { &mut *(ptr.as_ptr() as *mut Page) };
+        page.id = page_id;
+        page.overflow = num_pages - 1;
+        page.tx_id = self.meta.tx_id;
+        Ok(page)
+    }
+
+    /// Marks the block of `num_pages` pages starting at `page_id` as no longer used.
+    pub(crate) fn free(&mut self, page_id: PageID, num_pages: u64) {
+        self.freed.extend(page_id..page_id + num_pages);
+        self.stats.pages_freed += num_pages;
+    }
+
+    /// Frees the `num_pages` freelist pages at `old_page` and writes the freelist,
+    /// with this transaction's freed pages pending, to newly allocated pages.
+    /// Returns the id of the new freelist page.
+    pub(crate) fn write_freelist(&mut self, tx_id: u64, old_page: PageID, num_pages: u64) -> Result<PageID> {
+        self.free(old_page, num_pages);
+        let freed = std::mem::take(&mut self.freed);
+        self.inner.free(tx_id, &freed);
+
+        // Allocating only takes pages off the freelist, so it can only shrink
+        let page = self.allocate(self.inner.size())? as *mut Page;
+        let page = unsafe { &mut *page };
+        self.inner.write(page);
+        Ok(page.id)
+    }
+
+    /// Iterates over the pages allocated by this transaction as
+    /// `(first page id, bytes)` in page order, ready to be written out.
+    pub(crate) fn page_buffers(&self) -> impl Iterator<Item=(PageID, &[u8])> {
+        self.pages
+            .iter()
+            .map(|(id, (ptr, size))| (*id, unsafe { from_raw_parts(ptr.as_ptr() as *const u8, *size) }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bucket::BucketMeta;
+
+    use super::*;
+
+    fn tx_freelist(num_pages: u64, align: usize) -> TxFreelist {
+        let meta = Meta {
+            meta_page: 0,
+            integrity_code: 0,
+            version: 0,
+            pagesize: 1024,
+            root: BucketMeta::default(),
+            num_pages,
+            freelist_page: 2,
+            tx_id: 0,
+            user_version: 0,
+            hash: [0; 32],
+        };
+        TxFreelist::new(meta, Freelist::new(), align, Mode::LowSpace, 1.0, usize::MAX, usize::MAX)
+    }
+
+    #[test]
+    fn test_tx_freelist_allocate() {
+        let mut freelist = tx_freelist(4, 8);
+        let page = freelist.allocate(100).unwrap();
+        assert_eq!((page.id, page.overflow), (4, 0));
+        assert_eq!(page as *const Page as usize % align_of::<Page>(), 0);
+        page.page_type = Page::TYPE_LEAF;
+
+        let page = freelist.allocate(3000).unwrap();
+        assert_eq!((page.id, page.overflow), (5, 2));
+        assert_eq!(freelist.meta.num_pages, 8);
+        assert_eq!(freelist.stats.overflow_pages, 2);
+
+        // "commit": every buffer is visible, in page order, with what was written to it
+        let buffers: Vec<(PageID, &[u8])> = freelist.page_buffers().collect();
+        assert_eq!(buffers.len(), 2);
+        assert_eq!((buffers[0].0, buffers[0].1.len()), (4, 1024));
+        assert_eq!((buffers[1].0, buffers[1].1.len()), (5, 3072));
+        assert_eq!(Page::from_buf(buffers[0].1, 0, 1024).page_type, Page::TYPE_LEAF);
+        assert!(buffers[1].1[size_of::<Page>()..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_tx_freelist_block_alignment() {
+        let mut freelist = tx_freelist(4, 4096);
+        for bytes in [100, 5000] {
+            let page = freelist.allocate(bytes).unwrap();
+            assert_eq!(page as *const Page as usize % 4096, 0);
+        }
+    }
+
+    #[test]
+    fn test_tx_freelist_write_leaf() {
+        use crate::bytes::Bytes;
+        use crate::node::{Leaf, NodeData};
+
+        let mut freelist = tx_freelist(4, 8);
+        let leaves = NodeData::Leaves(vec![
+            Leaf::Kv(Bytes::Slice(b"a"), Bytes::Slice(b"1")),
+            Leaf::Kv(Bytes::Slice(b"b"), Bytes::Slice(&[2; 2000])),
+        ]);
+        let page = freelist.allocate(leaves.size(0..2)).unwrap();
+        leaves.write(0..2, page);
+
+        let (id, buf) = freelist.page_buffers().next().unwrap();
+        assert_eq!((id, buf.len()), (4, 3072));
+        match NodeData::from_page(Page::from_buf(buf, 0, 1024)).unwrap() {
+            NodeData::Leaves(read) => {
+                assert_eq!(read.len(), 2);
+                assert!(matches!(&read[1], Leaf::Kv(k, v) if k.as_ref() == b"b" && v.as_ref() == [2; 2000]));
+            }
+            NodeData::Branches(_) => panic!("expected a leaf page"),
+        }
+    }
+
+    #[test]
+    fn test_tx_freelist_rollback() {
+        let mut freelist = tx_freelist(4, 8);
+        for _ in 0..64 {
+            freelist.allocate(1024).unwrap();
+        }
+        // "rollback": dropping the freelist frees every buffer through the arena
+        drop(freelist);
+    }
+
+    #[test]
+    fn test_allocate() {
+        let mut freelist = Freelist::new();
+        freelist.init(&[3, 5, 6, 7, 9]);
+        assert_eq!(freelist.allocate(1, Mode::LowSpace), Some(3));
+        assert_eq!(freelist.allocate(3, Mode::LowSpace), Some(5));
+        assert_eq!(freelist.free_pages.iter().copied().collect::<Vec<_>>(), vec![9]);
+        assert_eq!(freelist.allocate(1, Mode::LowSpace), Some(9));
+        assert_eq!(freelist.allocate(1, Mode::LowSpace), None);
+    }
+
+    #[test]
+    fn test_allocate_fragmented() {
+        let mut freelist = Freelist::new();
+        freelist.init(&[2, 4, 5, 7, 8, 10]);
+        assert_eq!(freelist.allocate(3, Mode::LowSpace), None);
+        // nothing is taken when the allocation fails
+        assert_eq!(freelist.free_pages.len(), 6);
+        assert_eq!(freelist.allocate(2, Mode::LowSpace), Some(4));
+    }
+
+    #[test]
+    fn test_allocate_high_throughput() {
+        let mut freelist = Freelist::new();
+        freelist.init(&[3, 5, 6, 7]);
+        assert_eq!(freelist.allocate(2, Mode::HighThroughput), None);
+        assert_eq!(freelist.allocate(1, Mode::HighThroughput), Some(3));
+        assert_eq!(freelist.allocate(2, Mode::LowSpace), Some(5));
+    }
+
+    #[test]
+    fn test_tx_freelist_reuses_free_pages() {
+        let mut freelist = tx_freelist(16, 8);
+        freelist.inner.init(&[4, 5, 9]);
+        let page = freelist.allocate(2048).unwrap();
+        assert_eq!((page.id, page.overflow), (4, 1));
+        // no run of 3 is free, so the file has to grow
+        let page = freelist.allocate(3072).unwrap();
+        assert_eq!(page.id, 16);
+        assert_eq!(freelist.meta.num_pages, 19);
+        assert_eq!(freelist.allocate(1).unwrap().id, 9);
+    }
+
+    #[test]
+    fn test_release_holds_back_pages_readers_can_see() {
+        let mut freelist = Freelist::new();
+        freelist.free(4, &[10, 11]);
+        freelist.free(6, &[12]);
+        assert_eq!(freelist.pending_count(), 3);
+
+        // a reader at tx 5 can still see the pages freed at tx 6
+        freelist.release(5);
+        assert_eq!(freelist.free_pages.iter().copied().collect::<Vec<_>>(), vec![10, 11]);
+        assert_eq!(freelist.pending_count(), 1);
+
+        freelist.release(7);
+        assert_eq!(freelist.free_pages.len(), 3);
+        assert_eq!(freelist.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_write_read_multiple_pages() {
+        let mut freelist = tx_freelist(1000, 8);
+        let free: Vec<PageID> = (10..400).step_by(2).collect();
+        freelist.inner.init(&free[..150]);
+        freelist.inner.free(3, &free[150..]);
+
+        let page_id = freelist.write_freelist(4, 2, 1).unwrap();
+        let (_, buf) = freelist.page_buffers().find(|(id, _)| *id == page_id).unwrap();
+        let page = Page::from_buf(buf, 0, 1024);
+        assert!(page.overflow > 0);
+        assert_eq!(page.page_type, Page::TYPE_FREELIST);
+
+        let read = Freelist::read(page);
+        let mut expected = free.clone();
+        expected.push(2);
+        expected.sort_unstable();
+        assert_eq!(read.free_pages.iter().copied().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut freelist = Freelist::new();
+        freelist.init(&[3, 5, 7, 8, 9]);
+        assert_eq!(freelist.truncate(10), 7);
+        assert_eq!(freelist.free_pages.iter().copied().collect::<Vec<_>>(), vec![3, 5]);
+        // the last page isn't free, so nothing can be removed
+        assert_eq!(freelist.truncate(7), 7);
+    }
+}
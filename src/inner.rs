@@ -1,40 +1,93 @@
-use std::fmt::Debug;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::config::{Config, Mode};
+use crate::config::{Codec, Config, MmapAdvice, Mode};
 use crate::config::flags::DBFlags;
+use crate::defaults::{DATABASE_INTEGRITY_CODE, DEFAULT_NUM_PAGES};
+use crate::errors::Error;
+use crate::event_log::{EventLog, NoopEventLog};
+use crate::metrics::{Metrics, NoopMetrics};
+use crate::node::Comparator;
 
 const DEFAULT_PATH: &str = "default.db";
 
 #[derive(Debug, Clone)]
-pub(crate) struct Inner {
+pub struct Inner {
     pub cache_capacity: usize,
     pub flush_every_ms: Option<u64>,
     pub segment_size: usize,
     pub mode: Mode,
+    pub fill_percent: f32,
+    pub max_value_size: usize,
+    pub max_tx_dirty_bytes: usize,
+    pub scan_prefetch: usize,
+    pub initial_pages: usize,
+    pub lock_timeout: Option<Duration>,
     pub path: PathBuf,
     pub temporary: bool,
-    tmp_path: PathBuf,
+    // Generated when the database is made temporary, and only used without a path of its own
+    pub(crate) tmp_path: Option<PathBuf>,
+    // Where a temporary database's directory is moved to when it's closed, rather than removed
+    pub(crate) persist_path: Option<PathBuf>,
     pub create_new: bool,
+    pub read_only: bool,
+    // Set by `Config::open_in_memory`, for a database with no file to sync or snapshot next to
+    pub(crate) in_memory: bool,
     pub snapshot_after_ops: u64,
     pub version: (usize, usize),
-    // TODO: Event log handler for debugging
+    pub application_id: u32,
+    pub mmap_advise: MmapAdvice,
+    pub use_compression: bool,
+    pub compression: Option<Codec>,
+    pub(crate) on_flush_error: Option<FlushErrorHandler>,
+    pub(crate) event_log: Arc<dyn EventLog>,
+    pub(crate) metrics: Arc<dyn Metrics>,
+    // Key orders of top level buckets, by bucket name
+    pub(crate) comparators: Arc<HashMap<Vec<u8>, Comparator>>,
     pub(crate) flags: DBFlags,
 
 }
 
+/// Callback invoked with the error when a background flush fails.
+#[derive(Clone)]
+pub(crate) struct FlushErrorHandler(pub(crate) Arc<dyn Fn(&Error) + Send + Sync>);
+
+impl Debug for FlushErrorHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FlushErrorHandler")
+    }
+}
+
 impl Default for Inner {
     fn default() -> Self {
         Self {
             path: PathBuf::from(DEFAULT_PATH),
-            tmp_path: Config::gen_temp_path(),
+            tmp_path: None,
             cache_capacity: 1024 * 1024 * 1024, // 1gb
             mode: Mode::LowSpace,
+            fill_percent: 1.0,
             temporary: false,
+            persist_path: None,
             version: crate_version(),
+            application_id: DATABASE_INTEGRITY_CODE,
+            mmap_advise: MmapAdvice::Random,
+            use_compression: false,
+            compression: None,
+            on_flush_error: None,
+            event_log: Arc::new(NoopEventLog),
+            metrics: Arc::new(NoopMetrics),
+            comparators: Arc::new(HashMap::new()),
 
             // useful in testing
             segment_size: 512 * 1024, // 512kb in bytes
+            max_value_size: 1024 * 512 * 1024, // 1024 default segments
+            max_tx_dirty_bytes: usize::MAX,
+            scan_prefetch: 0,
+            initial_pages: DEFAULT_NUM_PAGES,
+            lock_timeout: None,
             flush_every_ms: Some(500),
             snapshot_after_ops: if cfg!(feature = "for-internal-testing-only") {
                 10
@@ -47,6 +100,8 @@ impl Default for Inner {
                 direct_writes: false,
             },
             create_new: false,
+            read_only: false,
+            in_memory: false,
         }
     }
 }
@@ -54,18 +109,33 @@ impl Default for Inner {
 
 impl Inner {
     pub fn get_path(&self) -> PathBuf {
-        if self.temporary && self.path == PathBuf::from(DEFAULT_PATH) {
-            self.tmp_path.clone()
-        } else {
-            self.path.clone()
+        self.tmp_path().unwrap_or(&self.path).to_path_buf()
+    }
+
+    // The directory for a database without a path is only generated once it's temporary
+    pub(crate) fn set_temporary(&mut self, temporary: bool) {
+        self.temporary = temporary;
+        if temporary && self.tmp_path.is_none() {
+            self.tmp_path = Some(Config::gen_temp_path());
         }
     }
 
+    /// The generated directory the database lives in, once one was generated.
+    pub(crate) fn tmp_path(&self) -> Option<&Path> {
+        self.tmp_path.as_deref().filter(|_| self.uses_tmp_path())
+    }
+
+    /// Whether the database lives in a generated temporary directory, which
+    /// is only the case for temporary databases without a path of their own.
+    pub(crate) fn uses_tmp_path(&self) -> bool {
+        self.temporary && self.path == Path::new(DEFAULT_PATH)
+    }
+
     pub(crate) fn db_path(&self) -> PathBuf {
         self.get_path().join("db")
     }
 
-    fn config_path(&self) -> PathBuf {
+    pub(crate) fn config_path(&self) -> PathBuf {
         self.get_path().join("conf")
     }
 
@@ -80,6 +150,13 @@ impl Inner {
         let segment_size: T = T::try_from(self.segment_size).unwrap();
         value / segment_size * segment_size
     }
+
+    /// The number of pages a new database file is created with: `initial_pages`,
+    /// rounded up to a whole number of segments.
+    pub(crate) fn initial_num_pages(&self, pagesize: u64) -> usize {
+        let size = self.initial_pages as u64 * pagesize;
+        (self.normalize(size + self.segment_size as u64 - 1) / pagesize) as usize
+    }
 }
 
 
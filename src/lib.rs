@@ -1,23 +1,49 @@
+mod batch;
 mod db;
 pub mod defaults;
 pub mod errors;
 mod page;
 mod bucket;
+mod cursor;
 mod meta;
+mod migrate;
+mod node;
 mod freelist;
 mod options;
 mod transaction;
 mod inner;
 mod bytes;
+mod codec;
 mod sys;
 mod context;
 mod pagecache;
+mod snapshot;
+#[cfg(feature = "serde")]
+mod typed;
 mod config;
 mod event_log;
+mod metrics;
+mod export;
+mod flusher;
 mod utils;
 
+#[cfg(unix)]
+pub use config::cleanup_orphaned_temps;
+pub use batch::WriteBatch;
+pub use bucket::Bucket;
+pub use bytes::Bytes;
+pub use config::{Codec, Config, MmapAdvice, Mode};
+pub use db::{Stats, DB};
+pub use event_log::{Event, EventLog, NoopEventLog, VecEventLog};
+pub use cursor::{Cursor, KeyIter, PrefixIter, RangeIter};
+pub use metrics::{Metrics, NoopMetrics};
+pub use node::CompareFn;
+pub use options::Options;
+pub use snapshot::Snapshot;
+pub use transaction::{MetaInfo, Tx, TxStats};
 
-#[cfg(all(unix))]
+
+#[cfg(unix)]
 fn maybe_fsync_directory<P: AsRef<std::path::Path>>(
     path: P,
 ) -> std::io::Result<()> {
@@ -1,10 +1,15 @@
+use std::fs::File;
 use std::io::Write;
+use std::os::unix::fs::FileExt;
 
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use sha3::{Digest, Sha3_256};
 
 use crate::bucket::BucketMeta;
-use crate::page::PageID;
+use crate::defaults::VERSION;
+use crate::errors::{Error, Result};
+use crate::page::{Page, PageID, Pages};
+use crate::utils::AlignedBuf;
 
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -17,10 +22,55 @@ pub(crate) struct Meta {
     pub(crate) num_pages: PageID,
     pub(crate) freelist_page: PageID,
     pub(crate) tx_id: u64,
+    // Application-defined schema version, see `DB::set_user_version`
+    pub(crate) user_version: u64,
     pub(crate) hash: [u8; 32],
 }
 
 impl Meta {
+    /// Returns why this meta can't be used by a database expecting `application_id`,
+    /// or `None` if it can: a torn write leaves a hash that doesn't match, and a page
+    /// from another application or format version has the wrong code or version.
+    pub(crate) fn check(&self, application_id: u32) -> Option<&'static str> {
+        if !self.valid() {
+            Some("its hash does not match")
+        } else if self.integrity_code != application_id {
+            Some("its integrity code does not match")
+        } else if self.version > VERSION {
+            Some("its version is newer than this build supports")
+        } else if self.version < VERSION {
+            Some("its version is older and has to be migrated")
+        } else {
+            None
+        }
+    }
+
+    /// Returns the newest meta of the two meta pages in `pages` that passes its checks.
+    pub(crate) fn newest(pages: &Pages, application_id: u32) -> Result<Meta> {
+        // A torn write can leave a meta page without even its page type
+        let read = |id| -> Result<std::result::Result<&Meta, &'static str>> {
+            let page = pages.try_page(id)?;
+            if page.page_type != Page::TYPE_META {
+                return Ok(Err("is not a meta page"));
+            }
+            let meta = page.meta();
+            Ok(meta.check(application_id).map_or(Ok(meta), Err))
+        };
+        // A meta page failing its checks is skipped in favour of the other one
+        let meta = match (read(0)?, read(1)?) {
+            (Ok(meta1), Ok(meta2)) if meta2.tx_id > meta1.tx_id => meta2,
+            (Ok(meta1), _) => meta1,
+            (Err(_), Ok(meta2)) => meta2,
+            (Err(problem1), Err(problem2)) => {
+                return Err(Error::InvalidDB(format!(
+                    "no valid meta page: page 0 {}, page 1 {}",
+                    problem1, problem2
+                )))
+            }
+        };
+        Ok(meta.clone())
+    }
+
     pub(crate) fn valid(&self) -> bool {
         self.hash == self.hash_self()
     }
@@ -35,6 +85,31 @@ impl Meta {
         hash_result
     }
 
+    /// Writes this meta to the meta page chosen by the parity of its `tx_id`,
+    /// so consecutive transactions alternate between the two meta pages.
+    ///
+    /// `align` is the alignment the file requires of write buffers.
+    pub(crate) fn write(&mut self, file: &File, pagesize: u64, align: usize) -> Result<()> {
+        self.meta_page = (self.tx_id % 2) as u32;
+        let mut buf = AlignedBuf::new(pagesize as usize, align)?;
+        self.write_page(&mut buf);
+
+        let offset = PageID::from(self.meta_page) * pagesize;
+        file.write_all_at(buf.as_slice(), offset)?;
+        Ok(())
+    }
+
+    /// Fills `buf` with the meta page `meta_page`, holding this meta and its hash.
+    pub(crate) fn write_page(&mut self, buf: &mut AlignedBuf) {
+        self.hash = self.hash_self();
+        #[allow(clippy::cast_ptr_alignment)]
+        let page = unsafe { &mut *(buf.as_mut_slice().as_mut_ptr() as *mut Page) };
+        page.id = PageID::from(self.meta_page);
+        page.page_type = Page::TYPE_META;
+        page.tx_id = self.tx_id;
+        *page.meta_mut() = self.clone();
+    }
+
     fn bytes(&self) -> Bytes {
         let buf = BytesMut::new();
         let mut w = buf.writer();
@@ -44,14 +119,53 @@ impl Meta {
         let _ = w.write(&self.pagesize.to_be_bytes());
         let _ = w.write(&self.root.root_page.to_be_bytes());
         let _ = w.write(&self.root.next_int.to_be_bytes());
+        let _ = w.write(&self.root.num_keys.to_be_bytes());
         let _ = w.write(&self.num_pages.to_be_bytes());
         let _ = w.write(&self.freelist_page.to_be_bytes());
         let _ = w.write(&self.tx_id.to_be_bytes());
+        let _ = w.write(&self.user_version.to_be_bytes());
 
         w.into_inner().freeze()
     }
+
+    /// Serializes every field, including the hash, in the same
+    /// big-endian layout used for hashing.
+    pub(crate) fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::from(&self.bytes()[..]);
+        buf.put_slice(&self.hash);
+        buf.freeze()
+    }
+
+    /// Reads back a `Meta` written by [`Meta::encode`], or `None` if
+    /// the buffer is too short.
+    pub(crate) fn decode(buf: &mut impl Buf) -> Option<Meta> {
+        if buf.remaining() < Meta::ENCODED_LEN {
+            return None;
+        }
+        let mut meta = Meta {
+            meta_page: buf.get_u32(),
+            integrity_code: buf.get_u32(),
+            version: buf.get_u32(),
+            pagesize: buf.get_u64(),
+            root: BucketMeta {
+                root_page: buf.get_u64(),
+                next_int: buf.get_u64(),
+                num_keys: buf.get_u64(),
+            },
+            num_pages: buf.get_u64(),
+            freelist_page: buf.get_u64(),
+            tx_id: buf.get_u64(),
+            user_version: buf.get_u64(),
+            hash: [0; 32],
+        };
+        buf.copy_to_slice(&mut meta.hash);
+        Some(meta)
+    }
+
+    const ENCODED_LEN: usize = 3 * 4 + 8 * 8 + 32;
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -65,13 +179,42 @@ mod tests {
             root: BucketMeta {
                 root_page: 0,
                 next_int: 0,
+                num_keys: 0,
             },
             num_pages: 0,
             freelist_page: 0,
             tx_id: 0,
+            user_version: 0,
             hash: [0; 32],
         };
         let hash = meta.hash_self();
         dbg!(hash);
     }
+
+    #[test]
+    fn test_meta_encode_decode() {
+        let mut meta = Meta {
+            meta_page: 1,
+            integrity_code: 2,
+            version: 3,
+            pagesize: 4096,
+            root: BucketMeta {
+                root_page: 5,
+                next_int: 6,
+                num_keys: 10,
+            },
+            num_pages: 7,
+            freelist_page: 8,
+            tx_id: 9,
+            user_version: 0,
+            hash: [0; 32],
+        };
+        meta.hash = meta.hash_self();
+        let encoded = meta.encode();
+        let decoded = Meta::decode(&mut &encoded[..]).unwrap();
+        assert!(decoded.valid());
+        assert_eq!(decoded.tx_id, 9);
+        assert_eq!(decoded.root, meta.root);
+        assert!(Meta::decode(&mut &encoded[..10]).is_none());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,92 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// Receives counters and gauges describing the database, to be passed on to a
+/// metrics system such as Prometheus or statsd. Every method does nothing by default.
+///
+/// Unlike an [`EventLog`](crate::EventLog), which is told about each event as it
+/// happens, these are numbers meant to be aggregated. They're reported on the
+/// thread running the transaction, so they should return quickly.
+pub trait Metrics: Debug + Send + Sync {
+    /// Counter: a writable transaction committed.
+    fn tx_committed(&self) {}
+
+    /// Counter: a commit allocated `pages` more pages, for nodes, values and the freelist.
+    fn pages_allocated_total(&self, _pages: u64) {}
+
+    /// Gauge: the page cache has answered `hits` reads so far, reported on each commit.
+    fn cache_hits(&self, _hits: u64) {}
+
+    /// A sync of the database file took `duration`.
+    fn fsync_duration(&self, _duration: Duration) {}
+}
+
+/// A [`Metrics`] that ignores everything. This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::config::Config;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Recorded {
+        commits: AtomicU64,
+        pages: AtomicU64,
+        cache_hits: AtomicU64,
+        syncs: AtomicU64,
+    }
+
+    impl Metrics for Recorded {
+        fn tx_committed(&self) {
+            self.commits.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn pages_allocated_total(&self, pages: u64) {
+            self.pages.fetch_add(pages, Ordering::SeqCst);
+        }
+
+        fn cache_hits(&self, hits: u64) {
+            self.cache_hits.store(hits, Ordering::SeqCst);
+        }
+
+        fn fsync_duration(&self, _duration: Duration) {
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = Arc::new(Recorded::default());
+        let db = Config::new().path(dir.path()).flush_every_ms(None).metrics(metrics.clone()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap().put("key", "value").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(metrics.commits.load(Ordering::SeqCst), 1);
+        let pages = metrics.pages.load(Ordering::SeqCst);
+        assert!(pages > 0);
+        assert!(metrics.syncs.load(Ordering::SeqCst) > 0);
+
+        // read-only transactions and rollbacks aren't commits
+        db.tx(false).unwrap().bucket(b"data").unwrap().get(b"key").unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.bucket(b"data").unwrap().get(b"key").unwrap();
+        tx.rollback().unwrap();
+        assert_eq!(metrics.commits.load(Ordering::SeqCst), 1);
+
+        let tx = db.tx(true).unwrap();
+        tx.bucket(b"data").unwrap().put("other", "value").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(metrics.commits.load(Ordering::SeqCst), 2);
+        assert!(metrics.pages.load(Ordering::SeqCst) > pages);
+        assert_eq!(metrics.cache_hits.load(Ordering::SeqCst), db.stats().unwrap().cache_hits);
+    }
+}
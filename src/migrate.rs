@@ -0,0 +1,518 @@
+use std::fs::File;
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+
+use sha3::{Digest, Sha3_256};
+
+use crate::bucket::BucketMeta;
+use crate::bytes::Bytes;
+use crate::db::write_freelist;
+use crate::defaults::VERSION;
+use crate::errors::{Error, Result};
+use crate::freelist::Freelist;
+use crate::meta::Meta;
+use crate::node::{Branch, Leaf, Node, NodeData};
+use crate::page::{BranchElement, LeafElement, Page, PageID};
+use crate::utils::AlignedBuf;
+
+/// What sets a format version's pages apart from the others. Elements are the
+/// same in every version, and point at their keys relative to themselves.
+#[derive(Clone, Copy, Debug)]
+struct Layout {
+    version: u32,
+    // Offset of a page's data from the start of the page: format 2 added the
+    // transaction that wrote the page to its header
+    header_size: u64,
+    // Whether bucket metas count their keys, as they do from format 3
+    num_keys: bool,
+}
+
+/// Every format a database can be opened in, oldest first. One in an older
+/// format is migrated by copying its tree into new pages in the current one.
+const LAYOUTS: &[Layout] = &[
+    Layout { version: 1, header_size: 32, num_keys: false },
+    Layout { version: 2, header_size: 40, num_keys: false },
+    Layout { version: VERSION, header_size: Page::HEADER_SIZE, num_keys: true },
+];
+
+impl Layout {
+    fn bucket_meta_size(&self) -> u64 {
+        if self.num_keys {
+            size_of::<BucketMeta>() as u64
+        } else {
+            2 * size_of::<u64>() as u64
+        }
+    }
+
+    // Reads the meta on `page`, if it's a meta page in this layout whose hash matches
+    fn meta(&self, page: &[u8]) -> Option<Meta> {
+        if *page.get(size_of::<PageID>())? != Page::TYPE_META {
+            return None;
+        }
+        let meta = self.read_meta(page).ok()?;
+        (meta.hash == self.hash(&meta)).then_some(meta)
+    }
+
+    fn read_meta(&self, page: &[u8]) -> Result<Meta> {
+        let mut r = Reader { data: page, pos: self.header_size };
+        let mut meta = Meta {
+            meta_page: r.u32()?,
+            integrity_code: r.u32()?,
+            version: r.u32()?,
+            pagesize: r.u64_aligned()?,
+            root: BucketMeta {
+                root_page: r.u64()?,
+                next_int: r.u64()?,
+                num_keys: if self.num_keys { r.u64()? } else { 0 },
+            },
+            num_pages: r.u64()?,
+            freelist_page: r.u64()?,
+            tx_id: r.u64()?,
+            user_version: r.u64()?,
+            hash: [0; 32],
+        };
+        meta.hash.copy_from_slice(r.bytes(32)?);
+        Ok(meta)
+    }
+
+    fn hash(&self, meta: &Meta) -> [u8; 32] {
+        if self.num_keys {
+            return meta.hash_self();
+        }
+        let mut hasher = Sha3_256::new();
+        hasher.update(meta.meta_page.to_be_bytes());
+        hasher.update(meta.integrity_code.to_be_bytes());
+        hasher.update(meta.version.to_be_bytes());
+        for field in [
+            meta.pagesize,
+            meta.root.root_page,
+            meta.root.next_int,
+            meta.num_pages,
+            meta.freelist_page,
+            meta.tx_id,
+            meta.user_version,
+        ] {
+            hasher.update(field.to_be_bytes());
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// Brings the database file up to the current format [`VERSION`], and returns
+/// whether it did. `data` maps the file, whose pagesize is taken from its first
+/// meta page or is otherwise `pagesize`.
+///
+/// The tree of the newest meta is copied into new pages at the end of the file,
+/// and the pages it had are all freed, so the file grows by the size of the
+/// tree until they're reused. The old pages aren't touched, so a crash before
+/// the new meta is written leaves the file as it was. That meta is then written
+/// again as the following transaction so neither meta page is in the old format,
+/// and synced. A file written by a newer version, or one in an older format
+/// opened `read_only`, returns [`Error::InvalidDB`].
+pub(crate) fn migrate(
+    file: &File,
+    data: &[u8],
+    pagesize: u64,
+    application_id: u32,
+    align: usize,
+    read_only: bool,
+) -> Result<bool> {
+    let (meta, layout) = match newest(data, pagesize, application_id) {
+        Some(newest) => newest,
+        None => return Ok(false),
+    };
+    if meta.version > VERSION {
+        return Err(Error::InvalidDB(format!(
+            "the database uses format version {}, newer than the {} this build supports",
+            meta.version, VERSION
+        )));
+    }
+    if meta.version == VERSION {
+        return Ok(false);
+    }
+    if read_only {
+        return Err(Error::InvalidDB(format!(
+            "the database uses format version {}, and has to be opened writable to be migrated",
+            meta.version
+        )));
+    }
+
+    let mut meta = copy_tree(file, data, &meta, layout, align)?;
+    meta.tx_id += 1;
+    meta.write(file, meta.pagesize, align)?;
+    file.sync_all()?;
+    Ok(true)
+}
+
+// The newest meta page that belongs to the application and is valid in the
+// layout of its version, with that layout
+fn newest(data: &[u8], pagesize: u64, application_id: u32) -> Option<(Meta, Layout)> {
+    let page = |id: u64, pagesize: u64| {
+        let start = id.checked_mul(pagesize)? as usize;
+        data.get(start..start.checked_add(pagesize as usize)?)
+    };
+    // A meta in the current layout may come from a newer version
+    let read = |page: &[u8]| {
+        LAYOUTS.iter().find_map(|layout| {
+            layout
+                .meta(page)
+                .filter(|meta| meta.version == layout.version || (layout.version == VERSION && meta.version > VERSION))
+                .filter(|meta| meta.integrity_code == application_id)
+                .map(|meta| (meta, *layout))
+        })
+    };
+    let first = page(0, pagesize).and_then(read);
+    let pagesize = first.as_ref().map_or(pagesize, |(meta, _)| meta.pagesize);
+    let second = page(1, pagesize).and_then(read);
+    match (first, second) {
+        (Some(first), Some(second)) if second.0.tx_id > first.0.tx_id => Some(second),
+        (Some(first), _) => Some(first),
+        (None, second) => second,
+    }
+}
+
+// Copies the tree of `meta`, whose pages are laid out as `layout`, into new pages
+// after its last one, then writes a freelist holding every page before them and
+// a meta in the current format for the following transaction, and returns it
+fn copy_tree(file: &File, data: &[u8], meta: &Meta, layout: Layout, align: usize) -> Result<Meta> {
+    let mut copy = Copy {
+        file,
+        data,
+        pagesize: meta.pagesize,
+        align,
+        layout,
+        tx_id: meta.tx_id + 1,
+        next_page: meta.num_pages,
+        visited: vec![false; meta.num_pages as usize],
+    };
+    let (root_page, num_keys) = copy.node(meta.root.root_page)?;
+
+    let free_pages: Vec<PageID> = (2..meta.num_pages).collect();
+    let mut freelist = Freelist::new();
+    freelist.init(&free_pages);
+    let freelist_page = copy.next_page;
+    let num_pages = write_freelist(file, &freelist, freelist_page, copy.tx_id, copy.pagesize, align)?;
+    file.sync_all()?;
+
+    let mut meta = Meta {
+        version: VERSION,
+        root: BucketMeta {
+            root_page,
+            next_int: meta.root.next_int,
+            num_keys,
+        },
+        num_pages: freelist_page + num_pages,
+        freelist_page,
+        tx_id: copy.tx_id,
+        ..meta.clone()
+    };
+    meta.write(file, meta.pagesize, align)?;
+    file.sync_all()?;
+    Ok(meta)
+}
+
+struct Copy<'a> {
+    file: &'a File,
+    data: &'a [u8],
+    pagesize: u64,
+    align: usize,
+    layout: Layout,
+    tx_id: u64,
+    // Where the next copied node is written
+    next_page: PageID,
+    // The old pages already copied, as a tree reaching one twice is corrupt
+    visited: Vec<bool>,
+}
+
+impl Copy<'_> {
+    // Copies the node on page `id` and everything under it, returning the page it
+    // was copied to and how many key / value pairs are under it
+    fn node(&mut self, id: PageID) -> Result<(PageID, u64)> {
+        let corrupt = |reason: &str| Err(Error::InvalidDB(format!("page {} can't be migrated: {}", id, reason)));
+        match self.visited.get_mut(id as usize) {
+            None => return corrupt("it is beyond the last page"),
+            Some(true) => return corrupt("it is in the tree twice"),
+            Some(visited) => *visited = true,
+        }
+        let start = id * self.pagesize;
+        let mut r = Reader { data: self.data, pos: start };
+        let (page_id, page_type, count, overflow) = (r.u64()?, r.u8()?, r.u64_aligned()?, r.u64()?);
+        if page_id != id {
+            return corrupt("its header has the wrong id");
+        }
+        let end = overflow
+            .checked_add(1)
+            .and_then(|n| n.checked_mul(self.pagesize))
+            .and_then(|size| start.checked_add(size))
+            .filter(|end| *end <= self.data.len() as u64);
+        let page = match end {
+            Some(end) => Reader { data: &self.data[..end as usize], pos: start + self.layout.header_size },
+            None => return corrupt("its overflow pages run past the end of the file"),
+        };
+
+        let mut num_keys = 0;
+        let data = match page_type {
+            Page::TYPE_BRANCH => {
+                let mut branches = Vec::new();
+                for i in 0..count {
+                    let offset = i * size_of::<BranchElement>() as u64;
+                    let mut elem = page.at(offset);
+                    let (child, key_size, pos) = (elem.u64()?, elem.u64()?, elem.u64()?);
+                    let key = page.at(offset + pos).bytes(key_size)?;
+                    let (page, keys) = self.node(child)?;
+                    num_keys += keys;
+                    branches.push(Branch { key: Bytes::Slice(key), page });
+                }
+                NodeData::Branches(branches)
+            }
+            Page::TYPE_LEAF => {
+                let mut leaves = Vec::new();
+                for i in 0..count {
+                    let offset = i * size_of::<LeafElement>() as u64;
+                    let mut elem = page.at(offset);
+                    let (node_type, pos, key_size, value_size) = (elem.u8()?, elem.u64_aligned()?, elem.u64()?, elem.u64()?);
+                    let mut value = page.at(offset + pos);
+                    let key = value.bytes(key_size)?;
+                    if node_type != Node::TYPE_BUCKET {
+                        num_keys += 1;
+                        leaves.push(Leaf::Kv(Bytes::Slice(key), Bytes::Slice(value.bytes(value_size)?)));
+                        continue;
+                    }
+                    if value_size != self.layout.bucket_meta_size() {
+                        return corrupt("a bucket meta has the wrong size");
+                    }
+                    let (root_page, next_int) = (value.u64()?, value.u64()?);
+                    let (root_page, keys) = self.node(root_page)?;
+                    num_keys += keys;
+                    leaves.push(Leaf::Bucket(Bytes::Slice(key), BucketMeta { root_page, next_int, num_keys: keys }));
+                }
+                NodeData::Leaves(leaves)
+            }
+            t => return corrupt(&format!("it has type {}, expected a branch or leaf", t)),
+        };
+        Ok((self.write(&data)?, num_keys))
+    }
+
+    fn write(&mut self, data: &NodeData) -> Result<PageID> {
+        let num_pages = data.size(0..data.len()).div_ceil(self.pagesize);
+        let mut buf = AlignedBuf::new((num_pages * self.pagesize) as usize, self.align)?;
+        #[allow(clippy::cast_ptr_alignment)]
+        let page = unsafe { &mut *(buf.as_mut_slice().as_mut_ptr() as *mut Page) };
+        page.id = self.next_page;
+        page.overflow = num_pages - 1;
+        page.tx_id = self.tx_id;
+        data.write(0..data.len(), page);
+        self.file.write_all_at(buf.as_slice(), self.next_page * self.pagesize)?;
+        self.next_page += num_pages;
+        Ok(page.id)
+    }
+}
+
+// Reads the native-endian fields of a page in an older format from `data`,
+// which ends where the page does
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn at(&self, offset: u64) -> Reader<'a> {
+        Reader { data: self.data, pos: self.pos.saturating_add(offset) }
+    }
+
+    fn bytes(&mut self, len: u64) -> Result<&'a [u8]> {
+        let start = self.pos;
+        let bytes = start
+            .checked_add(len)
+            .and_then(|end| self.data.get(start as usize..end as usize));
+        match bytes {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => Err(Error::InvalidDB(format!("an element at offset {} lies outside its page", start))),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_ne_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_ne_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    // Reads a u64 following a smaller field, after the padding that aligns it
+    fn u64_aligned(&mut self) -> Result<u64> {
+        self.pos = self.pos.next_multiple_of(8);
+        self.u64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::mem::align_of;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use crate::config::Config;
+    use crate::defaults::DATABASE_INTEGRITY_CODE;
+    use crate::page::Pages;
+
+    use super::*;
+
+    // The node type of a leaf element, or none and the child page of a branch
+    // element, then its key and value
+    type Elem<'a> = (Option<u8>, u64, &'a [u8], &'a [u8]);
+
+    // Encodes page `id` in `layout`, as a branch if its elements have no node
+    // types, laid out as the writer of that format did
+    fn node(layout: Layout, id: PageID, elems: &[Elem]) -> Vec<u8> {
+        let pagesize = page_size::get();
+        let mut page = vec![0; pagesize];
+        let branch = elems.iter().all(|(node_type, ..)| node_type.is_none());
+        page[..8].copy_from_slice(&id.to_ne_bytes());
+        page[8] = if branch { Page::TYPE_BRANCH } else { Page::TYPE_LEAF };
+        page[16..24].copy_from_slice(&(elems.len() as u64).to_ne_bytes());
+        if layout.header_size > 32 {
+            page[32..40].copy_from_slice(&6u64.to_ne_bytes());
+        }
+        let elem_size = if branch { size_of::<BranchElement>() } else { size_of::<LeafElement>() };
+        let mut data = layout.header_size as usize + elems.len() * elem_size;
+        for (i, (node_type, child, key, value)) in elems.iter().enumerate() {
+            let elem = layout.header_size as usize + i * elem_size;
+            let pos = (data - elem) as u64;
+            let fields = match node_type {
+                None => vec![*child, key.len() as u64, pos],
+                Some(t) => vec![*t as u64, pos, key.len() as u64, value.len() as u64],
+            };
+            for (j, field) in fields.iter().enumerate() {
+                page[elem + j * 8..elem + j * 8 + 8].copy_from_slice(&field.to_ne_bytes());
+            }
+            page[data..data + key.len()].copy_from_slice(key);
+            page[data + key.len()..data + key.len() + value.len()].copy_from_slice(value);
+            data += key.len() + value.len();
+        }
+        page
+    }
+
+    fn bucket(layout: Layout, root_page: PageID) -> Vec<u8> {
+        let mut meta = [root_page, 2, 99].map(u64::to_ne_bytes).concat();
+        meta.truncate(layout.bucket_meta_size() as usize);
+        meta
+    }
+
+    // Writes a database in `layout` to `dir` as its transaction 6 would have: bucket
+    // "a" holds two keys, and bucket "c" one key and a bucket holding another
+    fn write_old(dir: &Path, layout: Layout) {
+        let pagesize = page_size::get() as u64;
+        let mut pages = vec![Vec::new(), Vec::new()];
+        let mut freelist = vec![0; pagesize as usize];
+        freelist[..8].copy_from_slice(&2u64.to_ne_bytes());
+        freelist[8] = Page::TYPE_FREELIST;
+        pages.push(freelist);
+        pages.push(node(layout, 3, &[(None, 4, b"a", b""), (None, 5, b"c", b"")]));
+        let (bucket_6, bucket_7, bucket_8) = (bucket(layout, 6), bucket(layout, 7), bucket(layout, 8));
+        pages.push(node(layout, 4, &[(Some(Node::TYPE_BUCKET), 0, b"a", &bucket_6)]));
+        pages.push(node(layout, 5, &[(Some(Node::TYPE_BUCKET), 0, b"c", &bucket_7)]));
+        pages.push(node(layout, 6, &[(Some(Node::TYPE_DATA), 0, b"k1", b"v1"), (Some(Node::TYPE_DATA), 0, b"k2", b"v2")]));
+        let nested = [(Some(Node::TYPE_BUCKET), 0, &b"nested"[..], &bucket_8[..]), (Some(Node::TYPE_DATA), 0, b"x", b"y")];
+        pages.push(node(layout, 7, &nested));
+        pages.push(node(layout, 8, &[(Some(Node::TYPE_DATA), 0, b"k", b"v")]));
+
+        for (id, tx_id) in [(0u64, 6), (1, 5)] {
+            let mut meta = Meta {
+                meta_page: id as u32,
+                integrity_code: DATABASE_INTEGRITY_CODE,
+                version: layout.version,
+                pagesize,
+                root: BucketMeta { root_page: 3, next_int: 0, num_keys: 0 },
+                num_pages: 9,
+                freelist_page: 2,
+                tx_id,
+                user_version: 0,
+                hash: [0; 32],
+            };
+            meta.hash = layout.hash(&meta);
+            let mut page = vec![0; pagesize as usize];
+            page[..8].copy_from_slice(&id.to_ne_bytes());
+            page[8] = Page::TYPE_META;
+            let mut fields = [meta.meta_page, meta.integrity_code, meta.version, 0].map(u32::to_ne_bytes).concat();
+            let mut rest = vec![meta.pagesize, meta.root.root_page, meta.root.next_int];
+            rest.extend(layout.num_keys.then_some(meta.root.num_keys));
+            rest.extend([meta.num_pages, meta.freelist_page, meta.tx_id, meta.user_version]);
+            fields.extend(rest.iter().flat_map(|field| field.to_ne_bytes()));
+            fields.extend(meta.hash);
+            let start = layout.header_size as usize;
+            page[start..start + fields.len()].copy_from_slice(&fields);
+            pages[id as usize] = page;
+        }
+        std::fs::write(dir.join("db"), pages.concat()).unwrap();
+    }
+
+    // Rewrites both meta pages of the database in `dir` with `version`
+    fn set_version(dir: &Path, version: u32) {
+        let file = OpenOptions::new().read(true).write(true).open(dir.join("db")).unwrap();
+        let data = unsafe { memmap2::Mmap::map(&file).unwrap() };
+        let pagesize = page_size::get() as u64;
+        let pages = Pages::new(Arc::new(data), pagesize);
+        for id in 0..2 {
+            let mut meta = pages.try_page(id).unwrap().meta().clone();
+            meta.version = version;
+            let mut buf = AlignedBuf::new(pagesize as usize, align_of::<Page>()).unwrap();
+            meta.write_page(&mut buf);
+            file.write_all_at(buf.as_slice(), id * pagesize).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_migrate() {
+        for layout in &LAYOUTS[..LAYOUTS.len() - 1] {
+            let dir = tempfile::tempdir().unwrap();
+            write_old(dir.path(), *layout);
+
+            // opening read-only leaves the file alone
+            let err = Config::new().path(dir.path()).read_only(true).open().err().unwrap();
+            assert!(matches!(err, Error::InvalidDB(msg) if msg.contains("migrated")), "version {}", layout.version);
+
+            let db = Config::new().path(dir.path()).open().unwrap();
+            let meta = db.context.meta().unwrap();
+            assert_eq!((meta.version, meta.tx_id, meta.root.next_int), (VERSION, 8, 0));
+            assert_eq!(db.len().unwrap(), 4);
+            let tx = db.tx(false).unwrap();
+            let a = tx.bucket(b"a").unwrap();
+            assert_eq!(a.get(b"k1").unwrap().unwrap().as_ref(), b"v1");
+            assert_eq!(a.get(b"k2").unwrap().unwrap().as_ref(), b"v2");
+            let nested = tx.bucket(b"c").unwrap().bucket(b"nested").unwrap();
+            assert_eq!(nested.get(b"k").unwrap().unwrap().as_ref(), b"v");
+            drop(tx);
+
+            let tx = db.tx(true).unwrap();
+            assert_eq!(tx.bucket(b"a").unwrap().next_sequence().unwrap(), 3);
+            tx.bucket(b"c").unwrap().bucket(b"nested").unwrap().put("k3", "v3").unwrap();
+            tx.commit().unwrap();
+            drop(db);
+
+            let db = Config::new().path(dir.path()).open().unwrap();
+            assert_eq!(db.len().unwrap(), 5);
+            let tx = db.tx(false).unwrap();
+            let c = tx.bucket(b"c").unwrap();
+            assert_eq!(c.get(b"x").unwrap().unwrap().as_ref(), b"y");
+            assert_eq!(c.bucket(b"nested").unwrap().get(b"k3").unwrap().unwrap().as_ref(), b"v3");
+        }
+    }
+
+    #[test]
+    fn test_migrate_newer() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(Config::new().path(dir.path()).open().unwrap());
+        set_version(dir.path(), VERSION + 1);
+        let err = Config::new().path(dir.path()).open().err().unwrap();
+        assert!(matches!(err, Error::InvalidDB(msg) if msg.contains("newer")));
+    }
+}
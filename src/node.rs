@@ -0,0 +1,353 @@
+use std::cmp::Ordering;
+use std::fmt::{self, Debug};
+use std::mem::size_of;
+use std::ops::Range;
+use std::ptr::copy_nonoverlapping;
+use std::sync::Arc;
+
+use crate::bucket::BucketMeta;
+use crate::bytes::Bytes;
+use crate::errors::{Error, Result};
+use crate::page::{BranchElement, LeafElement, Page, PageID, Pages};
+
+pub(crate) type NodeID = u64;
+
+pub(crate) type NodeType = u8;
+
+/// Orders the keys of a bucket: by their bytes, unless the bucket was given
+/// a comparator with `Config::comparator`.
+#[derive(Clone, Default)]
+pub(crate) struct Comparator(pub(crate) Option<Arc<CompareFn>>);
+
+pub type CompareFn = dyn Fn(&[u8], &[u8]) -> Ordering + Send + Sync;
+
+impl Comparator {
+    pub(crate) fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        match &self.0 {
+            Some(cmp) => cmp(a, b),
+            None => a.cmp(b),
+        }
+    }
+
+    pub(crate) fn is_custom(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+impl Debug for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Comparator")
+    }
+}
+
+/// Points at either a page in the mmap that hasn't been touched by the
+/// transaction, or a node that has been read into memory to be modified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PageNodeID {
+    Page(PageID),
+    Node(NodeID),
+}
+
+pub(crate) struct Node<'n> {
+    // The page this node was read from, or 0 for a node that has never been written
+    pub(crate) page_id: PageID,
+    pub(crate) num_pages: u64,
+    // Nodes materialized for the pages this node's branches point to
+    pub(crate) children: Vec<NodeID>,
+    pub(crate) data: NodeData<'n>,
+}
+
+impl<'n> Node<'n> {
+    pub(crate) const TYPE_DATA: NodeType = 0x00;
+    pub(crate) const TYPE_BUCKET: NodeType = 0x01;
+
+    pub(crate) fn new(data: NodeData<'n>) -> Node<'n> {
+        Node {
+            page_id: 0,
+            num_pages: 0,
+            children: Vec::new(),
+            data,
+        }
+    }
+
+    pub(crate) fn read(pages: &Pages, page_id: PageID) -> Result<Node<'n>> {
+        let (data, num_pages) = pages.node_data(page_id)?;
+        Ok(Node {
+            page_id,
+            num_pages,
+            children: Vec::new(),
+            data,
+        })
+    }
+
+    /// Inserts `leaf` in key order, replacing and returning any leaf with the same key.
+    pub(crate) fn insert(&mut self, leaf: Leaf<'n>, cmp: &Comparator) -> Option<Leaf<'n>> {
+        let leaves = match &mut self.data {
+            NodeData::Leaves(leaves) => leaves,
+            NodeData::Branches(_) => panic!("cannot insert a leaf into a branch node"),
+        };
+        match leaves.binary_search_by(|l| cmp.compare(l.key(), leaf.key())) {
+            Ok(index) => Some(std::mem::replace(&mut leaves[index], leaf)),
+            Err(index) => {
+                leaves.insert(index, leaf);
+                None
+            }
+        }
+    }
+
+    /// Removes and returns the leaf stored under `key`.
+    pub(crate) fn remove(&mut self, key: &[u8], cmp: &Comparator) -> Option<Leaf<'n>> {
+        let leaves = match &mut self.data {
+            NodeData::Leaves(leaves) => leaves,
+            NodeData::Branches(_) => panic!("cannot remove a leaf from a branch node"),
+        };
+        match leaves.binary_search_by(|l| cmp.compare(l.key(), key)) {
+            Ok(index) => Some(leaves.remove(index)),
+            Err(_) => None,
+        }
+    }
+}
+
+pub(crate) enum NodeData<'n> {
+    Branches(Vec<Branch<'n>>),
+    Leaves(Vec<Leaf<'n>>),
+}
+
+impl<'n> NodeData<'n> {
+    pub(crate) fn from_page(page: &Page) -> Result<NodeData<'n>> {
+        match page.page_type {
+            Page::TYPE_BRANCH => Ok(NodeData::Branches(
+                page.branch_elements()
+                    .iter()
+                    .map(|e| Branch {
+                        key: Bytes::Slice(e.key()),
+                        page: e.page,
+                    })
+                    .collect(),
+            )),
+            Page::TYPE_LEAF => Ok(NodeData::Leaves(
+                page.leaf_elements()
+                    .iter()
+                    .map(|e| match e.node_type {
+                        Node::TYPE_BUCKET => Leaf::Bucket(Bytes::Slice(e.key()), bucket_meta(e.value())),
+                        _ => Leaf::Kv(Bytes::Slice(e.key()), Bytes::Slice(e.value())),
+                    })
+                    .collect(),
+            )),
+            t => Err(Error::InvalidDB(format!(
+                "page {} has type {}, expected a branch or leaf",
+                page.id, t
+            ))),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            NodeData::Branches(b) => b.len(),
+            NodeData::Leaves(l) => l.len(),
+        }
+    }
+
+    pub(crate) fn key(&self, index: usize) -> Bytes<'n> {
+        match self {
+            NodeData::Branches(b) => b[index].key.clone(),
+            NodeData::Leaves(l) => match &l[index] {
+                Leaf::Bucket(key, _) => key.clone(),
+                Leaf::Kv(key, _) => key.clone(),
+            },
+        }
+    }
+
+    fn elem_size(&self, index: usize) -> u64 {
+        match self {
+            NodeData::Branches(b) => (size_of::<BranchElement>() + b[index].key.as_ref().len()) as u64,
+            NodeData::Leaves(l) => (size_of::<LeafElement>() + l[index].key().len() + l[index].value_len()) as u64,
+        }
+    }
+
+    /// Number of bytes needed to write the elements in `range` to a page, header included.
+    pub(crate) fn size(&self, range: Range<usize>) -> u64 {
+        Page::HEADER_SIZE + range.map(|i| self.elem_size(i)).sum::<u64>()
+    }
+
+    /// Splits the elements into consecutive ranges that each fit in `limit` bytes,
+    /// at most a page, except for a lone element too large for one, which gets
+    /// overflow pages.
+    pub(crate) fn split(&self, limit: u64) -> Vec<Range<usize>> {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        let mut size = Page::HEADER_SIZE;
+        for i in 0..self.len() {
+            let elem_size = self.elem_size(i);
+            if i > start && size + elem_size > limit {
+                ranges.push(start..i);
+                start = i;
+                size = Page::HEADER_SIZE;
+            }
+            size += elem_size;
+        }
+        if start < self.len() {
+            ranges.push(start..self.len());
+        }
+        ranges
+    }
+
+    /// Writes the elements in `range` to `page`, which must be at least
+    /// [`NodeData::size`] bytes long.
+    pub(crate) fn write(&self, range: Range<usize>, page: &mut Page) {
+        page.count = range.len() as u64;
+        let elems = &mut page.ptr as *mut u64 as *mut u8;
+        match self {
+            NodeData::Branches(branches) => {
+                page.page_type = Page::TYPE_BRANCH;
+                let mut data = unsafe { elems.add(size_of::<BranchElement>() * range.len()) };
+                for (i, branch) in branches[range].iter().enumerate() {
+                    let key = branch.key.as_ref();
+                    #[allow(clippy::cast_ptr_alignment)]
+                    unsafe {
+                        let elem = elems.add(size_of::<BranchElement>() * i) as *mut BranchElement;
+                        *elem = BranchElement {
+                            page: branch.page,
+                            key_size: key.len() as u64,
+                            pos: data.offset_from(elem as *mut u8) as u64,
+                        };
+                        copy_nonoverlapping(key.as_ptr(), data, key.len());
+                        data = data.add(key.len());
+                    }
+                }
+            }
+            NodeData::Leaves(leaves) => {
+                page.page_type = Page::TYPE_LEAF;
+                let mut data = unsafe { elems.add(size_of::<LeafElement>() * range.len()) };
+                for (i, leaf) in leaves[range].iter().enumerate() {
+                    let key = leaf.key();
+                    let (node_type, value) = match leaf {
+                        Leaf::Bucket(_, meta) => (Node::TYPE_BUCKET, meta.as_bytes()),
+                        Leaf::Kv(_, value) => (Node::TYPE_DATA, value.as_ref()),
+                    };
+                    #[allow(clippy::cast_ptr_alignment)]
+                    unsafe {
+                        let elem = elems.add(size_of::<LeafElement>() * i) as *mut LeafElement;
+                        *elem = LeafElement {
+                            node_type,
+                            pos: data.offset_from(elem as *mut u8) as u64,
+                            key_size: key.len() as u64,
+                            value_size: value.len() as u64,
+                        };
+                        copy_nonoverlapping(key.as_ptr(), data, key.len());
+                        data = data.add(key.len());
+                        copy_nonoverlapping(value.as_ptr(), data, value.len());
+                        data = data.add(value.len());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Branch<'n> {
+    pub(crate) key: Bytes<'n>,
+    pub(crate) page: PageID,
+}
+
+/// Returns the index of the branch whose subtree may contain `key`.
+pub(crate) fn branch_index(branches: &[Branch], key: &[u8], cmp: &Comparator) -> usize {
+    match branches.binary_search_by(|b| cmp.compare(b.key.as_ref(), key)) {
+        Ok(index) => index,
+        Err(0) => 0,
+        Err(index) => index - 1,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Leaf<'n> {
+    Bucket(Bytes<'n>, BucketMeta),
+    Kv(Bytes<'n>, Bytes<'n>),
+}
+
+impl<'n> Leaf<'n> {
+    pub(crate) fn key(&self) -> &[u8] {
+        match self {
+            Leaf::Bucket(key, _) => key.as_ref(),
+            Leaf::Kv(key, _) => key.as_ref(),
+        }
+    }
+
+    fn value_len(&self) -> usize {
+        match self {
+            Leaf::Bucket(_, _) => size_of::<BucketMeta>(),
+            Leaf::Kv(_, value) => value.as_ref().len(),
+        }
+    }
+}
+
+fn bucket_meta(value: &[u8]) -> BucketMeta {
+    assert_eq!(value.len(), size_of::<BucketMeta>());
+    unsafe { (value.as_ptr() as *const BucketMeta).read_unaligned() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn kv(key: &str, value: &str) -> Leaf<'static> {
+        Leaf::Kv(
+            Bytes::Vec(Rc::new(key.as_bytes().to_vec())),
+            Bytes::Vec(Rc::new(value.as_bytes().to_vec())),
+        )
+    }
+
+    #[test]
+    fn test_write_read_leaves() {
+        let data = NodeData::Leaves(vec![
+            kv("a", "1"),
+            Leaf::Bucket(Bytes::Slice(b"b"), BucketMeta { root_page: 9, next_int: 3, num_keys: 4 }),
+            kv("c", "333"),
+        ]);
+        let mut buf = vec![0u64; 128];
+        #[allow(clippy::cast_ptr_alignment)]
+        let page = unsafe { &mut *(buf.as_mut_ptr() as *mut Page) };
+        data.write(0..3, page);
+
+        let read = NodeData::from_page(page).unwrap();
+        assert_eq!(read.len(), 3);
+        match read {
+            NodeData::Leaves(leaves) => {
+                assert!(matches!(&leaves[0], Leaf::Kv(k, v) if k.as_ref() == b"a" && v.as_ref() == b"1"));
+                assert!(matches!(&leaves[1], Leaf::Bucket(k, m) if k.as_ref() == b"b" && m.root_page == 9));
+                assert!(matches!(&leaves[2], Leaf::Kv(_, v) if v.as_ref() == b"333"));
+            }
+            NodeData::Branches(_) => panic!("expected leaves"),
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        let leaves = (0..100).map(|i| kv(&format!("{:04}", i), "value")).collect();
+        let data = NodeData::Leaves(leaves);
+        let ranges = data.split(1024);
+        assert!(ranges.len() > 1);
+        assert_eq!(ranges.first().unwrap().start, 0);
+        assert_eq!(ranges.last().unwrap().end, 100);
+        for range in ranges {
+            assert!(data.size(range) <= 1024);
+        }
+    }
+
+    #[test]
+    fn test_branch_index() {
+        let branches: Vec<Branch> = [b"b", b"d", b"f"]
+            .iter()
+            .enumerate()
+            .map(|(i, key)| Branch { key: Bytes::Slice(&key[..]), page: i as PageID })
+            .collect();
+        let cmp = Comparator::default();
+        assert_eq!(branch_index(&branches, b"a", &cmp), 0);
+        assert_eq!(branch_index(&branches, b"d", &cmp), 1);
+        assert_eq!(branch_index(&branches, b"e", &cmp), 1);
+        assert_eq!(branch_index(&branches, b"z", &cmp), 2);
+    }
+}
@@ -1,25 +1,21 @@
-use std::{
-    fs::{File, OpenOptions as FileOpenOptions},
-    io::Write,
-};
-use std::os::unix::fs::OpenOptionsExt;
+use std::fs::{File, OpenOptions as FileOpenOptions};
+use std::os::unix::fs::{FileExt as _, OpenOptionsExt};
 use std::path::Path;
-use std::sync::Arc;
 
 use fs2::FileExt;
 use log::error;
 use page_size::get as get_page_size;
 
 use crate::bucket::BucketMeta;
+use crate::config::Config;
 use crate::config::flags::{DBFlags};
 use crate::db::DB;
 use crate::defaults::{DATABASE_INTEGRITY_CODE, DEFAULT_NUM_PAGES, VERSION};
 use crate::errors::Result;
-use crate::inner::Inner;
 use crate::page::Page;
+use crate::utils::{io_alignment, AlignedBuf};
 
 pub struct Options {
-    read_only: bool,
     page_size: u64,
     num_pages: usize,
     #[doc(hidden)]
@@ -28,7 +24,8 @@ pub struct Options {
 }
 
 impl Options {
-    pub(crate) fn new() -> Self {
+    /// Returns the default options, which are the ones `DB::open` uses.
+    pub fn new() -> Self {
         Self::default()
     }
     pub fn page_size(mut self, pagesize: u64) -> Self {
@@ -108,14 +105,15 @@ impl Options {
                 self.page_size,
                 self.num_pages,
                 self.flags.direct_writes,
+                DATABASE_INTEGRITY_CODE,
             )?
         } else {
             open_file(path, false, self.flags.direct_writes)?
         };
-        let db = Inner::open(file, self.page_size, self.flags)?;
-        Ok(DB {
-            inner: Arc::new(db),
-        })
+        Config::new()
+            .flush_every_ms(self.flush_every_ms)
+            .flags(self.flags)
+            .open_file_handle(file)
     }
 }
 
@@ -126,7 +124,6 @@ impl Default for Options {
             panic!("Page size must be 1024 bytes minimum");
         }
         Options {
-            read_only: false,
             page_size,
             num_pages: DEFAULT_NUM_PAGES,
             flush_every_ms: Some(500),
@@ -152,7 +149,7 @@ impl Default for Options {
 /// - [O_DIRECT - Linux manual page](http://man7.org/linux/man-pages/man2/open.2.html)
 /// - [OpenOptions - Rust standard library](https://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
 /// - [File - Rust standard library](https://doc.rust-lang.org/std/fs/struct.File.html)
-#[cfg(any(target_os = "linux"))]
+#[cfg(target_os = "linux")]
 const O_DIRECT: libc::c_int = libc::O_DIRECT;
 
 #[cfg(unix)]
@@ -174,32 +171,54 @@ fn set_open_options(create: bool, direct_write: bool) -> FileOpenOptions {
     open_options
 }
 
-fn init_file(path: &Path, pagesize: u64, num_pages: usize, direct_write: bool) -> Result<File> {
-    let mut file = open_file(path, true, direct_write)?;
+pub(crate) fn init_file(
+    path: &Path,
+    pagesize: u64,
+    num_pages: usize,
+    direct_write: bool,
+    integrity_code: u32,
+) -> Result<File> {
+    let file = open_file(path, true, direct_write)?;
+    let align = io_alignment(&file, direct_write, pagesize)?;
+    init_pages(&file, pagesize, num_pages, integrity_code, align)?;
+    Ok(file)
+}
+
+/// Writes the pages of an empty database to `file`: two meta pages,
+/// an empty freelist and an empty root leaf, with room for `num_pages` pages.
+pub(crate) fn init_pages(
+    file: &File,
+    pagesize: u64,
+    num_pages: usize,
+    integrity_code: u32,
+    align: usize,
+) -> Result<()> {
     file.allocate(
         pagesize * (num_pages as u64)
     )?;
 
-    let mut buf = vec![0; (pagesize * 4) as usize];
-    let mut get_page = |index: u64| {
-        let ptr = &mut buf[(index * pagesize) as usize] as *mut u8;
-        let page_ptr = ptr as *mut Page;
+    let mut buf = AlignedBuf::new((pagesize * 4) as usize, align)?;
+    let buf_ptr = buf.as_mut_slice().as_mut_ptr();
+    let get_page = |index: u64| {
+        #[allow(clippy::cast_ptr_alignment)]
+        let page_ptr = unsafe { buf_ptr.add((index * pagesize) as usize) } as *mut Page;
         unsafe { &mut *page_ptr }
     };
 
     for i in 0..2 {
         let page = get_page(i);
-        page.id = 1;
+        page.id = i;
         page.page_type = Page::TYPE_META;
         let m = page.meta_mut();
         m.meta_page = i as u32;
-        m.integrity_code = DATABASE_INTEGRITY_CODE;
+        m.integrity_code = integrity_code;
         m.version = VERSION;
         m.pagesize = pagesize;
         m.freelist_page = 2;
         m.root = BucketMeta {
             root_page: 3,
             next_int: 0,
+            num_keys: 0,
         };
         m.num_pages = 4;
         m.hash = m.hash_self();
@@ -216,18 +235,18 @@ fn init_file(path: &Path, pagesize: u64, num_pages: usize, direct_write: bool) -
     p.page_type = Page::TYPE_LEAF;
     p.count = 0;
 
-    file.write_all(&buf[..])?;
-    file.flush()?;
+    file.write_all_at(buf.as_slice(), 0)?;
     file.sync_all()?;
-    Ok(file)
+    Ok(())
 }
 
+#[cfg(test)]
 mod tests {
     #[test]
     fn test_init_file() {
         let dir = tempfile::tempdir().unwrap();
         let path = dir.path().join("test.db");
-        let file = super::init_file(&path, 4096, 4, false).unwrap();
+        let file = super::init_file(&path, 4096, 4, false, crate::defaults::DATABASE_INTEGRITY_CODE).unwrap();
 
         dbg!(file);
     }
@@ -1,9 +1,17 @@
+use std::cell::Cell;
+use std::rc::Rc;
 use std::slice::from_raw_parts;
 use std::sync::Arc;
 
 use memmap2::Mmap;
 
+use crate::bytes::Bytes;
+use crate::codec::decompress;
+use crate::config::Codec;
+use crate::errors::{Error, Result};
 use crate::meta::Meta;
+use crate::node::{NodeData, NodeType};
+use crate::pagecache::PageCache;
 
 pub(crate) type PageID = u64;
 
@@ -13,11 +21,236 @@ pub(crate) type PageType = u8;
 pub(crate) struct Pages {
     pub(crate) data: Arc<Mmap>,
     pub(crate) pagesize: u64,
+    // Counts the pages read through this view and its clones, if set
+    reads: Option<Rc<Cell<u64>>>,
+    // Whether try_page validates every page it returns
+    strict: bool,
+    cache: Option<Arc<PageCache>>,
+    // Number of pages sequential scans read ahead of the cursor
+    prefetch: usize,
+    // How values are compressed, if they are
+    codec: Option<Codec>,
 }
 
 impl Pages {
     pub fn new(data: Arc<Mmap>, pagesize: u64) -> Pages {
-        Pages { data, pagesize }
+        Pages {
+            data,
+            pagesize,
+            reads: None,
+            strict: false,
+            cache: None,
+            prefetch: 0,
+            codec: None,
+        }
+    }
+
+    /// Makes [`Pages::node_data`] read through `cache`, if there is one.
+    pub(crate) fn cache(mut self, cache: Option<Arc<PageCache>>) -> Pages {
+        self.cache = cache;
+        self
+    }
+
+    /// Decodes the branch or leaf page `id`, returning its elements and how
+    /// many pages it spans. Pages are taken from the cache when there is one.
+    pub(crate) fn node_data<'a>(&self, id: PageID) -> Result<(NodeData<'a>, u64)> {
+        match &self.cache {
+            Some(cache) => {
+                let node = cache.get_or_insert(id, || self.try_page(id))?;
+                Ok((node.data(), node.num_pages))
+            }
+            None => {
+                let page = self.try_page(id)?;
+                Ok((NodeData::from_page(page)?, page.overflow + 1))
+            }
+        }
+    }
+
+    /// Returns `s` as a [`Bytes::Mmap`] holding on to the mapping if it lies
+    /// within it, such as a value read from a page, or as a `Slice` otherwise.
+    pub(crate) fn pin<'a>(&self, s: &'a [u8]) -> Bytes<'a> {
+        let start = (s.as_ptr() as usize).wrapping_sub(self.data.as_ptr() as usize);
+        match start.checked_add(s.len()) {
+            Some(end) if end <= self.data.len() => Bytes::Mmap(self.data.clone(), start..end),
+            _ => Bytes::Slice(s),
+        }
+    }
+
+    /// Makes [`Pages::try_page`] validate pages as [`Pages::checked_page`] does.
+    pub(crate) fn strict(mut self, strict: bool) -> Pages {
+        self.strict = strict;
+        self
+    }
+
+    pub(crate) fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Makes cursors moving forward advise the OS that they will need the
+    /// next `pages` pages of the branch they're walking.
+    pub(crate) fn prefetch(mut self, pages: usize) -> Pages {
+        self.prefetch = pages;
+        self
+    }
+
+    pub(crate) fn prefetch_window(&self) -> usize {
+        self.prefetch
+    }
+
+    /// Makes values be compressed with `codec` when they're put, and
+    /// decompressed when they're read.
+    pub(crate) fn codec(mut self, codec: Option<Codec>) -> Pages {
+        self.codec = codec;
+        self
+    }
+
+    /// Encodes a value to be put, compressing it if there is a codec.
+    pub(crate) fn encode<'a>(&self, value: Bytes<'a>) -> Bytes<'a> {
+        match self.codec {
+            Some(codec) => codec.compress(&value).into(),
+            None => value,
+        }
+    }
+
+    /// Decodes a value read from a page, undoing [`Pages::encode`].
+    pub(crate) fn decode<'a>(&self, value: Bytes<'a>) -> Result<Bytes<'a>> {
+        match self.codec {
+            Some(_) => decompress(value),
+            None => Ok(value),
+        }
+    }
+
+    /// Advises the OS that page `id` will be read soon, so it can start reading
+    /// it in. This is only a hint, so failures are ignored.
+    pub(crate) fn will_need(&self, id: PageID) {
+        let offset = id.saturating_mul(self.pagesize);
+        if offset.saturating_add(self.pagesize) <= self.data.len() as u64 {
+            let _ = self.data.advise_range(memmap2::Advice::WillNeed, offset as usize, self.pagesize as usize);
+        }
+    }
+
+    /// Makes [`Pages::try_page`] and [`Pages::checked_page`] count the pages
+    /// they return in `reads`, which is shared by every clone of this view.
+    pub(crate) fn count_reads(mut self, reads: Rc<Cell<u64>>) -> Pages {
+        self.reads = Some(reads);
+        self
+    }
+
+    fn count_read(&self) {
+        if let Some(reads) = &self.reads {
+            reads.set(reads.get() + 1);
+        }
+    }
+
+    /// Like [`Pages::page`], but returns [`Error::InvalidDB`] instead of reading
+    /// past the end of the mmap when the file is shorter than the page requires.
+    ///
+    /// In strict mode the page is also validated with [`Pages::checked_page`].
+    pub fn try_page<'a>(&self, id: PageID) -> Result<&'a Page> {
+        let page = self.bounded_page(id)?;
+        if self.strict {
+            self.check(id, page)?;
+        }
+        self.count_read();
+        Ok(page)
+    }
+
+    /// Like [`Pages::try_page`], but also checks that the page is structurally sound:
+    /// it has the expected id and a known type, its elements and overflow pages lie
+    /// within the file, and a meta page's hash matches. A corrupt page is reported
+    /// as [`Error::InvalidDB`] rather than read out of bounds.
+    pub(crate) fn checked_page<'a>(&self, id: PageID) -> Result<&'a Page> {
+        let page = self.bounded_page(id)?;
+        self.check(id, page)?;
+        self.count_read();
+        Ok(page)
+    }
+
+    fn bounded_page<'a>(&self, id: PageID) -> Result<&'a Page> {
+        let end = id
+            .checked_add(1)
+            .and_then(|n| n.checked_mul(self.pagesize));
+        match end {
+            Some(end) if end <= self.data.len() as u64 => Ok(self.page(id)),
+            _ => Err(Error::InvalidDB(format!(
+                "page {} is beyond the end of the file ({} bytes)",
+                id,
+                self.data.len()
+            ))),
+        }
+    }
+
+    fn check(&self, id: PageID, page: &Page) -> Result<()> {
+        let corrupt = |reason: &str| Err(Error::InvalidDB(format!("page {} is corrupt: {}", id, reason)));
+        if page.id != id {
+            return corrupt("its header has the wrong id");
+        }
+        let block_size = page
+            .overflow
+            .checked_add(1)
+            .and_then(|n| n.checked_mul(self.pagesize))
+            .filter(|size| {
+                id.checked_mul(self.pagesize)
+                    .and_then(|start| start.checked_add(*size))
+                    .is_some_and(|end| end <= self.data.len() as u64)
+            });
+        let data_size = match block_size {
+            Some(size) => size - Page::HEADER_SIZE,
+            None => return corrupt("its overflow pages run past the end of the file"),
+        };
+        // Checks that `count` elements of `elem_size` bytes fit in the page's data
+        let elems_fit = |elem_size: usize| {
+            page.count
+                .checked_mul(elem_size as u64)
+                .is_some_and(|size| size <= data_size)
+        };
+        // Checks that the bytes an element at `index` points to lie in the page's data
+        let within = |elem_size: usize, index: usize, pos: u64, len: u64| {
+            ((index * elem_size) as u64)
+                .checked_add(pos)
+                .and_then(|end| end.checked_add(len))
+                .is_some_and(|end| end <= data_size)
+        };
+
+        match page.page_type {
+            Page::TYPE_META => {
+                if !page.meta().valid() {
+                    return corrupt("the meta hash does not match");
+                }
+            }
+            Page::TYPE_FREELIST => {
+                if !elems_fit(size_of::<PageID>()) {
+                    return corrupt("the freelist is larger than the page");
+                }
+            }
+            Page::TYPE_BRANCH => {
+                let elem_size = size_of::<BranchElement>();
+                if !elems_fit(elem_size) {
+                    return corrupt("the element count is larger than the page");
+                }
+                let elems = page.branch_elements();
+                if !elems.iter().enumerate().all(|(i, e)| within(elem_size, i, e.pos, e.key_size)) {
+                    return corrupt("a key lies outside the page");
+                }
+            }
+            Page::TYPE_LEAF => {
+                let elem_size = size_of::<LeafElement>();
+                if !elems_fit(elem_size) {
+                    return corrupt("the element count is larger than the page");
+                }
+                let elems = page.leaf_elements();
+                let fits = elems.iter().enumerate().all(|(i, e)| {
+                    e.key_size
+                        .checked_add(e.value_size)
+                        .is_some_and(|len| within(elem_size, i, e.pos, len))
+                });
+                if !fits {
+                    return corrupt("a key or value lies outside the page");
+                }
+            }
+            t => return corrupt(&format!("unknown page type {}", t)),
+        }
+        Ok(())
     }
 
     #[inline]
@@ -39,11 +272,16 @@ pub(crate) struct Page {
     pub(crate) count: u64,
     // Number of additional pages after this one that are part of this block
     pub(crate) overflow: u64,
+    // The transaction that wrote this page, which lets backups find what changed
+    pub(crate) tx_id: u64,
     // ptr serves as a reference to where the actual data starts
     pub(crate) ptr: u64,
 }
 
 impl Page {
+    // Offset of the page's data from the start of the page
+    pub(crate) const HEADER_SIZE: u64 = (size_of::<Page>() - size_of::<u64>()) as u64;
+
     pub(crate) const TYPE_BRANCH: PageType = 0x01;
     pub(crate) const TYPE_LEAF: PageType = 0x02;
     pub(crate) const TYPE_META: PageType = 0x03;
@@ -54,11 +292,21 @@ impl Page {
         unsafe { &*(&self.ptr as *const u64 as *const Meta) }
     }
 
+    /// Like [`Page::meta`], but returns [`Error::InvalidDB`] instead of panicking
+    /// when this isn't a meta page, as in a file that isn't a database.
+    pub(crate) fn try_meta(&self) -> Result<&Meta> {
+        if self.page_type != Page::TYPE_META {
+            return Err(Error::invalid_db(format!("page {} is not a meta page", self.id)));
+        }
+        Ok(self.meta())
+    }
+
     pub(crate) fn meta_mut(&mut self) -> &mut Meta {
         assert_eq!(self.page_type, Page::TYPE_META);
         unsafe { &mut *(&mut self.ptr as *mut u64 as *mut Meta) }
     }
 
+    #[cfg(test)]
     #[inline]
     pub(crate) fn from_buf(buf: &[u8], id: PageID, pagesize: u64) -> &Page {
         #[allow(clippy::cast_ptr_alignment)]
@@ -67,6 +315,17 @@ impl Page {
         }
     }
 
+    pub(crate) fn branch_elements(&self) -> &[BranchElement] {
+        assert_eq!(self.page_type, Page::TYPE_BRANCH);
+        let start = &self.ptr as *const u64 as *const BranchElement;
+        unsafe { from_raw_parts(start, self.count as usize) }
+    }
+
+    pub(crate) fn leaf_elements(&self) -> &[LeafElement] {
+        assert_eq!(self.page_type, Page::TYPE_LEAF);
+        let start = &self.ptr as *const u64 as *const LeafElement;
+        unsafe { from_raw_parts(start, self.count as usize) }
+    }
 
     pub(crate) fn freelist(&self) -> &[PageID] {
         assert_eq!(self.page_type, Page::TYPE_FREELIST);
@@ -77,6 +336,50 @@ impl Page {
     }
 }
 
+// Elements are laid out from the start of a page's data, followed by their keys and values.
+// `pos` is the offset from the element itself to its key, so elements can be read in place.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct BranchElement {
+    pub(crate) page: PageID,
+    pub(crate) key_size: u64,
+    pub(crate) pos: u64,
+}
+
+impl BranchElement {
+    pub(crate) fn key<'a>(&self) -> &'a [u8] {
+        let start = self as *const BranchElement as *const u8;
+        unsafe { from_raw_parts(start.add(self.pos as usize), self.key_size as usize) }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct LeafElement {
+    pub(crate) node_type: NodeType,
+    pub(crate) pos: u64,
+    pub(crate) key_size: u64,
+    pub(crate) value_size: u64,
+}
+
+impl LeafElement {
+    pub(crate) fn key<'a>(&self) -> &'a [u8] {
+        let start = self as *const LeafElement as *const u8;
+        unsafe { from_raw_parts(start.add(self.pos as usize), self.key_size as usize) }
+    }
+
+    pub(crate) fn value<'a>(&self) -> &'a [u8] {
+        let start = self as *const LeafElement as *const u8;
+        unsafe {
+            from_raw_parts(
+                start.add((self.pos + self.key_size) as usize),
+                self.value_size as usize,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use crate::sys::sys_limits;
 
@@ -84,4 +387,31 @@ mod tests {
     fn test_new_page() {
         dbg!(sys_limits::get_memory_limit());
     }
+
+    #[test]
+    fn test_try_page_out_of_bounds() {
+        let data = memmap2::MmapMut::map_anon(4096 * 4).unwrap().make_read_only().unwrap();
+        let pages = super::Pages::new(std::sync::Arc::new(data), 4096);
+        assert!(pages.try_page(3).is_ok());
+        assert!(matches!(
+            pages.try_page(4),
+            Err(crate::errors::Error::InvalidDB(_))
+        ));
+        assert!(pages.try_page(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_pages_read_counter() {
+        let data = memmap2::MmapMut::map_anon(4096 * 4).unwrap().make_read_only().unwrap();
+        let reads = std::rc::Rc::new(std::cell::Cell::new(0));
+        let pages = super::Pages::new(std::sync::Arc::new(data), 4096).count_reads(reads.clone());
+        let clone = pages.clone();
+        pages.try_page(0).unwrap();
+        clone.try_page(3).unwrap();
+        assert!(clone.try_page(4).is_err());
+        assert_eq!(reads.get(), 2);
+        // the raw accessor isn't counted
+        pages.page(1);
+        assert_eq!(reads.get(), 2);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, HashMap};
+use std::slice::from_raw_parts;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+
+use crate::bucket::BucketMeta;
+use crate::bytes::Bytes;
+use crate::errors::Result;
+use crate::node::{Branch, Leaf, NodeData};
+use crate::page::{Page, PageID};
+
+/// A branch or leaf page decoded once and shared by every transaction that reads it.
+///
+/// Keys and values are slices of a single copy of the page, so a transaction
+/// holding on to them keeps that copy alive even after the cache evicts it.
+pub(crate) struct CachedNode {
+    pub(crate) num_pages: u64,
+    elems: CachedElems,
+}
+
+enum CachedElems {
+    Branches(Vec<(bytes::Bytes, PageID)>),
+    Leaves(Vec<CachedLeaf>),
+}
+
+enum CachedLeaf {
+    Bucket(bytes::Bytes, BucketMeta),
+    Kv(bytes::Bytes, bytes::Bytes),
+}
+
+impl CachedNode {
+    /// Copies `page` and its overflow pages out of the mmap and decodes them.
+    pub(crate) fn from_page(page: &Page, pagesize: u64) -> Result<CachedNode> {
+        let num_pages = page.overflow + 1;
+        let start = page as *const Page as *const u8;
+        let block = unsafe { from_raw_parts(start, (num_pages * pagesize) as usize) };
+        let copy = bytes::Bytes::copy_from_slice(block);
+        // Points a slice of the mmap at the same bytes in the copy
+        let slice = |s: &[u8]| {
+            let offset = s.as_ptr() as usize - start as usize;
+            copy.slice(offset..offset + s.len())
+        };
+
+        let elems = match NodeData::from_page(page)? {
+            NodeData::Branches(branches) => CachedElems::Branches(
+                branches
+                    .iter()
+                    .map(|b| (slice(b.key.as_slice()), b.page))
+                    .collect(),
+            ),
+            NodeData::Leaves(leaves) => CachedElems::Leaves(
+                leaves
+                    .iter()
+                    .map(|l| match l {
+                        Leaf::Bucket(key, meta) => CachedLeaf::Bucket(slice(key), *meta),
+                        Leaf::Kv(key, value) => CachedLeaf::Kv(slice(key), slice(value)),
+                    })
+                    .collect(),
+            ),
+        };
+        Ok(CachedNode { num_pages, elems })
+    }
+
+    pub(crate) fn data<'n>(&self) -> NodeData<'n> {
+        match &self.elems {
+            CachedElems::Branches(branches) => NodeData::Branches(
+                branches
+                    .iter()
+                    .map(|(key, page)| Branch {
+                        key: Bytes::Bytes(key.clone()),
+                        page: *page,
+                    })
+                    .collect(),
+            ),
+            CachedElems::Leaves(leaves) => NodeData::Leaves(
+                leaves
+                    .iter()
+                    .map(|l| match l {
+                        CachedLeaf::Bucket(key, meta) => Leaf::Bucket(Bytes::Bytes(key.clone()), *meta),
+                        CachedLeaf::Kv(key, value) => Leaf::Kv(Bytes::Bytes(key.clone()), Bytes::Bytes(value.clone())),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    // Bytes held by this node: the copy of the page plus the decoded elements
+    fn size(&self, pagesize: u64) -> u64 {
+        let elems = match &self.elems {
+            CachedElems::Branches(b) => b.len(),
+            CachedElems::Leaves(l) => l.len(),
+        };
+        self.num_pages * pagesize + (elems * size_of::<CachedLeaf>()) as u64
+    }
+}
+
+struct Entry {
+    node: Arc<CachedNode>,
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<PageID, Entry>,
+    // Page ids by the tick they were last used at, least recently used first
+    order: BTreeMap<u64, PageID>,
+    tick: u64,
+    size: u64,
+}
+
+/// Caches decoded pages by id, evicting the least recently used ones
+/// once they take up more than `capacity` bytes.
+///
+/// Pages are cached by id, so a commit must call [`PageCache::invalidate`]
+/// for every page it writes.
+pub(crate) struct PageCache {
+    capacity: u64,
+    pagesize: u64,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl PageCache {
+    pub(crate) fn new(capacity: u64, pagesize: u64) -> PageCache {
+        PageCache {
+            capacity,
+            pagesize,
+            lru: Mutex::new(Lru::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the decoded page `id`, decoding and caching `page` on a miss.
+    pub(crate) fn get_or_insert(&self, id: PageID, page: impl FnOnce() -> Result<&'static Page>) -> Result<Arc<CachedNode>> {
+        let mut lru = self.lru.lock();
+        lru.tick += 1;
+        let tick = lru.tick;
+        if let Some(entry) = lru.entries.get_mut(&id) {
+            let last_used = std::mem::replace(&mut entry.last_used, tick);
+            let node = entry.node.clone();
+            lru.order.remove(&last_used);
+            lru.order.insert(tick, id);
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(node);
+        }
+        drop(lru);
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let node = Arc::new(CachedNode::from_page(page()?, self.pagesize)?);
+        let size = node.size(self.pagesize);
+        if size > self.capacity {
+            return Ok(node);
+        }
+
+        let mut lru = self.lru.lock();
+        lru.tick += 1;
+        let tick = lru.tick;
+        let entry = Entry {
+            node: node.clone(),
+            size,
+            last_used: tick,
+        };
+        if let Some(old) = lru.entries.insert(id, entry) {
+            lru.order.remove(&old.last_used);
+            lru.size -= old.size;
+        }
+        lru.order.insert(tick, id);
+        lru.size += size;
+        while lru.size > self.capacity {
+            let (_, evict) = lru.order.pop_first().unwrap();
+            let entry = lru.entries.remove(&evict).unwrap();
+            lru.size -= entry.size;
+        }
+        Ok(node)
+    }
+
+    /// Drops any cached copy of the `num_pages` pages starting at `id`.
+    pub(crate) fn invalidate(&self, id: PageID, num_pages: u64) {
+        let mut lru = self.lru.lock();
+        for id in id..id + num_pages {
+            if let Some(entry) = lru.entries.remove(&id) {
+                lru.order.remove(&entry.last_used);
+                lru.size -= entry.size;
+            }
+        }
+    }
+
+    /// Returns the bytes taken up by the cached pages, which never exceeds the capacity.
+    pub(crate) fn size(&self) -> u64 {
+        self.lru.lock().size
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+
+    use super::*;
+
+    #[test]
+    fn test_repeated_reads_hit() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap().put("key", "value").unwrap();
+        tx.commit().unwrap();
+
+        let read = || {
+            let tx = db.tx(false).unwrap();
+            let bucket = tx.bucket(b"data").unwrap();
+            assert_eq!(bucket.get(b"key").unwrap().unwrap().as_slice(), b"value");
+        };
+        read();
+        let first = db.stats().unwrap();
+        assert!(first.cache_misses > 0);
+        read();
+        let second = db.stats().unwrap();
+        assert_eq!(second.cache_misses, first.cache_misses);
+        assert!(second.cache_hits > first.cache_hits);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let pagesize = 1024;
+        let mut buf = vec![0u64; 3 * 128];
+        for id in 0..3 {
+            #[allow(clippy::cast_ptr_alignment)]
+            let page = unsafe { &mut *(buf[id * 128..].as_mut_ptr() as *mut Page) };
+            page.id = id as PageID;
+            NodeData::Leaves(Vec::new()).write(0..0, page);
+        }
+        let page = |id: PageID| -> Result<&'static Page> {
+            Ok(unsafe { &*(buf[id as usize * 128..].as_ptr() as *const Page) })
+        };
+
+        // room for two pages
+        let cache = PageCache::new(2 * pagesize + 64, pagesize);
+        cache.get_or_insert(0, || page(0)).unwrap();
+        cache.get_or_insert(1, || page(1)).unwrap();
+        cache.get_or_insert(0, || page(0)).unwrap();
+        cache.get_or_insert(2, || page(2)).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (1, 3));
+        // 1 was the least recently used
+        cache.get_or_insert(0, || page(0)).unwrap();
+        cache.get_or_insert(1, || page(1)).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (2, 4));
+
+        cache.invalidate(0, 2);
+        cache.get_or_insert(0, || page(0)).unwrap();
+        assert_eq!(cache.misses(), 5);
+    }
+
+    #[test]
+    fn test_capacity_enforced() {
+        let dir = tempfile::tempdir().unwrap();
+        let capacity = 16 * 1024;
+        let db = Config::new().path(dir.path()).cache_capacity(capacity).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..10000 {
+            bucket.put(format!("key{:05}", i), "value").unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        let get = |i: usize| bucket.get(format!("key{:05}", i).as_bytes()).unwrap().unwrap();
+        get(0);
+        assert!(db.cache_size() > 0);
+        // every leaf is read once, far more than fits
+        for i in (0..10000).step_by(50) {
+            get(i);
+            assert!(db.cache_size() <= capacity);
+        }
+        // the first leaf was evicted to make room
+        let misses = db.stats().unwrap().cache_misses;
+        get(0);
+        assert!(db.stats().unwrap().cache_misses > misses);
+    }
+}
@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+use std::ops::RangeBounds;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use memmap2::Mmap;
+
+use crate::bucket::{Bucket, InnerBucket};
+use crate::bytes::Bytes;
+use crate::context::Context;
+use crate::cursor::RangeIter;
+use crate::errors::Result;
+use crate::freelist::{Freelist, TxFreelist};
+use crate::meta::Meta;
+use crate::node::Leaf;
+use crate::page::Pages;
+
+/// A consistent, read-only view of the database as of the moment it was taken.
+///
+/// Unlike a read-only [`Tx`](crate::Tx), a `Snapshot` does not borrow the
+/// [`DB`](crate::db::DB) and can be sent to other threads. Like a reader, it
+/// pins the pages it can see so writers won't reuse them until it is dropped.
+pub struct Snapshot {
+    context: Context,
+    data: Arc<Mmap>,
+    meta: Meta,
+}
+
+impl Snapshot {
+    pub(crate) fn new(context: &Context) -> Result<Snapshot> {
+        // Registering under the same lock writers use to decide which pages
+        // can be reclaimed means none of our pages can be released in between.
+        let mut open_ro_txs = context.open_ro_txs.lock();
+        let meta = context.meta()?;
+        let data = context.data.lock().clone();
+        open_ro_txs.push(meta.tx_id);
+        drop(open_ro_txs);
+
+        Ok(Snapshot {
+            context: context.clone(),
+            data,
+            meta,
+        })
+    }
+
+    /// The id of the last transaction committed before the snapshot was taken.
+    pub fn tx_id(&self) -> u64 {
+        self.meta.tx_id
+    }
+
+    /// Returns the top level bucket named `name`, which can be read like one
+    /// from a read-only transaction.
+    ///
+    /// Returns [`Error::BucketMissing`](crate::errors::Error::BucketMissing) if there
+    /// is no such bucket, and [`Error::IncompatibleValue`](crate::errors::Error::IncompatibleValue)
+    /// if `name` holds a key / value pair instead.
+    pub fn bucket(&self, name: &[u8]) -> Result<Bucket<'_, '_>> {
+        let bucket = self.root().borrow_mut().get_bucket(name)?;
+        Ok(Bucket::new(bucket, false))
+    }
+
+    /// Returns the value stored under `key` in the top level bucket named `bucket`,
+    /// as [`Bucket::get`] does.
+    pub fn get(&self, bucket: &[u8], key: &[u8]) -> Result<Option<Bytes<'_>>> {
+        self.bucket(bucket)?.get(key)
+    }
+
+    /// Returns an iterator over the key / value pairs in the top level bucket named
+    /// `bucket` whose keys lie in `range`, as [`Bucket::range`] does.
+    pub fn range<K: AsRef<[u8]> + ?Sized, R: RangeBounds<K>>(&self, bucket: &[u8], range: R) -> Result<RangeIter<'_, '_>> {
+        Ok(self.bucket(bucket)?.range(range))
+    }
+
+    /// Returns the names of the top level buckets, in sorted order.
+    pub fn bucket_names(&self) -> Result<Vec<Bytes<'_>>> {
+        let mut names = Vec::new();
+        let mut cursor = Bucket::new(self.root(), false).cursor();
+        while let Some(leaf) = cursor.next_leaf()? {
+            if let Leaf::Bucket(name, _) = leaf {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    // The bucket holding every top level bucket, read as a read-only transaction would.
+    // It's built for each call, as the Rc it lives in can't be sent along with the snapshot.
+    fn root(&self) -> Rc<RefCell<InnerBucket<'_>>> {
+        let context = &self.context;
+        let pages = Pages::new(self.data.clone(), context.pagesize)
+            .strict(context.flags.strict_mode)
+            .cache(context.pagecache.clone())
+            .prefetch(context.scan_prefetch)
+            .codec(context.compression);
+        let freelist = Rc::new(RefCell::new(TxFreelist::new(
+            self.meta.clone(),
+            Freelist::new(),
+            context.io_align,
+            context.mode,
+            context.fill_percent,
+            context.max_value_size,
+            context.max_tx_dirty_bytes,
+        )));
+        let root = InnerBucket::from_meta(self.meta.root, pages, freelist).with_comparators(context.comparators.clone());
+        Rc::new(RefCell::new(root))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut open_ro_txs = self.context.open_ro_txs.lock();
+        if let Some(index) = open_ro_txs.iter().position(|id| *id == self.meta.tx_id) {
+            open_ro_txs.swap_remove(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::errors::Error;
+
+    fn assert_send<T: Send + 'static>(_: &T) {}
+
+    #[test]
+    fn test_snapshot_registers_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let snapshot = db.snapshot().unwrap();
+        assert_send(&snapshot);
+        assert_eq!(*db.context.open_ro_txs.lock(), vec![snapshot.tx_id()]);
+
+        let handle = std::thread::spawn(move || snapshot.tx_id());
+        handle.join().unwrap();
+        assert!(db.context.open_ro_txs.lock().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..1000 {
+            bucket.put(format!("key{:04}", i), format!("value{}", i)).unwrap();
+        }
+        tx.create_bucket(b"other").unwrap();
+        tx.commit().unwrap();
+        let snapshot = db.snapshot().unwrap();
+
+        // changes committed after the snapshot was taken can't be seen through it
+        let tx = db.tx(true).unwrap();
+        tx.bucket(b"data").unwrap().put("key0001", "changed").unwrap();
+        tx.delete_bucket(b"other").unwrap();
+        tx.commit().unwrap();
+
+        let handle = std::thread::spawn(move || {
+            let value = snapshot.get(b"data", b"key0001").unwrap().unwrap();
+            assert_eq!(value.as_slice(), b"value1");
+            assert!(snapshot.get(b"data", b"missing").unwrap().is_none());
+            assert_eq!(snapshot.get(b"missing", b"key0001"), Err(Error::BucketMissing));
+
+            let keys: Vec<_> = snapshot
+                .range(b"data", "key0100".."key0103")
+                .unwrap()
+                .map(|pair| pair.unwrap().0.to_vec())
+                .collect();
+            assert_eq!(keys, vec![b"key0100".to_vec(), b"key0101".to_vec(), b"key0102".to_vec()]);
+
+            let names: Vec<_> = snapshot.bucket_names().unwrap().iter().map(|n| n.to_vec()).collect();
+            assert_eq!(names, vec![b"data".to_vec(), b"other".to_vec()]);
+            // a snapshot is read-only
+            let bucket = snapshot.bucket(b"data").unwrap();
+            assert_eq!(bucket.put("key", "value"), Err(Error::ReadOnlyTx));
+        });
+        handle.join().unwrap();
+        assert!(db.context.open_ro_txs.lock().is_empty());
+    }
+}
@@ -1,38 +1,54 @@
 #![allow(unsafe_code)]
 
 use std::convert::TryFrom;
-#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[cfg(target_os = "linux")]
 use std::io;
 
-#[cfg(any(target_os = "linux"))]
-use {std::fs::File, std::io::Read};
+#[cfg(target_os = "linux")]
+use {std::fs::File, std::io::Read, std::path::Path};
+
+#[cfg(target_os = "linux")]
+const CGROUP_V2_MEMORY_MAX: &str = "/sys/fs/cgroup/memory.max";
+#[cfg(target_os = "linux")]
+const CGROUP_V1_MEMORY_LIMIT: &str = "/sys/fs/cgroup/memory/memory.limit_in_bytes";
 
-/// See the Kernel's documentation for more information about this subsystem,
-/// found at:  [Documentation/cgroup-v1/memory.txt](https://www.kernel.org/doc/Documentation/cgroup-v1/memory.txt)
+/// Returns the memory limit of the process's cgroup, or `None` if it is unlimited.
+///
+/// cgroup v2 is tried first, which writes `max` when there is no limit. See the
+/// Kernel's documentation for more information about this subsystem, found at:
+/// [Documentation/admin-guide/cgroup-v2.rst](https://www.kernel.org/doc/Documentation/admin-guide/cgroup-v2.rst)
+/// and [Documentation/cgroup-v1/memory.txt](https://www.kernel.org/doc/Documentation/cgroup-v1/memory.txt)
 ///
-/// If there's no memory limit specified on the container this may return
+/// If there's no memory limit specified on a v1 container this may return
 /// 0x7FFFFFFFFFFFF000 (2^63-1 rounded down to 4k which is a common page size).
 /// So we know we are not running in a memory restricted environment.
 #[cfg(target_os = "linux")]
-fn get_cgroup_memory_limit() -> io::Result<u64> {
-    File::open("/sys/fs/cgroup/memory/memory.limit_in_bytes")
-        .and_then(read_u64_from)
+fn get_cgroup_memory_limit() -> io::Result<Option<u64>> {
+    read_limit_from(CGROUP_V2_MEMORY_MAX).or_else(|_| read_limit_from(CGROUP_V1_MEMORY_LIMIT))
 }
 
 #[cfg(target_os = "linux")]
-fn read_u64_from(mut file: File) -> io::Result<u64> {
+fn read_limit_from<P: AsRef<Path>>(path: P) -> io::Result<Option<u64>> {
     let mut s = String::new();
-    file.read_to_string(&mut s).and_then(|_| {
-        s.trim()
+    File::open(path)?.read_to_string(&mut s)?;
+    parse_limit(&s)
+}
+
+#[cfg(target_os = "linux")]
+fn parse_limit(s: &str) -> io::Result<Option<u64>> {
+    match s.trim() {
+        "max" => Ok(None),
+        limit => limit
             .parse()
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
-    })
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
 }
 
 /// Returns the maximum size of total available memory of the process, in bytes.
 /// If this limit is exceeded, the malloc() and mmap() functions shall fail with
 /// errno set to [ENOMEM].
-#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[cfg(target_os = "linux")]
 fn get_rlimit_as() -> io::Result<libc::rlimit> {
     let mut limit = std::mem::MaybeUninit::<libc::rlimit>::uninit();
 
@@ -45,7 +61,7 @@ fn get_rlimit_as() -> io::Result<libc::rlimit> {
     }
 }
 
-#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[cfg(target_os = "linux")]
 pub fn get_available_memory() -> io::Result<usize> {
     let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
     if pages == -1 {
@@ -65,14 +81,20 @@ pub fn get_memory_limit() -> Option<usize> {
     None
 }
 
-#[cfg(not(miri))]
+#[cfg(all(not(miri), not(target_os = "linux")))]
+pub fn get_memory_limit() -> Option<usize> {
+    None
+}
+
+#[cfg(all(not(miri), target_os = "linux"))]
 pub fn get_memory_limit() -> Option<usize> {
     let mut max: u64 = 0;
 
-    #[cfg(target_os = "linux")]
     {
-        if let Ok(mem) = get_cgroup_memory_limit() {
-            max = mem;
+        match get_cgroup_memory_limit() {
+            Ok(Some(mem)) => max = mem,
+            Ok(None) => return None,
+            Err(_) => {}
         }
 
         // If there's no memory limit specified on the container this
@@ -86,7 +108,6 @@ pub fn get_memory_limit() -> Option<usize> {
     }
 
     #[allow(clippy::useless_conversion)]
-    #[cfg(any(target_os = "linux", target_os = "macos"))]
     {
         if let Ok(rlim) = get_rlimit_as() {
             let rlim_cur = u64::try_from(rlim.rlim_cur).unwrap();
@@ -121,3 +142,21 @@ pub fn get_memory_limit() -> Option<usize> {
     }
 }
 
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("memory.max");
+        std::fs::write(&path, "max\n").unwrap();
+        assert_eq!(read_limit_from(&path).unwrap(), None);
+        std::fs::write(&path, "536870912\n").unwrap();
+        assert_eq!(read_limit_from(&path).unwrap(), Some(536870912));
+
+        assert!(parse_limit("lots").is_err());
+        assert!(read_limit_from(dir.path().join("missing")).is_err());
+    }
+}
@@ -1,52 +1,800 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::FileExt;
 use std::rc::Rc;
-use parking_lot::{MutexGuard, RwLockReadGuard};
-use crate::bucket::InnerBucket;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use log::error;
+use parking_lot::MutexGuard;
+use crate::bucket::{Bucket, InnerBucket};
+use crate::bytes::Bytes;
 
 use crate::db::DB;
-use crate::errors::Result;
+use crate::errors::{Error, Result};
 use crate::freelist::TxFreelist;
 use crate::meta::Meta;
-use crate::page::Pages;
+use crate::node::Leaf;
+use crate::page::{Page, Pages};
+use crate::utils::AlignedBuf;
 
 pub(crate) enum TxLock<'tx> {
-    Rw(MutexGuard<'tx, File>),
-    Ro(RwLockReadGuard<'tx, ()>),
+    Rw(MutexGuard<'tx, Arc<File>>),
+    // Readers take no lock: their pages keep the mapping they started with alive,
+    // and registering in `open_ro_txs` keeps the file from shrinking under them
+    Ro,
 }
 
 impl<'tx> TxLock<'tx> {
     fn writable(&self) -> bool {
         match self {
             Self::Rw(_) => true,
-            Self::Ro(_) => false,
+            Self::Ro => false,
         }
     }
 }
 
+/// Counters accumulated over the lifetime of a single transaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TxStats {
+    /// Pages read from the memory map
+    pub pages_read: u64,
+    /// Pages modified by the transaction that will be rewritten on commit
+    pub pages_dirtied: u64,
+    /// Overflow pages allocated to hold large nodes or values
+    pub overflow_pages: u64,
+    /// Bytes of values written by the transaction
+    pub bytes_written: u64,
+    /// Pages allocated for nodes, values and the freelist
+    pub pages_allocated: u64,
+    /// Pages the transaction stopped using, which become free once no reader needs them
+    pub pages_freed: u64,
+    /// Nodes too large for one page that were split into several
+    pub splits: u64,
+    /// Pages written to the file on commit, the meta page included
+    pub pages_written: u64,
+}
+
+/// A copy of the meta a transaction was started from, for debugging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetaInfo {
+    /// The id of the transaction: one more than the last committed one for a
+    /// writable transaction, the id of the snapshot it reads for a read-only one
+    pub tx_id: u64,
+    /// Pages in use in the file, up to the highest one allocated
+    pub num_pages: u64,
+    /// The root page of the bucket holding every top level bucket
+    pub root_page: u64,
+    /// The first page of the freelist
+    pub freelist_page: u64,
+    /// The pagesize the database was created with
+    pub pagesize: u64,
+}
+
 pub struct Tx<'tx> {
     pub(crate) inner: RefCell<TxInner<'tx>>,
 }
 
+/// Dropping a transaction that wasn't committed rolls it back.
+///
+/// Dirty nodes and the page buffers allocated for them are dropped with the
+/// transaction, the meta on disk was never touched, and the writer lock is
+/// released with the guard. A read-only transaction is unregistered by its
+/// [`Reader`], so writers can reuse the pages it could see.
 pub(crate) struct TxInner<'tx> {
     pub(crate) db: &'tx DB,
     pub(crate) lock: TxLock<'tx>,
+    // Only held to be dropped with the transaction
+    _reader: Option<Reader<'tx>>,
     pub(crate) root: Rc<RefCell<InnerBucket<'tx>>>,
     pub(crate) meta: Meta,
     pub(crate) freelist: Rc<RefCell<TxFreelist>>,
     pages: Pages,
+    // Pages read by the transaction, counted by every view of `pages`
+    reads: Rc<Cell<u64>>,
     num_freelist_pages: u64,
 }
 
 impl <'tx> Tx<'tx> {
     pub(crate) fn new(db: &'tx DB, writable: bool) -> Result<Tx<'tx>> {
         let lock = match writable {
-            true => TxLock::Rw(db.inner.file.lock()),
-            false => TxLock::Ro(db.inner.mmap_lock.read()),
+            true => TxLock::Rw(db.context.file.lock()),
+            false => TxLock::Ro,
+        };
+        Tx::with_lock(db, lock)
+    }
+
+    /// Like [`Tx::new`], but gives up with [`Error::Timeout`] if the lock
+    /// can't be acquired within `timeout`.
+    pub(crate) fn with_timeout(db: &'tx DB, writable: bool, timeout: Duration) -> Result<Tx<'tx>> {
+        let lock = match writable {
+            true => db.context.file.try_lock_for(timeout).map(TxLock::Rw),
+            false => Some(TxLock::Ro),
+        };
+        match lock {
+            Some(lock) => Tx::with_lock(db, lock),
+            None => Err(Error::Timeout),
+        }
+    }
+
+    fn with_lock(db: &'tx DB, lock: TxLock<'tx>) -> Result<Tx<'tx>> {
+        if lock.writable() && db.context.read_only {
+            return Err(Error::ReadOnlyTx);
+        }
+        if lock.writable() && db.context.is_degraded() {
+            return Err(Error::Io(ErrorKind::Other, "background flush failed"));
+        }
+        let mut freelist = db.context.freelist.lock().clone();
+        let (mut meta, reader) = if lock.writable() {
+            let mut meta = db.context.latest_meta()?;
+            // Readers that have finished since the last commit may let more pages be reused
+            freelist.release(db.context.reusable_before(meta.tx_id + 1));
+            meta.tx_id += 1;
+            (meta, None)
+        } else {
+            // Registering under the same lock writers use to decide which pages
+            // can be reclaimed means none of our pages can be released in between.
+            let mut open_ro_txs = db.context.open_ro_txs.lock();
+            let meta = db.context.meta()?;
+            open_ro_txs.push(meta.tx_id);
+            let reader = Reader { db, tx_id: meta.tx_id };
+            (meta, Some(reader))
+        };
+        meta.pagesize = db.context.pagesize;
+        db.context.event_log.on_tx_begin(meta.tx_id);
+
+        let reads = Rc::new(Cell::new(0));
+        let pages = Pages::new(db.context.data.lock().clone(), db.context.pagesize)
+            .count_reads(reads.clone())
+            .strict(db.context.flags.strict_mode)
+            .cache(db.context.pagecache.clone())
+            .prefetch(db.context.scan_prefetch)
+            .codec(db.context.compression);
+        let num_freelist_pages = pages.try_page(meta.freelist_page)?.overflow + 1;
+        let freelist = Rc::new(RefCell::new(TxFreelist::new(
+            meta.clone(),
+            freelist,
+            db.context.io_align,
+            db.context.mode,
+            db.context.fill_percent,
+            db.context.max_value_size,
+            db.context.max_tx_dirty_bytes,
+        )));
+        let root = InnerBucket::from_meta(meta.root, pages.clone(), freelist.clone())
+            .with_comparators(db.context.comparators.clone());
+        let root = Rc::new(RefCell::new(root));
+
+        Ok(Tx {
+            inner: RefCell::new(TxInner {
+                db,
+                lock,
+                _reader: reader,
+                root,
+                meta,
+                freelist,
+                pages,
+                reads,
+                num_freelist_pages,
+            }),
+        })
+    }
+
+    /// Returns the bucket named `name`.
+    ///
+    /// Returns [`Error::BucketMissing`] if there is no such bucket, and
+    /// [`Error::IncompatibleValue`] if `name` holds a key / value pair instead.
+    pub fn bucket<'b>(&'b self, name: &[u8]) -> Result<Bucket<'b, 'tx>> {
+        let inner = self.inner.borrow();
+        let bucket = inner.root.borrow_mut().get_bucket(name)?;
+        Ok(Bucket::new(bucket, inner.lock.writable()))
+    }
+
+    /// Creates a new bucket named `name`.
+    ///
+    /// Returns [`Error::BucketExists`] if a bucket or key / value pair named `name` already exists.
+    pub fn create_bucket<'b>(&'b self, name: &[u8]) -> Result<Bucket<'b, 'tx>> {
+        let inner = self.inner.borrow();
+        if !inner.lock.writable() {
+            return Err(Error::ReadOnlyTx);
+        }
+        let bucket = inner.root.borrow_mut().create_bucket(name.to_vec().into())?;
+        Ok(Bucket::new(bucket, true))
+    }
+
+    /// Deletes the bucket named `name`, along with everything in it.
+    ///
+    /// Returns [`Error::BucketMissing`] if there is no such bucket.
+    pub fn delete_bucket(&self, name: &[u8]) -> Result<()> {
+        let inner = self.inner.borrow();
+        if !inner.lock.writable() {
+            return Err(Error::ReadOnlyTx);
+        }
+        let result = inner.root.borrow_mut().delete_bucket(name);
+        result
+    }
+
+    /// Returns whether a value is stored under `key` in the top level bucket named `bucket`,
+    /// as [`Bucket::contains`] does.
+    ///
+    /// Returns [`Error::BucketMissing`] if there is no such bucket.
+    pub fn contains(&self, bucket: &[u8], key: &[u8]) -> Result<bool> {
+        self.bucket(bucket)?.contains(key)
+    }
+
+    /// Returns the names of the top level buckets, in sorted order.
+    pub fn bucket_names<'b>(&'b self) -> Result<Vec<Bytes<'b>>> {
+        let mut names = Vec::new();
+        let mut cursor = self.root_bucket().cursor();
+        while let Some(leaf) = cursor.next_leaf()? {
+            if let Leaf::Bucket(name, _) = leaf {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    // The bucket holding every top level bucket
+    pub(crate) fn root_bucket<'b>(&'b self) -> Bucket<'b, 'tx> {
+        let inner = self.inner.borrow();
+        Bucket::new(inner.root.clone(), inner.lock.writable())
+    }
+
+    /// Returns true if this transaction can write to the database.
+    pub fn writable(&self) -> bool {
+        self.inner.borrow().lock.writable()
+    }
+
+    /// Writes the transaction's changes to the database file and makes them
+    /// visible to transactions started afterwards.
+    ///
+    /// Committing a read-only transaction just releases it. Returns the
+    /// transaction's statistics, including the work done by the commit itself.
+    pub fn commit(self) -> Result<TxStats> {
+        self.inner.into_inner().commit()
+    }
+
+    /// Discards the transaction's changes, leaving the database as it was
+    /// when the transaction started.
+    ///
+    /// Pages allocated by the transaction were never part of the database's
+    /// freelist, so they are available again to the next transaction.
+    pub fn rollback(self) -> Result<()> {
+        self.inner.into_inner().rollback();
+        Ok(())
+    }
+
+    /// Writes the database as this transaction sees it to `out`, as a file that
+    /// can be opened by itself, returning the number of bytes written.
+    ///
+    /// The meta pages may since have been overwritten by later commits, so both
+    /// are written afresh from this transaction's meta.
+    pub(crate) fn write_to<W: Write>(&self, out: &mut W) -> Result<u64> {
+        let inner = self.inner.borrow();
+        let pagesize = inner.pages.pagesize;
+        for meta_page in 0..2 {
+            let mut meta = inner.meta.clone();
+            meta.meta_page = meta_page;
+            let mut buf = AlignedBuf::new(pagesize as usize, align_of::<Page>())?;
+            meta.write_page(&mut buf);
+            out.write_all(buf.as_slice())?;
+        }
+        let end = (inner.meta.num_pages * pagesize) as usize;
+        out.write_all(&inner.pages.data[2 * pagesize as usize..end])?;
+        Ok(end as u64)
+    }
+
+    /// Returns a copy of the meta this transaction was started from.
+    ///
+    /// Pages a writable transaction allocates or frees aren't reflected until it commits.
+    pub fn meta(&self) -> MetaInfo {
+        let meta = &self.inner.borrow().meta;
+        MetaInfo {
+            tx_id: meta.tx_id,
+            num_pages: meta.num_pages,
+            root_page: meta.root.root_page,
+            freelist_page: meta.freelist_page,
+            pagesize: meta.pagesize,
+        }
+    }
+
+    /// Returns the statistics gathered by this transaction so far.
+    pub fn stats(&self) -> TxStats {
+        self.inner.borrow().stats()
+    }
+}
+
+impl<'tx> TxInner<'tx> {
+    fn commit(mut self) -> Result<TxStats> {
+        let file = match &self.lock {
+            TxLock::Rw(file) => Arc::clone(file),
+            TxLock::Ro => return Ok(self.stats()),
+        };
+
+        self.meta.root = self.root.borrow_mut().spill()?;
+        let mut freelist = self.freelist.borrow_mut();
+        self.meta.freelist_page =
+            freelist.write_freelist(self.meta.tx_id, self.meta.freelist_page, self.num_freelist_pages)?;
+        self.meta.num_pages = freelist.meta.num_pages;
+
+        let context = &self.db.context;
+        let pagesize = context.pagesize;
+        context.grow(&file, self.meta.num_pages)?;
+        let mut written = pagesize;
+        for (page_id, buf) in freelist.page_buffers() {
+            file.write_all_at(buf, page_id * pagesize)?;
+            if let Some(cache) = &context.pagecache {
+                cache.invalidate(page_id, buf.len() as u64 / pagesize);
+            }
+            written += buf.len() as u64;
+        }
+        // Without a background flusher, changes must be durable before commit returns,
+        // so the meta is left to a group sync shared with concurrent commits
+        let sync = context.syncs_on_commit();
+        // Releasing may make this transaction's freed pages reusable at once
+        let freed = freelist.inner.pending(self.meta.tx_id).to_vec();
+        freelist.inner.release(context.reusable_before(self.meta.tx_id + 1));
+        {
+            // The meta and freelist are published under the freelist lock,
+            // so DB::stats never sees one without the other
+            let mut shared = context.freelist.lock();
+            if sync {
+                context.queue_commit(self.meta.clone());
+            } else {
+                // Not ordered after the page writes: the flusher's next sync covers both,
+                // and until then the pages this commit freed stay pending
+                self.meta.write(&file, pagesize, context.io_align)?;
+                context.unflushed.fetch_add(written, Ordering::SeqCst);
+            }
+            *shared = freelist.inner.clone();
+        }
+        for page_id in freed {
+            context.event_log.on_page_free(page_id);
+        }
+        context.event_log.on_commit(self.meta.tx_id, written / pagesize);
+        context.metrics.tx_committed();
+        context.metrics.pages_allocated_total(freelist.stats.pages_allocated);
+        if let Some(cache) = &context.pagecache {
+            context.metrics.cache_hits(cache.hits());
+        }
+        // The commit is written either way, so a failed snapshot only costs recovery time
+        if let Err(e) = context.record_op(&self.meta, &freelist.inner) {
+            error!("failed to take a metadata snapshot: {}", e);
+        }
+        drop(freelist);
+        let mut stats = self.stats();
+        stats.pages_written = written / pagesize;
+        if sync {
+            // The writer lock is released first, so the next transaction can
+            // write its pages while this one waits for the sync
+            let (db, tx_id) = (self.db, self.meta.tx_id);
+            drop(self);
+            db.context.sync_commit(tx_id)?;
+        }
+        Ok(stats)
+    }
+
+    fn stats(&self) -> TxStats {
+        let mut stats = self.freelist.borrow().stats;
+        stats.pages_read = self.reads.get();
+        stats
+    }
+
+    fn rollback(self) {
+        // Everything is undone when the transaction is dropped
+    }
+}
+
+/// The registration of a read-only transaction in `open_ro_txs`, which keeps
+/// writers from reusing the pages it can see.
+///
+/// It's taken as soon as the id is registered and removes it when dropped, so
+/// the id can't leak and block reclamation forever: not when starting the
+/// transaction fails part way, nor when a panic unwinds through it.
+struct Reader<'tx> {
+    db: &'tx DB,
+    tx_id: u64,
+}
+
+impl<'tx> Drop for Reader<'tx> {
+    fn drop(&mut self) {
+        let mut open_ro_txs = self.db.context.open_ro_txs.lock();
+        if let Some(index) = open_ro_txs.iter().position(|id| *id == self.tx_id) {
+            open_ro_txs.swap_remove(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::bucket::Bucket;
+    use crate::bytes::Bytes;
+    use crate::config::Config;
+    use crate::errors::Error;
+    use crate::event_log::{Event, VecEventLog};
+    use crate::node::Leaf;
+
+    use super::TxLock;
+
+    #[test]
+    fn test_tx_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        assert!(matches!(
+            db.tx_timeout(true, Duration::from_millis(10)),
+            Err(Error::Timeout)
+        ));
+        assert!(db.tx_timeout(false, Duration::from_millis(10)).is_ok());
+        drop(tx);
+        assert!(db.tx_timeout(true, Duration::from_millis(10)).is_ok());
+    }
+
+    #[test]
+    fn test_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Config::new().path(dir.path()).open().unwrap();
+            let tx = db.tx(true).unwrap();
+            tx.inner.borrow().root.borrow_mut().put(bytes("key"), bytes("value")).unwrap();
+            tx.commit().unwrap();
+
+            let tx = db.tx(false).unwrap();
+            assert_eq!(db.context.open_ro_txs.lock().len(), 1);
+            tx.commit().unwrap();
+            assert!(db.context.open_ro_txs.lock().is_empty());
+        }
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(false).unwrap();
+        let leaf = tx.inner.borrow().root.borrow().get(b"key").unwrap();
+        assert!(matches!(leaf, Some(Leaf::Kv(_, v)) if v.as_ref() == b"value"));
+    }
+
+    #[test]
+    fn test_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let first = db.tx(false).unwrap().meta();
+        assert_eq!((first.tx_id, first.freelist_page, first.root_page), (0, 2, 3));
+        assert_eq!(first.pagesize, db.pagesize());
+
+        for i in 1..=3 {
+            let tx = db.tx(true).unwrap();
+            assert_eq!(tx.meta().tx_id, first.tx_id + i);
+            tx.create_bucket(format!("bucket{}", i).as_bytes()).unwrap();
+            tx.commit().unwrap();
+            assert_eq!(db.tx(false).unwrap().meta().tx_id, first.tx_id + i);
+        }
+        assert!(db.tx(false).unwrap().meta().num_pages > first.num_pages);
+    }
+
+    #[test]
+    fn test_readers_unregistered_on_panic() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // Panics when a transaction begins, after a reader has registered but before it's built
+        #[derive(Debug, Default)]
+        struct PanickingLog(AtomicBool);
+        impl crate::event_log::EventLog for PanickingLog {
+            fn on_tx_begin(&self, _tx_id: u64) {
+                if self.0.load(Ordering::SeqCst) {
+                    panic!("event log failed");
+                }
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(PanickingLog::default());
+        let db = Config::new().path(dir.path()).event_log(log.clone()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap().put("key", "v".repeat(10000)).unwrap();
+        tx.commit().unwrap();
+
+        let panicked = catch_unwind(AssertUnwindSafe(|| {
+            let tx = db.tx(false).unwrap();
+            assert_eq!(db.context.open_ro_txs.lock().len(), 1);
+            tx.bucket(b"data").unwrap().get(b"nope").unwrap().unwrap();
+        }));
+        assert!(panicked.is_err());
+        assert!(db.context.open_ro_txs.lock().is_empty());
+
+        log.0.store(true, Ordering::SeqCst);
+        assert!(catch_unwind(AssertUnwindSafe(|| db.tx(false))).is_err());
+        assert!(db.context.open_ro_txs.lock().is_empty());
+        log.0.store(false, Ordering::SeqCst);
+
+        // with no reader left, the pages the next commit frees can be reused by the one after
+        let tx = db.tx(true).unwrap();
+        tx.bucket(b"data").unwrap().put("key", "v".repeat(10000)).unwrap();
+        tx.commit().unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.context.reusable_before(u64::MAX), db.context.meta().unwrap().tx_id + 1);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let meta = db.context.meta().unwrap();
+
+        let tx = db.tx(true).unwrap();
+        {
+            let inner = tx.inner.borrow();
+            let value = "v".repeat(db.context.pagesize as usize * 3);
+            for i in 0..4 {
+                inner.root.borrow_mut().put(bytes(&format!("key{}", i)), bytes(&value)).unwrap();
+            }
+            inner.root.borrow_mut().spill().unwrap();
+            assert!(inner.freelist.borrow().stats.overflow_pages > 0);
+        }
+        tx.rollback().unwrap();
+
+        let after = db.context.meta().unwrap();
+        assert_eq!(after.num_pages, meta.num_pages);
+        assert_eq!(after.tx_id, meta.tx_id);
+        let tx = db.tx(true).unwrap();
+        assert_eq!(tx.inner.borrow().freelist.borrow().meta.num_pages, meta.num_pages);
+        assert!(tx.inner.borrow().root.borrow().get(b"key0").unwrap().is_none());
+        tx.rollback().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        tx.rollback().unwrap();
+        assert!(db.context.open_ro_txs.lock().is_empty());
+    }
+
+    #[test]
+    fn test_drop_rolls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let meta = db.context.meta().unwrap();
+        {
+            let tx = db.tx(true).unwrap();
+            tx.create_bucket(b"data").unwrap().put("key", "v".repeat(10000)).unwrap();
+            let _reader = db.tx(false).unwrap();
+            assert_eq!(db.context.open_ro_txs.lock().len(), 1);
+        }
+        assert!(db.context.open_ro_txs.lock().is_empty());
+        assert_eq!(db.context.meta().unwrap().tx_id, meta.tx_id);
+
+        // the writer lock was released
+        let tx = db.tx_timeout(true, Duration::from_millis(10)).unwrap();
+        assert_eq!(tx.bucket(b"data").err(), Some(Error::BucketMissing));
+        assert_eq!(tx.inner.borrow().freelist.borrow().meta.num_pages, meta.num_pages);
+        tx.create_bucket(b"data").unwrap();
+        tx.commit().unwrap();
+        assert!(db.tx(false).unwrap().bucket(b"data").is_ok());
+    }
+
+    #[test]
+    fn test_reader_isolated_from_writer() {
+        use std::sync::mpsc::channel;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let pagesize = db.context.pagesize as usize;
+        let value = move |i: u32| format!("{}", i).repeat(pagesize);
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap().put("key", value(0)).unwrap();
+        tx.commit().unwrap();
+
+        let (opened_tx, opened) = channel();
+        let (committed_tx, committed) = channel();
+        let db = &db;
+        std::thread::scope(|s| {
+            let reader = s.spawn(move || {
+                let tx = db.tx(false).unwrap();
+                let bucket = tx.bucket(b"data").unwrap();
+                assert_eq!(bucket.get(b"key").unwrap().unwrap().as_slice(), value(0).as_bytes());
+                opened_tx.send(()).unwrap();
+                committed.recv().unwrap();
+                // the writer's commits freed the pages holding the old value,
+                // but none could be reused while this reader might still read them
+                let tx2 = db.tx(false).unwrap();
+                assert_eq!(tx2.bucket(b"data").unwrap().get(b"key").unwrap().unwrap().as_slice(), value(5).as_bytes());
+                assert_eq!(bucket.get(b"key").unwrap().unwrap().as_slice(), value(0).as_bytes());
+                let bucket = tx.bucket(b"data").unwrap();
+                assert_eq!(bucket.get(b"key").unwrap().unwrap().as_slice(), value(0).as_bytes());
+            });
+
+            opened.recv().unwrap();
+            for i in 1..=5 {
+                let tx = db.tx(true).unwrap();
+                tx.bucket(b"data").unwrap().put("key", value(i)).unwrap();
+                tx.commit().unwrap();
+            }
+            assert!(db.stats().unwrap().pending_pages > 0);
+            committed_tx.send(()).unwrap();
+            reader.join().unwrap();
+        });
+        assert!(db.context.open_ro_txs.lock().is_empty());
+    }
+
+    #[test]
+    fn test_bucket_get_put_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let root = Bucket::new(tx.inner.borrow().root.clone(), true);
+        root.put(&b"key"[..], &b"value"[..]).unwrap();
+        root.put("other", String::from("other value")).unwrap();
+        assert_eq!(root.get(b"key").unwrap().unwrap().as_ref(), b"value");
+        root.delete(b"key").unwrap();
+        assert!(root.get(b"key").unwrap().is_none());
+        assert_eq!(root.delete(b"key"), Err(Error::KeyValueMissing));
+        assert_eq!(tx.bucket(b"other").err(), Some(Error::IncompatibleValue));
+        assert_eq!(tx.bucket(b"missing").err(), Some(Error::BucketMissing));
+        tx.commit().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        let root = Bucket::new(tx.inner.borrow().root.clone(), false);
+        assert_eq!(root.get(b"other").unwrap().unwrap().as_ref(), b"other value");
+        assert_eq!(root.put("key", "value"), Err(Error::ReadOnlyTx));
+    }
+
+    #[test]
+    fn test_into_owned_outlives_tx() {
+        let dir = tempfile::tempdir().unwrap();
+        // Without a page cache values borrow the mmap
+        let db = Config::new().path(dir.path()).cache_capacity(0).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap().put("key", "value").unwrap();
+        tx.commit().unwrap();
+
+        let value = {
+            let tx = db.tx(false).unwrap();
+            let bucket = tx.bucket(b"data").unwrap();
+            let value = bucket.get(b"key").unwrap().unwrap();
+            assert!(matches!(value, Bytes::Mmap(..)));
+            value.into_owned()
         };
+        assert_eq!(value.as_slice(), b"value");
+    }
+
+    #[test]
+    fn test_mmap_value_survives_remap() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).cache_capacity(0).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap().put("key", "value").unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.bucket(b"data").unwrap();
+        let value = bucket.get(b"key").unwrap().unwrap();
+        let map = match &value {
+            Bytes::Mmap(map, _) => map.clone(),
+            other => panic!("expected a value in the mmap, got {:?}", other),
+        };
+        // growing the file swaps in a new mapping, but the value keeps the old one mapped
+        let file = match &tx.inner.borrow().lock {
+            TxLock::Rw(file) => Arc::clone(file),
+            TxLock::Ro => unreachable!(),
+        };
+        let num_pages = db.context.data.lock().len() as u64 / db.context.pagesize;
+        db.context.grow(&file, num_pages * 2).unwrap();
+        assert!(!Arc::ptr_eq(&map, &db.context.data.lock()));
+        drop(map);
+        bucket.put("other", "x".repeat(10000)).unwrap();
+        let _ = bucket.cursor().first().unwrap();
+        assert_eq!(value.as_slice(), b"value");
+        assert_eq!(value, bucket.get(b"key").unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_event_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(VecEventLog::new());
+        let db = Config::new().path(dir.path()).event_log(log.clone()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        tx.create_bucket(b"data").unwrap();
+        tx.commit().unwrap();
+
+        let events: Vec<Event> = log
+            .events()
+            .into_iter()
+            .filter(|e| !matches!(e, Event::PageFree(_)))
+            .collect();
+        assert!(matches!(
+            events[..],
+            [Event::TxBegin(1), Event::Commit { tx_id: 1, pages_written }] if pages_written > 0
+        ));
+        // the old root and freelist pages were freed
+        assert!(log.events().contains(&Event::PageFree(3)));
+    }
+
+    #[test]
+    fn test_commit_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"data").unwrap();
+        for i in 0..1000 {
+            bucket.put(format!("key{:04}", i), "value").unwrap();
+        }
+        assert_eq!(tx.stats().splits, 0);
+        let stats = tx.commit().unwrap();
+        assert!(stats.splits > 0);
+        assert!(stats.pages_allocated > stats.splits);
+        assert_eq!(stats.pages_written, stats.pages_allocated + 1);
+
+        let tx = db.tx(true).unwrap();
+        tx.bucket(b"data").unwrap().put("key0000", "changed").unwrap();
+        let stats = tx.commit().unwrap();
+        assert_eq!(stats.splits, 0);
+        assert!(stats.pages_freed > 0);
+        assert_eq!(stats.bytes_written, 7);
+    }
+
+    #[test]
+    fn test_bucket_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let tx = db.tx(true).unwrap();
+        for name in [b"cherry", b"apple_", b"banana"] {
+            tx.create_bucket(name).unwrap();
+        }
+        tx.root_bucket().put("plain", "value").unwrap();
+        tx.commit().unwrap();
 
-        let mut freelist = db.inner.freelist.lock().clone();
-        let mut meta = db.inner.meta();
-        todo!()
+        let tx = db.tx(false).unwrap();
+        let names = tx.bucket_names().unwrap();
+        let names: Vec<&[u8]> = names.iter().map(|n| n.as_slice()).collect();
+        assert_eq!(names, vec![&b"apple_"[..], b"banana", b"cherry"]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_create_delete_bucket() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"fruit").unwrap();
+        bucket.put("apple", "red").unwrap();
+        assert_eq!(tx.create_bucket(b"fruit").err(), Some(Error::BucketExists));
+        tx.commit().unwrap();
+
+        let tx = db.tx(true).unwrap();
+        assert_eq!(tx.bucket(b"fruit").unwrap().get(b"apple").unwrap().unwrap().as_ref(), b"red");
+        tx.delete_bucket(b"fruit").unwrap();
+        assert_eq!(tx.delete_bucket(b"fruit"), Err(Error::BucketMissing));
+        assert!(!tx.inner.borrow().freelist.borrow().freed.is_empty());
+        tx.commit().unwrap();
+
+        let tx = db.tx(true).unwrap();
+        assert_eq!(tx.bucket(b"fruit").err(), Some(Error::BucketMissing));
+        let bucket = tx.create_bucket(b"fruit").unwrap();
+        assert!(bucket.get(b"apple").unwrap().is_none());
+        tx.commit().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        assert!(tx.bucket(b"fruit").unwrap().get(b"apple").unwrap().is_none());
+        assert_eq!(tx.create_bucket(b"veg").err(), Some(Error::ReadOnlyTx));
+    }
+
+    #[test]
+    fn test_snapshot_after_ops() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).snapshot_after_ops(10).open().unwrap();
+        let snapshots = || db.context.get_snapshot_files().unwrap();
+        for i in 0..25 {
+            let tx = db.tx(true).unwrap();
+            Bucket::new(tx.inner.borrow().root.clone(), true).put(format!("key{}", i), "value").unwrap();
+            tx.commit().unwrap();
+            if i == 8 {
+                assert!(snapshots().is_empty());
+            }
+        }
+        // the snapshot taken by the 20th commit is a delta against the base taken by the 10th
+        assert_eq!(snapshots(), vec![dir.path().join("snap.10"), dir.path().join("snap.20.delta")]);
+        assert_eq!(db.context.ops.load(std::sync::atomic::Ordering::SeqCst), 5);
+    }
+
+    fn bytes(s: &str) -> Bytes<'static> {
+        Bytes::Vec(Rc::new(s.as_bytes().to_vec()))
+    }
+}
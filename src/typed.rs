@@ -0,0 +1,85 @@
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bucket::Bucket;
+use crate::errors::{Error, Result};
+
+// Big endian, fixed width integers, so unsigned integer keys sort numerically
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_big_endian()
+        .with_fixint_encoding()
+}
+
+fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    options().serialize(value).map_err(|e| Error::Encoding(e.to_string()))
+}
+
+/// Typed access to a bucket, encoding keys and values with `bincode`.
+///
+/// Keys are ordered by their encoded bytes. Unsigned integers, strings and
+/// structs of them sort as expected, but negative integers sort after positive
+/// ones, floats sort by their bit pattern, and maps sort by their iteration order.
+impl<'b, 'tx> Bucket<'b, 'tx> {
+    /// Encodes `key` and `value` and stores them, as [`Bucket::put`] does.
+    pub fn put_typed<K: Serialize + ?Sized, V: Serialize + ?Sized>(&self, key: &K, value: &V) -> Result<()> {
+        self.put(encode(key)?, encode(value)?)
+    }
+
+    /// Returns the value stored under the encoded `key`, decoded as a `V`.
+    ///
+    /// Returns [`Error::Encoding`] if the stored value isn't a valid `V`.
+    pub fn get_typed<K: Serialize + ?Sized, V: DeserializeOwned>(&self, key: &K) -> Result<Option<V>> {
+        match self.get(&encode(key)?)? {
+            Some(value) => options()
+                .deserialize(value.as_slice())
+                .map(Some)
+                .map_err(|e| Error::Encoding(e.to_string())),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use crate::config::Config;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u8,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn test_put_get_typed() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Config::new().path(dir.path()).open().unwrap();
+        let user = User {
+            name: "ada".to_string(),
+            age: 36,
+            tags: vec!["admin".to_string()],
+        };
+        let tx = db.tx(true).unwrap();
+        let bucket = tx.create_bucket(b"users").unwrap();
+        bucket.put_typed(&7u64, &user).unwrap();
+        bucket.put_typed(&300u64, &user).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.bucket(b"users").unwrap();
+        assert_eq!(bucket.get_typed::<_, User>(&7u64).unwrap(), Some(user));
+        assert_eq!(bucket.get_typed::<_, User>(&8u64).unwrap(), None);
+        assert!(matches!(bucket.get_typed::<_, (u64, u64, u64)>(&7u64), Err(Error::Encoding(_))));
+
+        // integer keys sort numerically
+        let mut cursor = bucket.cursor();
+        let (first, _) = cursor.next().unwrap().unwrap();
+        assert_eq!(first.as_slice(), encode(&7u64).unwrap());
+    }
+}
@@ -1,24 +1,88 @@
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
 use std::fs::File;
+use std::ptr::NonNull;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
 
-use memmap2::Mmap;
+use memmap2::{Advice, Mmap};
 
+use crate::config::MmapAdvice;
 use crate::errors::Result;
 
 #[cfg(unix)]
-pub(crate) fn mmap(file: &File, populate: bool) -> Result<Mmap> {
+pub(crate) fn mmap(file: &File, populate: bool, advice: MmapAdvice) -> Result<Mmap> {
     use memmap2::MmapOptions;
     let mut opts = MmapOptions::new();
     if populate {
         opts.populate();
     }
     let mmap = unsafe { opts.map(file)? };
-    // On Unix we advice the OS that page access will be random.
-    mmap.advise(memmap2::Advice::Random)?;
+    // On Unix we advise the OS of the expected access pattern, random by default.
+    mmap.advise(advice.into())?;
     Ok(mmap)
 }
 
+impl From<MmapAdvice> for Advice {
+    fn from(advice: MmapAdvice) -> Advice {
+        match advice {
+            MmapAdvice::Normal => Advice::Normal,
+            MmapAdvice::Random => Advice::Random,
+            MmapAdvice::Sequential => Advice::Sequential,
+            MmapAdvice::WillNeed => Advice::WillNeed,
+        }
+    }
+}
+
+/// A zeroed heap buffer with a caller-chosen alignment,
+/// for IO that requires aligned memory such as `O_DIRECT` writes.
+pub(crate) struct AlignedBuf {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    pub(crate) fn new(size: usize, align: usize) -> Result<AlignedBuf> {
+        let layout = Layout::from_size_align(size.max(1), align)?;
+        let ptr = unsafe { alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| handle_alloc_error(layout));
+        Ok(AlignedBuf { ptr, layout })
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
 
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// Returns the alignment that buffers written to `file` need. With `direct_writes`
+/// this is the filesystem's block size, otherwise only the page header's alignment.
+pub(crate) fn io_alignment(file: &File, direct_writes: bool, pagesize: u64) -> Result<usize> {
+    use std::os::unix::fs::MetadataExt;
 
+    if !direct_writes {
+        return Ok(std::mem::align_of::<crate::page::Page>());
+    }
+    let block_size = match file.metadata()?.blksize() {
+        0 => pagesize,
+        block_size => block_size,
+    };
+    if !block_size.is_power_of_two() || !pagesize.is_multiple_of(block_size) {
+        return Err(crate::errors::Error::Unsupported(
+            "direct_writes requires a pagesize that is a multiple of the filesystem block size",
+        ));
+    }
+    Ok(block_size as usize)
+}
+
+#[cfg(test)]
 mod tests {
     use crate::utils::mmap;
 
@@ -30,11 +94,21 @@ mod tests {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&path)
             .unwrap();
-        let mmap = mmap(&file, true).unwrap();
+        let mmap = mmap(&file, true, crate::config::MmapAdvice::Random).unwrap();
         dbg!(mmap);
 
         dbg!(file.metadata().unwrap().len());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_aligned_buf() {
+        let mut buf = super::AlignedBuf::new(8192, 4096).unwrap();
+        assert_eq!(buf.as_slice().as_ptr() as usize % 4096, 0);
+        assert!(buf.as_slice().iter().all(|b| *b == 0));
+        buf.as_mut_slice()[8191] = 1;
+        assert_eq!(buf.as_slice().len(), 8192);
+    }
+}